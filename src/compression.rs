@@ -0,0 +1,296 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// compression.rs
+// - GBA BIOS compression formats (LZ77, Huffman and RLE)
+//
+// Compressed data starts with a 32-bit little-endian header: bits 4-7 give the format, bits 0-3
+// the Huffman symbol size, and bits 8-31 the decompressed size.
+use memory::MemoryState;
+use std::collections::HashSet;
+
+/// `try!` for Option (returns None if the value is None)
+macro_rules! try_opt{
+	($e:expr) => (match $e { Some(v) => v, None => return None });
+}
+
+/// Smallest decompressed size accepted by the scanner (smaller blobs are mostly false positives)
+const MIN_SIZE: usize = 0x20;
+/// Largest decompressed size accepted by the scanner (the size of on-board WRAM)
+const MAX_SIZE: usize = 0x40000;
+
+/// A compressed blob found in ROM
+pub struct Found
+{
+	/// Address of the compressed data
+	pub source: u64,
+	/// Length of the compressed data (including the header)
+	pub compressed_len: usize,
+	/// Address of the virtual region holding the decompressed data
+	pub base: u64,
+	pub size: usize,
+}
+
+/// Find compressed data in ROM and decompress it into read-only virtual regions
+///
+/// Candidates must be word aligned, decompress without errors, and be referenced by an aligned
+/// pointer elsewhere in ROM (compressed data is found through pointer tables or literal pools).
+pub fn scan(memory: &mut MemoryState) -> Vec<Found>
+{
+	let mut found = Vec::new();
+	{
+		let roms = memory.rom_regions();
+		// - Collect every aligned word in ROM (as a possible pointer)
+		let mut pointers = HashSet::new();
+		for &(_, data) in roms.iter()
+		{
+			for w in data.chunks(4).filter(|w| w.len() == 4) {
+				pointers.insert( memory.canonicalise(le32(w)) );
+			}
+		}
+		for &(start, data) in roms.iter()
+		{
+			let mut ofs = 0;
+			while ofs + 4 <= data.len()
+			{
+				let addr = start + ofs as u64;
+				if is_header(&data[ofs..]) && pointers.contains(&addr)
+				{
+					if let Some( (out, len) ) = decompress(&data[ofs..])
+					{
+						debug!("Compressed data ({:#x}) at {:#x}: {:#x} bytes from {:#x}", data[ofs], addr, out.len(), len);
+						found.push( (addr, len, out) );
+						ofs += (len + 3) & !3;
+						continue ;
+					}
+				}
+				ofs += 4;
+			}
+		}
+	}
+	info!("Found {} compressed blobs in ROM", found.len());
+	found.into_iter().map(|(source, len, out)| {
+		let size = out.len();
+		Found { source: source, compressed_len: len, base: memory.add_virtual(source, out), size: size }
+		}).collect()
+}
+
+fn le32(b: &[u8]) -> u64 {
+	b[0] as u64 | (b[1] as u64) << 8 | (b[2] as u64) << 16 | (b[3] as u64) << 24
+}
+
+/// Check if a header is a known format with a plausible size
+fn is_header(data: &[u8]) -> bool
+{
+	let size = (le32(data) >> 8) as usize;
+	match data[0]
+	{
+	0x10 | 0x24 | 0x28 | 0x30 => MIN_SIZE <= size && size <= MAX_SIZE,
+	_ => false,
+	}
+}
+
+/// Decompress data, returning the decompressed data and the length of the compressed data
+///
+/// Returns None if the data isn't valid (unknown format, truncated, or inconsistent)
+pub fn decompress(data: &[u8]) -> Option<(Vec<u8>, usize)>
+{
+	if data.len() < 4 {
+		return None;
+	}
+	let size = (le32(data) >> 8) as usize;
+	let (mut out, len) = match data[0]
+		{
+		0x10 => try_opt!(decompress_lz77(data, size)),
+		0x24 => try_opt!(decompress_huffman(data, size, 4)),
+		0x28 => try_opt!(decompress_huffman(data, size, 8)),
+		0x30 => try_opt!(decompress_rle(data, size)),
+		_ => return None,
+		};
+	// - The final block may decode past the end
+	out.truncate(size);
+	Some( (out, len) )
+}
+
+/// LZ77: Groups of 8 blocks (flags byte, MSB first), each a literal byte (0) or a back-reference (1)
+fn decompress_lz77(data: &[u8], size: usize) -> Option<(Vec<u8>, usize)>
+{
+	let mut out = Vec::with_capacity(size);
+	let mut pos = 4;
+	while out.len() < size
+	{
+		let flags = *try_opt!(data.get(pos));
+		pos += 1;
+		for i in 0 .. 8
+		{
+			if out.len() >= size {
+				break ;
+			}
+			if flags & (0x80 >> i) == 0 {
+				out.push( *try_opt!(data.get(pos)) );
+				pos += 1;
+			}
+			else {
+				let b0 = *try_opt!(data.get(pos)) as usize;
+				let b1 = *try_opt!(data.get(pos+1)) as usize;
+				pos += 2;
+				let count = (b0 >> 4) + 3;
+				let disp = ((b0 & 0xF) << 8 | b1) + 1;
+				// - Can't refer to data before the start
+				if disp > out.len() {
+					return None;
+				}
+				for _ in 0 .. count {
+					let v = out[out.len() - disp];
+					out.push(v);
+				}
+			}
+		}
+	}
+	Some( (out, pos) )
+}
+
+/// RLE: Flag byte, bit 7 set for a run of ((flag&0x7F)+3) copies of one byte, otherwise ((flag&0x7F)+1) literal bytes
+fn decompress_rle(data: &[u8], size: usize) -> Option<(Vec<u8>, usize)>
+{
+	let mut out = Vec::with_capacity(size);
+	let mut pos = 4;
+	while out.len() < size
+	{
+		let flag = *try_opt!(data.get(pos)) as usize;
+		pos += 1;
+		if flag & 0x80 != 0 {
+			let v = *try_opt!(data.get(pos));
+			pos += 1;
+			for _ in 0 .. (flag & 0x7F) + 3 {
+				out.push(v);
+			}
+		}
+		else {
+			let count = (flag & 0x7F) + 1;
+			out.extend_from_slice( try_opt!(data.get(pos .. pos + count)) );
+			pos += count;
+		}
+	}
+	Some( (out, pos) )
+}
+
+/// Huffman: Tree (size byte, then nodes) followed by a bitstream of 32-bit words (MSB first)
+///
+/// Each node has a 6-bit offset to its pair of children, and flags for which children are symbols
+fn decompress_huffman(data: &[u8], size: usize, bits: usize) -> Option<(Vec<u8>, usize)>
+{
+	let tree_len = (*try_opt!(data.get(4)) as usize + 1) * 2;
+	let tree_end = 4 + tree_len;
+	if tree_end > data.len() {
+		return None;
+	}
+	const ROOT: usize = 5;
+	let mut out = Vec::with_capacity(size);
+	let mut acc = 0u8;
+	let mut nsyms = 0;
+	let mut node_pos = ROOT;
+	let mut pos = tree_end;
+	while out.len() < size
+	{
+		let word = le32( try_opt!(data.get(pos .. pos + 4)) );
+		pos += 4;
+		for i in (0 .. 32).rev()
+		{
+			if out.len() >= size {
+				break ;
+			}
+			let node = *try_opt!(data.get(node_pos));
+			let child = (node_pos & !1) + (node as usize & 0x3F) * 2 + 2 + ((word >> i) & 1) as usize;
+			if child >= tree_end {
+				return None;
+			}
+			let is_sym = node & (if (word >> i) & 1 == 0 { 0x80 } else { 0x40 }) != 0;
+			if !is_sym {
+				node_pos = child;
+				continue ;
+			}
+			// - Symbols are packed from the low bits up
+			let sym = *try_opt!(data.get(child));
+			if bits == 8 {
+				out.push(sym);
+			}
+			else {
+				acc |= (sym & 0xF) << (nsyms * 4);
+				nsyms += 1;
+				if nsyms == 2 {
+					out.push(acc);
+					acc = 0;
+					nsyms = 0;
+				}
+			}
+			node_pos = ROOT;
+		}
+	}
+	Some( (out, pos) )
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	
+	#[test]
+	fn lz77()
+	{
+		// - Three literals, then a 9-byte back-reference 3 bytes back
+		let data = [0x10, 0x0C, 0x00, 0x00, 0x10, b'A', b'B', b'C', 0x60, 0x02];
+		assert_eq!( decompress(&data), Some( (b"ABCABCABCABC".to_vec(), 10) ) );
+		// - References before the start, and truncated data
+		assert_eq!( decompress(&[0x10, 0x0C, 0x00, 0x00, 0x80, 0x60, 0x02]), None );
+		assert_eq!( decompress(&data[.. 9]), None );
+	}
+	
+	#[test]
+	fn rle()
+	{
+		// - A run of 5, then 3 literals
+		let data = [0x30, 0x08, 0x00, 0x00, 0x82, b'A', 0x02, b'x', b'y', b'z'];
+		assert_eq!( decompress(&data), Some( (b"AAAAAxyz".to_vec(), 10) ) );
+		// - The last run is cut to the stated size
+		assert_eq!( decompress(&[0x30, 0x04, 0x00, 0x00, 0xFF, b'A']), Some( (b"AAAA".to_vec(), 6) ) );
+		assert_eq!( decompress(&data[.. 8]), None );
+	}
+	
+	#[test]
+	fn huffman()
+	{
+		// - Tree with a root whose children are both symbols, bitstream 0110
+		let data = [0x28, 0x04, 0x00, 0x00, 0x01, 0xC0, b'A', b'B', 0x00, 0x00, 0x00, 0x60];
+		assert_eq!( decompress(&data), Some( (b"ABBA".to_vec(), 12) ) );
+		// - 4-bit symbols are packed low nibble first
+		let data = [0x24, 0x02, 0x00, 0x00, 0x01, 0xC0, 0x1, 0x2, 0x00, 0x00, 0x00, 0x60];
+		assert_eq!( decompress(&data), Some( (vec![0x21, 0x12], 12) ) );
+		// - Trees past the end of the data, and children outside the tree
+		assert_eq!( decompress(&[0x28, 0x04, 0x00, 0x00, 0xFF, 0xC0, b'A', b'B']), None );
+		assert_eq!( decompress(&[0x28, 0x04, 0x00, 0x00, 0x01, 0xC1, b'A', b'B', 0x00, 0x00, 0x00, 0x60]), None );
+		assert_eq!( decompress(&[0x28, 0x04, 0x00, 0x00, 0x01, 0xC0, b'A', b'B', 0x00]), None );
+	}
+	
+	#[test]
+	fn scan_rom()
+	{
+		// - A pointer to an RLE blob of 0x20 bytes
+		let rom = vec![0x08, 0x00, 0x00, 0x08, 0, 0, 0, 0, 0x30, 0x20, 0x00, 0x00, 0xFF, b'A', 0, 0];
+		let mut mem = MemoryState::new();
+		mem.add_rom_data(0x08000000, rom.clone());
+		let found = scan(&mut mem);
+		assert_eq!( found.len(), 1 );
+		assert_eq!( (found[0].source, found[0].compressed_len, found[0].size), (0x08000008, 6, 0x20) );
+		assert_eq!( mem.read_u8(found[0].base + 0x1F).and_then(|v| v.val_known()), Some(b'A') );
+		assert_eq!( mem.virtual_source(found[0].base), Some(0x08000008) );
+		assert!( mem.read_u8(found[0].base + 0x20).is_none() );
+		
+		// - Data that nothing points to is ignored
+		let mut mem = MemoryState::new();
+		mem.add_rom_data(0x08000000, rom[4 ..].to_vec());
+		assert_eq!( scan(&mut mem).len(), 0 );
+	}
+}
+
+// vim: ft=rust
@@ -5,8 +5,8 @@
 // - Block of code in a disassembled program
 use std::rc::Rc;
 use std::cell::RefCell;
-use disasm::state::StateData;
-use disasm::instruction::Instruction;
+use disasm::state::{StateData,Access};
+use disasm::instruction::{Instruction,Diagnostic};
 use disasm::{CodePtr,CodeRange};
 use std::default::Default;
 use bit_set::BitSet;
@@ -19,6 +19,8 @@ pub struct Block
 	instructions: Vec<Instruction>,
 	
 	refs: Vec<CodePtr>,
+	/// State on entry, merged from all predecessors
+	entrystate: Option<StateData>,
 	endstate: Option<StateData>,
 }
 
@@ -41,6 +43,14 @@ pub struct Function
 	populate_state: CCState,
 	inputs: BitSet,
 	clobbers: BitSet,
+	/// Clobbered registers that only have a constant offset applied (e.g. the stack pointer)
+	offsets: Vec<(u8,u64)>,
+	/// Memory accesses made through pointers with an unknown base (e.g. fields of structure arguments)
+	accesses: Vec<Access>,
+	/// Defined outside the program (e.g. an import), so isn't disassembled
+	external: bool,
+	/// CPU that runs the function, for systems with more than one (e.g. "arm9"), "" if not tagged
+	core: &'static str,
 }
 
 impl Block
@@ -51,6 +61,7 @@ impl Block
 		Block {
 			instructions: instrs,
 			refs: refs,
+			entrystate: None,
 			endstate: None,
 		}
 	}
@@ -86,6 +97,7 @@ impl Block
 		Block {
 			instructions: new_instrs,
 			refs: ::std::mem::replace(&mut self.refs, vec![addr]),
+			entrystate: None,
 			endstate: None,
 		}
 	}
@@ -93,6 +105,24 @@ impl Block
 	pub fn instrs(&self) -> &[Instruction] {
 		&self.instructions
 	}
+	/// Attach a diagnostic to the instruction at `addr`
+	pub fn add_diagnostic(&mut self, addr: CodePtr, diag: Diagnostic)
+	{
+		match self.instructions.binary_search_by(|e| e.addr().cmp(&addr))
+		{
+		Ok(i) => self.instructions[i].add_diagnostic(diag),
+		Err(_) => error!("Diagnostic for {} not in block ({}): {}", addr, self.range(), diag),
+		}
+	}
+	/// Set the statically known condition outcome of the instruction at `addr`
+	pub fn set_static_outcome(&mut self, addr: CodePtr, outcome: ::value::ValueBool)
+	{
+		match self.instructions.binary_search_by(|e| e.addr().cmp(&addr))
+		{
+		Ok(i) => self.instructions[i].set_static_outcome(outcome),
+		Err(_) => error!("Instruction {} not in block ({})", addr, self.range()),
+		}
+	}
 	pub fn refs(&self) -> &[CodePtr] {
 		&self.refs
 	}
@@ -102,10 +132,17 @@ impl Block
 		let last  = self.instructions.last(). expect("No instructions in block").addr();
 		CodeRange::new(first, last)
 	}
+	pub fn entry_state(&self) -> Option<&StateData> {
+		self.entrystate.as_ref()
+	}
 	pub fn end_state(&self) -> Option<&StateData> {
 		self.endstate.as_ref()
 	}
 	
+	pub fn set_entry_state(&mut self, state: StateData) {
+		self.entrystate = Some(state);
+	}
+	
 	pub fn set_state(&mut self, state: StateData) {
 		debug!("State for block {} set to: {:?}", self.range(), state);
 		self.endstate = Some(state);
@@ -135,23 +172,84 @@ impl Default for CCState
 
 impl Function
 {
-	pub fn set_reg_usage(&mut self, fully_known: bool, inputs: BitSet, clobbers: BitSet)
+	/// Create an external function, with register usage from a calling convention
+	pub fn new_external(name: &str, conv: &::disasm::CallingConv) -> Function
 	{
-		debug!("Function::set_reg_usage(fully_known={}, inputs={:?}, clobbers={:?}", fully_known, inputs, clobbers);
+		Function {
+			name: String::from(name),
+			populate_state: CCState::Full,
+			clobbers: conv.clobbers.clone(),
+			offsets: conv.offsets.clone(),
+			external: true,
+			.. Default::default()
+		}
+	}
+	
+	pub fn set_name(&mut self, name: &str)
+	{
+		self.name = String::from(name);
+	}
+	pub fn set_core(&mut self, core: &'static str)
+	{
+		self.core = core;
+	}
+	
+	pub fn set_reg_usage(&mut self, fully_known: bool, inputs: BitSet, clobbers: BitSet, offsets: Vec<(u8,u64)>)
+	{
+		debug!("Function::set_reg_usage(fully_known={}, inputs={:?}, clobbers={:?}, offsets={:?}", fully_known, inputs, clobbers, offsets);
 		self.populate_state = if fully_known { CCState::Full } else { CCState::Partial };
 		self.inputs = inputs;
 		self.clobbers = clobbers;
+		self.offsets = offsets;
+	}
+	
+	pub fn set_accesses(&mut self, accesses: Vec<Access>)
+	{
+		self.accesses = accesses;
 	}
 	
 	pub fn cc_state(&self) -> CCState {
 		self.populate_state
 	}
+	pub fn is_external(&self) -> bool {
+		self.external
+	}
 	pub fn inputs(&self) -> &BitSet {
 		&self.inputs
 	}
 	pub fn clobbers(&self) -> &BitSet {
 		&self.clobbers
 	}
+	/// Get the offset applied to a clobbered register (if it's only offset)
+	pub fn reg_offset(&self, reg: u8) -> Option<u64> {
+		self.offsets.iter().find(|e| e.0 == reg).map(|e| e.1)
+	}
+	pub fn accesses(&self) -> &[Access] {
+		&self.accesses
+	}
+	/// Collate the fields (offset, size) accessed through each pointer origin, sorted by offset
+	pub fn field_layouts(&self) -> Vec<(::value::Value<u64>, Vec<(u64,u8)>)>
+	{
+		let mut ret: Vec<(::value::Value<u64>, Vec<(u64,u8)>)> = Vec::new();
+		for a in self.accesses.iter()
+		{
+			let idx = match ret.iter().position(|e| e.0 == a.base)
+				{
+				Some(i) => i,
+				None => {
+					ret.push( (a.base.clone(), Vec::new()) );
+					ret.len() - 1
+					},
+				};
+			ret[idx].1.push( (a.offset, a.size) );
+		}
+		for &mut (_, ref mut fields) in ret.iter_mut()
+		{
+			fields.sort();
+			fields.dedup();
+		}
+		ret
+	}
 }
 
 impl ::std::fmt::Display for Function
@@ -164,16 +262,39 @@ impl ::std::fmt::Display for Function
 		else {
 			try!(write!(f, "method_?"));
 		}
+		if ! self.core.is_empty() {
+			try!(write!(f, " [{}]", self.core));
+		}
 		try!(write!(f, ": ("));
 		for r in self.inputs.iter() {
 			try!(write!(f, " R{}", r));
 		}
 		try!(write!(f, " ):"));
 		for r in self.clobbers.iter() {
-			try!(write!(f, " R{}", r));
+			match self.reg_offset(r as u8)
+			{
+			Some(ofs) => try!(write!(f, " R{}{:+}", r, ofs as i64)),
+			None => try!(write!(f, " R{}", r)),
+			}
 		}
 		Ok( () )
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	
+	#[test]
+	fn function_core_tag()
+	{
+		let mut f = Function::default();
+		f.set_name("arm9_start");
+		assert_eq!( f.to_string(), "arm9_start: ( ):" );
+		f.set_core("arm9");
+		assert_eq!( f.to_string(), "arm9_start [arm9]: ( ):" );
+	}
+}
+
 // vim: ft=rust
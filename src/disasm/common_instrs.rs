@@ -76,13 +76,13 @@ def_instr!{CALL, IClassCall, (f,instr,p,state) => {
 		panic!("TODO: CALL.backwards");
 	};
 }}
-
 // MOVE - Shift a value between registers
 def_instr!{MOVE, IClassMove, (f,instr,params,state) => {
 	{ false };
 	{ write!(f, "{:?}, {:?}", params[0], params[1]) };
 	{
 		let val = state.get(params[1]);
+		set_nz_flags(state, instr.opsize(), &val);
 		state.set(params[0], val);
 	};
 	{
@@ -92,6 +92,41 @@ def_instr!{MOVE, IClassMove, (f,instr,params,state) => {
 	};
 }}
 
+/// Set the Negative and Zero flags from an operation's result
+pub fn set_nz_flags(state: &mut ::disasm::state::State, size: InstrSize, val: &Value<u64>)
+{
+	let (n, z) = match size
+		{
+		InstrSize::SizeNA => (ValueBool::Unknown, ValueBool::Unknown),
+		_ => size_call!( size, nz_fwds(val.truncate()) ),
+		};
+	state.flag_set(StatusFlags::Negative, n);
+	state.flag_set(StatusFlags::Zero, z);
+	state.clear_compare();
+}
+fn nz_fwds<T:ValueType>(val: Value<T>) -> (ValueBool,ValueBool)
+{
+	(val.sign_bit(), val.is_zero())
+}
+
+/// Run a shift operation for every possible shift count, merging the results
+fn shift_over<F>(count: &Value<u64>, op: F) -> (Value<u64>,ValueBool)
+where
+	F: Fn(usize) -> (Value<u64>,ValueBool)
+{
+	let mut rv: Option<(Value<u64>,ValueBool)> = None;
+	for c in count.possibilities()
+	{
+		let (v, cf) = op(c as usize);
+		rv = Some(match rv
+			{
+			None => (v, cf),
+			Some( (pv, pcf) ) => (pv.union(&v), pcf.union(cf)),
+			});
+	}
+	rv.expect("Empty shift count set")
+}
+
 // SHL - Bitwise Shift Left
 def_instr!{SHL, IClassShl, (f, instr, params, state) => {
 	{ false };
@@ -99,17 +134,18 @@ def_instr!{SHL, IClassShl, (f, instr, params, state) => {
 	{
 		let v = state.get(params[1]);
 		let count = state.get(params[2]);
-		if let Some(c) = count.val_known()
-		{
-			let (ov,cf) = size_call!( instr.opsize(), shl_fwds(v.truncate(), c as usize) );
-			state.set(params[0], ov);
-			state.flag_set(StatusFlags::Carry, cf);
-		}
-		else
-		{
-			warn!("TODO: SHL by a set/range of values");
-			state.set(params[0], Value::unknown());
-		}
+		let (res, cf) = if count.is_fixed_set()
+			{
+				shift_over(&count, |c| size_call!( instr.opsize(), shl_fwds(v.truncate(), c) ))
+			}
+			else
+			{
+				warn!("TODO: SHL by a range of values");
+				(Value::unknown(), ValueBool::Unknown)
+			};
+		set_nz_flags(state, instr.opsize(), &res);
+		state.flag_set(StatusFlags::Carry, cf);
+		state.set(params[0], res);
 	};
 	{ unimplemented!(); };
 }}
@@ -131,18 +167,18 @@ def_instr!{SHR, IClassShr, (f, instr, params, state) => {
 	{
 		let v = state.get(params[1]);
 		let count = state.get(params[2]);
-		if let Some(c) = count.val_known()
-		{
-			let (res, cf) = size_call!( instr.opsize(), shr_fwds(v.truncate(), c as usize) );
-			state.set(params[0], res);
-			state.flag_set(StatusFlags::Carry, cf);
-		}
-		else
-		{
-			warn!("TODO: SHL by a set/range of values");
-			state.set(params[0], Value::unknown());
-			state.flag_set(StatusFlags::Carry, ValueBool::Unknown);
-		}
+		let (res, cf) = if count.is_fixed_set()
+			{
+				shift_over(&count, |c| size_call!( instr.opsize(), shr_fwds(v.truncate(), c) ))
+			}
+			else
+			{
+				warn!("TODO: SHR by a range of values");
+				(Value::unknown(), ValueBool::Unknown)
+			};
+		set_nz_flags(state, instr.opsize(), &res);
+		state.flag_set(StatusFlags::Carry, cf);
+		state.set(params[0], res);
 	};
 	{ unimplemented!(); };
 }}
@@ -157,6 +193,63 @@ fn shr_fwds<T:ValueType>(val: Value<T>, c: usize) -> (Value<u64>,ValueBool)
 	}
 }
 
+// ASR - Arithmetic (sign-preserving) Shift Right
+def_instr!{ASR, IClassAsr, (f, instr, params, state) => {
+	{ false };
+	{ write!(f, "{:?} := {:?} >>s {:?}", params[0], params[1], params[2]) };
+	{
+		let v = state.get(params[1]);
+		let count = state.get(params[2]);
+		let (res, cf) = if count.is_fixed_set()
+			{
+				shift_over(&count, |c| size_call!( instr.opsize(), asr_fwds(v.truncate(), c) ))
+			}
+			else
+			{
+				warn!("TODO: ASR by a range of values");
+				(Value::unknown(), ValueBool::Unknown)
+			};
+		set_nz_flags(state, instr.opsize(), &res);
+		state.flag_set(StatusFlags::Carry, cf);
+		state.set(params[0], res);
+	};
+	{ unimplemented!(); };
+}}
+fn asr_fwds<T:ValueType>(val: Value<T>, c: usize) -> (Value<u64>,ValueBool)
+{
+	if c >= val.bitsize() {
+		// Everything is shifted out, leaving copies of the sign bit
+		let sign = val.sign_bit();
+		let bits = val.bitsize();
+		let (_,res) = val.asr(bits);
+		(res.zero_extend(), sign)
+	}
+	else {
+		let (extra,res) = val.asr(c);
+		(res.zero_extend(), extra.bit(0))
+	}
+}
+
+// SEXT - Sign-extend the bottom `bits` bits of a value
+def_instr!{SEXT, IClassSext, (f, instr, params, state) => {
+	{ false };
+	{ write!(f, "{:?} := sext({:?}, {:?})", params[0], params[1], params[2]) };
+	{
+		let v = state.get(params[1]);
+		let bits = match params[2]
+			{
+			InstrParam::Immediate(v) => v as usize,
+			_ => panic!("Invalid type for param[2] of SEXT, {:?}", params[2]),
+			};
+		let (res,) = size_call!( instr.opsize(), sext_fwds(v.truncate(), bits) );
+		state.set(params[0], res);
+	};
+	{ unimplemented!(); };
+}}
+fn sext_fwds<T:ValueType>(val: Value<T>, bits: usize) -> (Value<u64>,)
+{
+	(val.sign_extend_from(bits).zero_extend(),)
+}
 
 // ROR - Bitwise Rotate Right
 def_instr!{ROR, IClassRor, (f, instr, params, state) => {
@@ -165,16 +258,24 @@ def_instr!{ROR, IClassRor, (f, instr, params, state) => {
 	{
 		let v = state.get(params[1]);
 		let count = state.get(params[2]);
-		if let Some(c) = count.val_known()
-		{
-			let (res, ) = size_call!( instr.opsize(), ror_fwds(v.truncate(), c as usize) );
-			state.set(params[0], res);
-		}
-		else
-		{
-			warn!("TODO: ROR by a set/range of values");
-			state.set(params[0], Value::unknown());
-		}
+		let res = if count.is_fixed_set()
+			{
+				let (res, _) = shift_over(&count, |c| {
+					let (res, ) = size_call!( instr.opsize(), ror_fwds(v.truncate(), c) );
+					(res, ValueBool::Unknown)
+					});
+				res
+			}
+			else
+			{
+				warn!("TODO: ROR by a range of values");
+				Value::unknown()
+			};
+		set_nz_flags(state, instr.opsize(), &res);
+		// - Carry out is the new top bit (same as the negative flag)
+		let cf = state.flag_get(StatusFlags::Negative);
+		state.flag_set(StatusFlags::Carry, cf);
+		state.set(params[0], res);
 	};
 	{ unimplemented!(); };
 }}
@@ -193,36 +294,103 @@ fn carry_val<T:ValueType>(c: ValueBool) -> Value<T>
 {
 	match c
 	{
-	// TODO: Convert unknown into a two value set
-	ValueBool::Unknown => Value::unknown(),
+	ValueBool::Unknown => Value::set(vec![T::zero(), T::one()]),
 	// NOTE: .truncate() can zero extend too
 	ValueBool::True  => Value::known(1u64).truncate(),
 	ValueBool::False => Value::known(0u64).truncate(),
 	}
 }
+fn bool_val<T:ValueType>(v: bool) -> T
+{
+	if v { T::one() } else { T::zero() }
+}
+/// Mask for the sign bit of a type
+fn sign_mask<T:ValueType>() -> T
+{
+	!(T::max_value() >> 1)
+}
+/// Evaluate a flag for every combination of possible inputs (unknown if they disagree)
+fn flag_over<T: ValueType, F: Fn(T,T,bool)->bool>(a: &Value<T>, b: &Value<T>, c: ValueBool, f: F) -> ValueBool
+{
+	if !a.is_fixed_set() || !b.is_fixed_set() {
+		return ValueBool::Unknown;
+	}
+	let carries: &[bool] = match c
+		{
+		ValueBool::True => &[true],
+		ValueBool::False => &[false],
+		ValueBool::Unknown => &[false, true],
+		};
+	let mut rv: Option<ValueBool> = None;
+	for va in a.possibilities()
+	{
+		for vb in b.possibilities()
+		{
+			for &vc in carries
+			{
+				let v = ValueBool::from_bool( f(va, vb, vc) );
+				rv = Some(match rv
+					{
+					None => v,
+					Some(r) => r.union(v),
+					});
+				if rv == Some(ValueBool::Unknown) {
+					return ValueBool::Unknown;
+				}
+			}
+		}
+	}
+	rv.unwrap_or(ValueBool::Unknown)
+}
+
 // ADD - Addition of two values into a register
 def_instr!{ADD, IClassAdd, (f, instr, params, state) => {
 	{ false };
 	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
 	{
-		let a = state.get(params[1]);
-		let b = state.get(params[2]);
+		add_instr(state, instr, ValueBool::False);
+	};
+	{
+		unimplemented!();
+	};
+}}
+// ADC - Addition with the carry flag
+def_instr!{ADC, IClassAdc, (f, instr, params, state) => {
+	{ false };
+	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
+	{
 		let ci = state.flag_get(StatusFlags::Carry);
-		let (val, cf) = size_call!( instr.opsize(), add_fwds(a.truncate(), b.truncate(), ci) );
-		state.set(params[0], val);
-		state.flag_set(StatusFlags::Carry, cf);
+		add_instr(state, instr, ci);
 	};
 	{
 		unimplemented!();
 	};
 }}
-fn add_fwds<T:ValueType>(a: Value<T>, b: Value<T>, c: ValueBool) -> (Value<u64>, ValueBool)
+fn add_instr(state: &mut ::disasm::state::State, instr: &::disasm::instruction::Instruction, ci: ValueBool)
+{
+	let params = instr.params();
+	let a = state.get(params[1]);
+	let b = state.get(params[2]);
+	let (val, cf, vf) = size_call!( instr.opsize(), add_fwds(a.truncate(), b.truncate(), ci) );
+	set_nz_flags(state, instr.opsize(), &val);
+	state.flag_set(StatusFlags::Carry, cf);
+	state.flag_set(StatusFlags::Overflow, vf);
+	state.set(params[0], val);
+}
+fn add_fwds<T:ValueType>(a: Value<T>, b: Value<T>, c: ValueBool) -> (Value<u64>, ValueBool, ValueBool)
 {
-	let carry_in = carry_val(c);
-	let rv = a + b + carry_in;
-	// TODO: Set flags based on val (requires changing return of "Add<Value>")
-	let co = ValueBool::Unknown;
-	(rv.zero_extend(), co)
+	let co = flag_over(&a, &b, c, |a,b,c| a.checked_add(&b).and_then(|v| v.checked_add(&bool_val(c))).is_none());
+	let vo = match c
+		{
+		// - Without a carry in, the signed bounds also cover non-enumerable inputs
+		ValueBool::False if !a.is_fixed_set() || !b.is_fixed_set() => a.signed_add_overflows(&b),
+		_ => flag_over(&a, &b, c, |a,b,c| {
+			let r = a.wrapping_add(&b).wrapping_add(&bool_val(c));
+			(a ^ r) & (b ^ r) & sign_mask() != T::zero()
+			}),
+		};
+	let rv = a + b + carry_val(c);
+	(rv.zero_extend(), co, vo)
 }
 
 // SUB - Subtraction of two values into a register
@@ -230,24 +398,53 @@ def_instr!{SUB, IClassSub, (f, instr, params, state) => {
 	{ false };
 	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
 	{
-		let a = state.get(params[1]);
-		let b = state.get(params[2]);
+		sub_instr(state, instr, ValueBool::True);
+		// Record the comparison for branch refinement (only valid if the operands weren't overwritten)
+		if params[0] != params[1] && params[0] != params[2] {
+			state.set_compare(params[1], params[2], instr.opsize());
+		}
+	};
+	{
+		unimplemented!();
+	};
+}}
+// SBC - Subtraction with borrow (inverse of the carry flag)
+def_instr!{SBC, IClassSbc, (f, instr, params, state) => {
+	{ false };
+	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
+	{
 		let ci = state.flag_get(StatusFlags::Carry);
-		let (val, borrowed) = size_call!( instr.opsize(), sub_fwds(a.truncate(), b.truncate(), ci) );
-		state.set(params[0], val);
-		state.flag_set(StatusFlags::Carry, borrowed);
+		sub_instr(state, instr, ci);
 	};
 	{
 		unimplemented!();
 	};
 }}
-fn sub_fwds<T:ValueType>(a: Value<T>, b: Value<T>, c: ValueBool) -> (Value<u64>, ValueBool)
+fn sub_instr(state: &mut ::disasm::state::State, instr: &::disasm::instruction::Instruction, ci: ValueBool)
 {
-	let borrow = carry_val(c);
-	let rv = a - b - borrow;
-	// TODO: Support borrow out
-	let borrow_out = ValueBool::Unknown;
-	(rv.zero_extend(), borrow_out)
+	let params = instr.params();
+	let a = state.get(params[1]);
+	let b = state.get(params[2]);
+	let (val, cf, vf) = size_call!( instr.opsize(), sub_fwds(a.truncate(), b.truncate(), ci) );
+	set_nz_flags(state, instr.opsize(), &val);
+	state.flag_set(StatusFlags::Carry, cf);
+	state.flag_set(StatusFlags::Overflow, vf);
+	state.set(params[0], val);
+}
+/// NOTE: Carry is "not borrow" (as on ARM), both for input and output
+fn sub_fwds<T:ValueType>(a: Value<T>, b: Value<T>, c: ValueBool) -> (Value<u64>, ValueBool, ValueBool)
+{
+	let co = flag_over(&a, &b, c, |a,b,c| a.checked_sub(&b).and_then(|v| v.checked_sub(&bool_val(!c))).is_some());
+	let vo = match c
+		{
+		ValueBool::True if !a.is_fixed_set() || !b.is_fixed_set() => a.signed_sub_overflows(&b),
+		_ => flag_over(&a, &b, c, |a,b,c| {
+			let r = a.wrapping_sub(&b).wrapping_sub(&bool_val(!c));
+			(a ^ b) & (a ^ r) & sign_mask() != T::zero()
+			}),
+		};
+	let rv = a - b - carry_val(!c);
+	(rv.zero_extend(), co, vo)
 }
 
 // AND - bitwise AND of two values into a register
@@ -256,8 +453,8 @@ def_instr!{AND, IClassAnd, (f, instr, params, state) => {
 	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
 	{
 		let val = state.get(params[1]) & state.get(params[2]);
+		set_nz_flags(state, instr.opsize(), &val);
 		state.set(params[0], val);
-		// TODO: Set flags based on val (e.g. ZF)
 	};
 	{
 		unimplemented!();
@@ -270,8 +467,8 @@ def_instr!{OR, IClassOr, (f, instr, params, state) => {
 	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
 	{
 		let val = state.get(params[1]) | state.get(params[2]);
+		set_nz_flags(state, instr.opsize(), &val);
 		state.set(params[0], val);
-		// TODO: Set flags based on val
 	};
 	{
 		unimplemented!();
@@ -284,8 +481,8 @@ def_instr!{XOR, IClassXor, (f, instr, params, state) => {
 	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
 	{
 		let val = state.get(params[1]) ^ state.get(params[2]);
+		set_nz_flags(state, instr.opsize(), &val);
 		state.set(params[0], val);
-		// TODO: Set flags based on val
 	};
 	{
 		unimplemented!();
@@ -298,8 +495,8 @@ def_instr!{MUL, IClassMul, (f, instr, params, state) => {
 	{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
 	{
 		let (_hi,val) = state.get(params[1]) * state.get(params[2]);
+		set_nz_flags(state, instr.opsize(), &val);
 		state.set(params[0], val);
-		// TODO: Set flags based on val
 	};
 	{
 		unimplemented!();
@@ -312,6 +509,7 @@ def_instr!{NOT, IClassNot, (f, instr, params, state) => {
 	{ write!(f, "{:?}, {:?}", params[0], params[1]) };
 	{
 		let val = !state.get(params[1]);
+		set_nz_flags(state, instr.opsize(), &val);
 		state.set(params[0], val);
 	};
 	{
@@ -3,12 +3,14 @@
 //
 // disam/cpus/arm.rs
 // - Recent ARM CPU disassembly (written against ARMv5)
-use value::Value;
+use value::{Value,ValueBool,Relation,sign_extend_raw};
 use disasm::common_instrs;
+use disasm::UnalignedRead;
+use memory::{BankId,Endian};
 use disasm::instruction::COND_ALWAYS;
 use disasm::instruction::{Instruction,InstructionClass};
 use disasm::instruction::{InstrParam,InstrSize};
-//use disasm::state::State;
+use disasm::state::{State,StatusFlags};
 
 trait BitExtractor {
 	fn bits(&self, base: usize, count: usize) -> Self;
@@ -44,13 +46,81 @@ impl ::disasm::CPU for ArmCpu
 			};
 		state.set( InstrParam::TrueReg(15), Value::known(pc_val) );
 	}
+	fn check_condition(&self, state: &mut State, cond: u8) -> ValueBool {
+		let n = state.flag_get(StatusFlags::Negative);
+		let z = state.flag_get(StatusFlags::Zero);
+		let c = state.flag_get(StatusFlags::Carry);
+		let v = state.flag_get(StatusFlags::Overflow);
+		match cond
+		{
+		0x0 => z,	// EQ
+		0x1 => !z,	// NE
+		0x2 => c,	// CS/HS
+		0x3 => !c,	// CC/LO
+		0x4 => n,	// MI
+		0x5 => !n,	// PL
+		0x6 => v,	// VS
+		0x7 => !v,	// VC
+		0x8 => c & !z,	// HI
+		0x9 => !c | z,	// LS
+		0xA => !(n ^ v),	// GE
+		0xB => n ^ v,	// LT
+		0xC => !z & !(n ^ v),	// GT
+		0xD => z | (n ^ v),	// LE
+		_ if cond == COND_ALWAYS => ValueBool::True,
+		_ => panic!("Invalid ARM condition code {:x}", cond),
+		}
+	}
+	fn condition_relation(&self, cond: u8) -> Option<Relation> {
+		// Conditions are evaluated on the flags from `CMP a, b`
+		match cond
+		{
+		0x0 => Some(Relation::Equal),	// EQ
+		0x1 => Some(Relation::NotEqual),	// NE
+		0x2 => Some(Relation::AboveEqual),	// CS/HS
+		0x3 => Some(Relation::Below),	// CC/LO
+		0x8 => Some(Relation::Above),	// HI
+		0x9 => Some(Relation::BelowEqual),	// LS
+		0xA => Some(Relation::GreaterEqual),	// GE
+		0xB => Some(Relation::Less),	// LT
+		0xC => Some(Relation::Greater),	// GT
+		0xD => Some(Relation::LessEqual),	// LE
+		// MI/PL/VS/VC test single flags
+		_ => None,
+		}
+	}
+	fn unaligned_read(&self, size: u8) -> UnalignedRead {
+		match size
+		{
+		// LDR reads the aligned word and rotates the addressed byte into the bottom (pre-ARMv6)
+		4 => UnalignedRead::Rotate,
+		// LDRH from an odd address is unpredictable
+		2 => UnalignedRead::Unpredictable,
+		_ => UnalignedRead::Split,
+		}
+	}
+	fn default_endian(&self) -> Endian {
+		// Can be configured as big endian (BE-32), but little endian is by far the most common
+		Endian::Little
+	}
+	fn calling_convention(&self, name: &str) -> Option<::disasm::CallingConv> {
+		match name
+		{
+		// R0-R3, R12 and LR aren't preserved
+		"aapcs" => Some(::disasm::CallingConv {
+			clobbers: [0,1,2,3,12,14].iter().map(|&r| r as usize).collect(),
+			offsets: Vec::new(),
+			}),
+		_ => None,
+		}
+	}
 	
-	fn disassemble(&self, mem: &::memory::MemoryState, addr: u64, mode: ::disasm::CPUMode) -> Result<Instruction,()>
+	fn disassemble(&self, mem: &::memory::MemoryState, addr: u64, mode: ::disasm::CPUMode, bank: BankId) -> Result<Instruction,()>
 	{
 		match mode
 		{
-		0 => disassemble_arm(mem, addr),
-		1 => disassemble_thumb(mem, addr),
+		0 => disassemble_arm(mem, bank, addr),
+		1 => disassemble_thumb(mem, bank, addr),
 		_ => panic!("Invalid ARM mode"),
 		}
 	}
@@ -59,9 +129,9 @@ impl ::disasm::CPU for ArmCpu
 
 #[allow(non_snake_case)]
 /// Disassemble code in ARM mode (32-bits per instruction)
-fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction,()>
+fn disassemble_arm(mem: &::memory::MemoryState, bank: BankId, addr: u64) -> Result<Instruction,()>
 {
-	let word = try!(readmem::<u32>(mem, addr));
+	let word = try!(readmem::<u32>(mem, bank, addr));
 
 	let ccode = match (word >> 28) as u8
 		{
@@ -86,7 +156,7 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 				reg(word, 0),
 				InstrParam::Immediate( match (word>>20)&3 { 0=>0,1=>0,2=>0,_=>0 } ),
 				]
-			)
+			).set_flags()
 		},
 	// Branch+Exchange Register
 	0x121 => Instruction::new( 4, ccode, InstrSize::SizeNA, &instrs::BX, vec![ reg(word, 0) ] ),
@@ -118,6 +188,13 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 			reg(word, 12), reg(word, 16), InstrParam::Immediate( expand_imm_arm(word & 0xFFF) ),
 			]
 		),
+	// CMP Rn, #imm
+	0x350 ... 0x35F => Instruction::new(
+		4, ccode, InstrSize::Size32, &common_instrs::SUB,	// < Use SUB and assign to #tr0
+		vec![
+			InstrParam::TmpReg(0), reg(word, 16), InstrParam::Immediate( expand_imm_arm(word & 0xFFF) ),
+			]
+		).set_flags(),
 	0x3A0 ... 0x3BF => {
 		// Mov Rd, immediate
 		let Rd = ((word >> 12) & 0xF) as u8;
@@ -125,14 +202,16 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 			// TODO: Handle moving to R15 (aka PC)
 			panic!("TODO: Handle move immediate to PC");
 		}
-		Instruction::new(
+		let i = Instruction::new(
 			4, ccode, InstrSize::Size32,
 			&common_instrs::MOVE,
 			vec![
 				InstrParam::TrueReg( Rd ),
 				InstrParam::Immediate( expand_imm_arm(word & 0xFFF) ),
 				]
-			)
+			);
+		// MOVS (S bit set) updates the flags
+		if (word >> 20) & 1 != 0 { i.set_flags() } else { i }
 		},
 	// STR Rd, [Rn,#imm12]
 	0x580 ... 0x58F => Instruction::new(
@@ -141,7 +220,7 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 		vec![
 			InstrParam::TrueReg( ((word>>12)&0xF) as u8 ),
 			InstrParam::TrueReg( ((word>>16)&0xF) as u8 ),
-			InstrParam::Immediate( sign_extend(12, word & 0xFFF) ),
+			InstrParam::Immediate( sign_extend_raw(12, (word & 0xFFF) as u64) ),
 			]
 		),
 	// LDR Rd, [Rn,#imm12]
@@ -151,7 +230,7 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 		vec![
 			InstrParam::TrueReg( ((word>>12)&0xF) as u8 ),
 			InstrParam::TrueReg( ((word>>16)&0xF) as u8 ),
-			InstrParam::Immediate( sign_extend(12, word & 0xFFF) ),
+			InstrParam::Immediate( sign_extend_raw(12, (word & 0xFFF) as u64) ),
 			]
 		),
 	0xA00 ... 0xAFF => {
@@ -159,7 +238,7 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 		Instruction::new(
 			4, ccode, InstrSize::SizeNA,
 			&common_instrs::JUMP as &InstructionClass,
-			vec![ InstrParam::Immediate(addr + 8 + sign_extend(24, word & 0xFFFFFF) * 4), ]
+			vec![ InstrParam::Immediate(addr + 8 + sign_extend_raw(24, (word & 0xFFFFFF) as u64) * 4), ]
 			)
 		},
 	0xB00 ... 0xBFF => {
@@ -167,7 +246,7 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 		Instruction::new(
 			4, ccode, InstrSize::SizeNA,
 			&common_instrs::CALL as &InstructionClass,
-			vec![ InstrParam::Immediate(addr + 8 + sign_extend(24, word & 0xFFFFFF) * 4), ]
+			vec![ InstrParam::Immediate(addr + 8 + sign_extend_raw(24, (word & 0xFFFFFF) as u64) * 4), ]
 			)
 		},
 	_ => {
@@ -179,9 +258,9 @@ fn disassemble_arm(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction
 
 #[allow(non_snake_case)]	// Suppresses warning on Rd/Rn/Rt
 /// Disassemble in THUMB mode
-fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instruction,()>
+fn disassemble_thumb(mem: &::memory::MemoryState, bank: BankId, addr: u64) -> Result<Instruction,()>
 {
-	let word = try!(readmem::<u16>(mem, addr));
+	let word = try!(readmem::<u16>(mem, bank, addr));
 
 	Ok(match word >> 10
 	{
@@ -189,49 +268,49 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 	0x00 ... 0x01 => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SHL,
 		vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate( word.bits(6,5) as u64) ]
-		),
+		).set_flags(),
 	// Logical Shift Right
 	0x02 ... 0x03 => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SHR,
 		vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate( word.bits(6,5) as u64) ]
-		),
+		).set_flags(),
 	// Arithmetic Shift Right
 	0x04 ... 0x05 => Instruction::new(
-		2, COND_ALWAYS, InstrSize::Size32, &instrs::ASR,
+		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::ASR,
 		vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate( word.bits(6,5) as u64) ]
-		),
+		).set_flags(),
 	// Add/Sub reg
 	0x06 => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32,
 		if (word >> 9) & 1 != 0 { &common_instrs::SUB } else { &common_instrs::ADD },
 		vec![ reg_t(word, 0), reg_t(word, 3), reg_t(word, 6) ]
-		),
+		).set_flags(),
 	// Add/Sub imm3
 	0x07 => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32,
 		if (word >> 9) & 1 != 0 { &common_instrs::SUB } else { &common_instrs::ADD },
 		vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate( word.bits(6,3) as u64 ) ]
-		),
+		).set_flags(),
 	// MOV Rd, #imm8
 	0x08 ... 0x09 => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::MOVE,
 		vec![ reg_t(word, 8), InstrParam::Immediate( (word & 0xFF) as u64 ) ]
-		),
+		).set_flags(),
 	// CMP Rd, #imm8
 	0x0a ... 0x0b => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SUB,	// < Use SUB and assign to #tr0
 		vec![ InstrParam::TmpReg(0), reg_t(word, 8), InstrParam::Immediate( (word & 0xFF) as u64 ) ]
-		),
+		).set_flags(),
 	// ADD Rd, Rd, #imm8
 	0x0c ... 0x0d => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::ADD,
 		vec![ reg_t(word,8), reg_t(word,8), InstrParam::Immediate( (word & 0xFF) as u64 ) ]
-		),
+		).set_flags(),
 	// SUB Rd, Rd, #imm8
 	0x0e ... 0x0f => Instruction::new(
 		2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SUB,
 		vec![ reg_t(word,8), reg_t(word,8), InstrParam::Immediate( (word & 0xFF) as u64 ) ]
-		),
+		).set_flags(),
 	// 0x10 - Data Processing (Sect A6-8)
 	0x10 => match (word >> 6) & 0xF
 		{
@@ -243,9 +322,9 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 				0x1 => &common_instrs::XOR as &InstructionClass,
 				0x2 => &common_instrs::SHL as &InstructionClass,
 				0x3 => &common_instrs::SHR as &InstructionClass,
-				0x4 => &instrs::ASR        as &InstructionClass,
-				0x5 => &common_instrs::ADD as &InstructionClass,
-				0x6 => &common_instrs::SUB as &InstructionClass,
+				0x4 => &common_instrs::ASR as &InstructionClass,
+				0x5 => &common_instrs::ADC as &InstructionClass,
+				0x6 => &common_instrs::SBC as &InstructionClass,
 				0x7 => &common_instrs::ROR as &InstructionClass,
 				0xc => &common_instrs::OR  as &InstructionClass,
 				0xd => &common_instrs::MUL as &InstructionClass,
@@ -253,22 +332,22 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 				_ => panic!("ARM THUMB 0x10:{{0-7,c-e}} Unmatched {}", v)
 				},
 				vec![ reg_t(word, 0), reg_t(word,0), reg_t(word,3) ]
-			),
+			).set_flags(),
 		// - TEST Rt, Rn
 		0x8 => Instruction::new(
 			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::AND,
 			vec![ InstrParam::TmpReg(0), reg_t(word, 0), reg_t(word,3) ]
-			),
+			).set_flags(),
 		// - Reverse Subtract (RSB) (Negate?)
 		0x9 => Instruction::new(
 			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SUB,
 			vec![ reg_t(word, 0), InstrParam::Immediate(0), reg_t(word,3) ]
-			),
+			).set_flags(),
 		// - CMP Rt, Rn
 		0xA => Instruction::new(
 			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SUB,
 			vec![ InstrParam::TmpReg(0), reg_t(word, 0), reg_t(word,3) ]
-			),
+			).set_flags(),
 		0xB => {
 			error!("ARM THUMB 0x10:B Undefined");
 			return Err( () );
@@ -277,7 +356,7 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 		0xF => Instruction::new(
 			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::NOT,
 			vec![ reg_t(word, 0), reg_t(word,3) ]
-			),
+			).set_flags(),
 		v @ _ => panic!("ARM THUMB 0x10 Unmatched {:x}", v)
 		},
 	// 0x11: Special data instructions, branch and exchange
@@ -310,7 +389,7 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 		0x5 ... 0x7 => Instruction::new(
 				2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SUB,
 				vec![ InstrParam::TmpReg(0), InstrParam::TrueReg(Rd), reg(word as u32, 3) ]
-			),
+			).set_flags(),
 		// Move Register (High)
 		0x9 ... 0xb => {
 			let Rn = (word >> 3) & 0xF;
@@ -386,6 +465,24 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SUB,
 			vec![ InstrParam::TrueReg(13), InstrParam::TrueReg(13), InstrParam::Immediate( (word.bits(0,5) * 4) as u64 ) ]
 			),
+		// SXTH/SXTB Rd, Rm
+		0x10 ... 0x11 => Instruction::new(
+			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SEXT,
+			vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate(16) ]
+			),
+		0x12 ... 0x13 => Instruction::new(
+			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::SEXT,
+			vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate(8) ]
+			),
+		// UXTH/UXTB Rd, Rm
+		0x14 ... 0x15 => Instruction::new(
+			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::AND,
+			vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate(0xFFFF) ]
+			),
+		0x16 ... 0x17 => Instruction::new(
+			2, COND_ALWAYS, InstrSize::Size32, &common_instrs::AND,
+			vec![ reg_t(word, 0), reg_t(word, 3), InstrParam::Immediate(0xFF) ]
+			),
 		v @ _ => {
 			error!("Unknown opcode 2C:{:x}", v);
 			return Err( () );
@@ -432,7 +529,7 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 		{
 		0x0 ... 0xD => Instruction::new(
 			2, word.bits(8,4) as u8, InstrSize::SizeNA, &common_instrs::JUMP,
-			vec![ InstrParam::Immediate(addr + 4 + sign_extend(9, (word.bits(0,8)*2) as u32)) ]
+			vec![ InstrParam::Immediate(addr + 4 + sign_extend_raw(9, (word.bits(0,8)*2) as u64)) ]
 			),
 		0xE => return Err( () ),
 		0xF => Instruction::new(
@@ -444,11 +541,11 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 	// B imm11
 	0x38 ... 0x39 => Instruction::new(
 		2, COND_ALWAYS, InstrSize::SizeNA, &common_instrs::JUMP,
-		vec![ InstrParam::Immediate(addr + 4 + sign_extend(12, (word.bits(0,11)*2) as u32)) ]
+		vec![ InstrParam::Immediate(addr + 4 + sign_extend_raw(12, (word.bits(0,11)*2) as u64)) ]
 		),
 	// 32-bit instructions
 	0x3a ... 0x3f => {
-		let word2 = try!(readmem::<u16>(mem, addr+2));
+		let word2 = try!(readmem::<u16>(mem, bank, addr+2));
 		match (word >> 11) & 3
 		{
 		0 => {
@@ -503,11 +600,11 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 					if (word2>>12) & 1 == 0 {
 						// Switch to ARM mode
 						Instruction::new(4, COND_ALWAYS, InstrSize::SizeNA, &instrs::BLX,
-							vec![ InstrParam::Immediate( addr + 4 + sign_extend(25, ofs) ) ])
+							vec![ InstrParam::Immediate( addr + 4 + sign_extend_raw(25, ofs as u64) ) ])
 					}
 					else {
 						Instruction::new(4, COND_ALWAYS, InstrSize::SizeNA, &common_instrs::CALL,
-							vec![ InstrParam::Immediate( addr + 4 + sign_extend(25, ofs) ) ])
+							vec![ InstrParam::Immediate( addr + 4 + sign_extend_raw(25, ofs as u64) ) ])
 					}
 					},
 				v @ _ => {
@@ -559,10 +656,9 @@ fn disassemble_thumb(mem: &::memory::MemoryState, addr: u64) -> Result<Instructi
 	})
 }
 
-fn readmem<T: ::value::ValueType+::memory::MemoryStateAccess>(mem: &::memory::MemoryState, addr: u64) -> Result<T,()>
+fn readmem<T: ::value::ValueType+::memory::MemoryStateAccess>(mem: &::memory::MemoryState, bank: BankId, addr: u64) -> Result<T,()>
 {
-	use memory::MemoryStateAccess;
-	match MemoryStateAccess::read(mem, addr)
+	match mem.read_in_bank(bank, addr)
 	{
 	Some(Value::Known(x)) => Ok(x),
 	Some(_) => {
@@ -579,16 +675,6 @@ fn readmem<T: ::value::ValueType+::memory::MemoryStateAccess>(mem: &::memory::Me
 // ---
 // Helpers
 // ---
-fn sign_extend(bits: usize, value: u32) -> u64
-{
-	if value >> (bits-1) != 0 {
-		(value as u64) | ( ::std::u64::MAX << bits )
-	}
-	else {
-		(value as u64)
-	}
-}
-
 fn expand_imm_arm(imm12: u32) -> u64
 {
 	let val_ur = imm12 & 0xFF;
@@ -609,6 +695,8 @@ mod instrs
 {
 	use value::{Value,ValueBool};
 	use disasm::instruction::InstrParam;
+	use disasm::state::StatusFlags;
+	use disasm::common_instrs::set_nz_flags;
 
 	// Set system register
 	def_instr!{SET_SREG, InstrSetSReg, (f,instr,p,state) => {
@@ -621,6 +709,14 @@ mod instrs
 				};
 			let val = state.get(p[1]);
 			warn!("TODO: Assign SReg {} value {:?}", regid, val);
+			// - The top four bits of the CPSR are the flags
+			if regid == super::SReg::CPSR as u64 {
+				state.flag_set(StatusFlags::Negative, val.bit(31));
+				state.flag_set(StatusFlags::Zero, val.bit(30));
+				state.flag_set(StatusFlags::Carry, val.bit(29));
+				state.flag_set(StatusFlags::Overflow, val.bit(28));
+				state.clear_compare();
+			}
 		};
 		{
 			unimplemented!();
@@ -654,6 +750,14 @@ mod instrs
 			{
 				state.jump(addr & Value::known(!1), 0)
 			}
+			else if addr.is_fixed_set()
+			{
+				// Mixed modes, queue each target with its own mode
+				for a in addr.possibilities()
+				{
+					state.jump(Value::known(a & !1), (a & 1) as ::disasm::CPUMode);
+				}
+			}
 			else
 			{
 				// No idea! Can't add target
@@ -698,11 +802,12 @@ mod instrs
 			debug!("mask={:x}", mask);
 			for i in (0 .. 16).rev() {
 				if mask & 1 << i != 0 {
-					// TODO: Decrement stack pointer
 					let val = state.get( InstrParam::TrueReg(i as u8) );
 					state.stack_push( val );
 				}
 			}
+			let sp = state.get( InstrParam::TrueReg(13) );
+			state.set( InstrParam::TrueReg(13), sp - Value::known(4 * mask.count_ones() as u64) );
 		};
 		{
 			let _ = p;
@@ -731,11 +836,12 @@ mod instrs
 			debug!("mask={:x}", mask);
 			for i in (0 .. 16) {
 				if mask & 1 << i != 0 {
-					// TODO: Decrement stack pointer
 					let val = state.stack_pop();
 					state.set( InstrParam::TrueReg(i as u8), val );
 				}
 			}
+			let sp = state.get( InstrParam::TrueReg(13) );
+			state.set( InstrParam::TrueReg(13), sp + Value::known(4 * mask.count_ones() as u64) );
 		};
 		{
 			let _ = p;
@@ -810,43 +916,6 @@ mod instrs
 		};
 	}}
 
-	// ASR - Arithmetic Shift Right
-	def_instr!{ASR, IClassAsr, (f, instr, params, state) => {
-		{ false };
-		{ write!(f, "{:?}, {:?}, {:?}", params[0], params[1], params[2]) };
-		{
-			let v = state.get(params[1]);
-			let count = state.get(params[2]);
-			if let Some(c) = count.val_known()
-			{
-				let base_mask = match v.bit(v.bitsize()-1)
-					{
-					ValueBool::Unknown => Value::unknown(),
-					ValueBool::True => Value::ones(),
-					ValueBool::False => Value::zero(),
-					};
-				if c >= v.bitsize() as u64 {
-					warn!("Overshift in ASR {} >= {}", c, v.bitsize());
-					state.set(params[0], base_mask);
-				}
-				else {
-					let c = c as usize;
-					let (_,mask) = base_mask << c;
-					let (_out,base_val) = v >> c;
-					let val = base_val | mask;
-					state.set(params[0], val);
-					//state.set_flag(FlagCarry, extra & Value::known(1))
-				}
-			}
-			else
-			{
-				warn!("TODO: ASR by a set/range of values");
-				state.set(params[0], Value::unknown());
-			}
-		};
-		{ unimplemented!(); };
-	}}
-
 	// BIC - Bit Clear
 	// AND with NOT of provided mask
 	def_instr!{BIC, IClassBic, (f, instr, params, state) => {
@@ -856,10 +925,85 @@ mod instrs
 			let v = state.get(params[1]);
 			let mask = state.get(params[2]);
 			let val = v & !mask;
+			set_nz_flags(state, instr.opsize(), &val);
 			state.set(params[0], val);
 		};
 		{ unimplemented!(); };
 	}}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use disasm::CPU;
+	use disasm::state::RunMode;
+	
+	#[test]
+	fn conditions()
+	{
+		let mem = ::memory::MemoryState::new();
+		let mut state = State::null(RunMode::Parse, &CPU_STRUCT, &mem);
+		// - Flags after comparing 3 with 5 (signed and unsigned less than)
+		state.flag_set(StatusFlags::Negative, ValueBool::True);
+		state.flag_set(StatusFlags::Zero, ValueBool::False);
+		state.flag_set(StatusFlags::Carry, ValueBool::False);
+		state.flag_set(StatusFlags::Overflow, ValueBool::False);
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0x0), ValueBool::False );	// EQ
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0x3), ValueBool::True );	// CC
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0x9), ValueBool::True );	// LS
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0xB), ValueBool::True );	// LT
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0xC), ValueBool::False );	// GT
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, COND_ALWAYS), ValueBool::True );
+		// - Unknown flags give unknown outcomes
+		state.flag_set(StatusFlags::Zero, ValueBool::Unknown);
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0x1), ValueBool::Unknown );	// NE
+		assert_eq!( CPU_STRUCT.check_condition(&mut state, 0x9), ValueBool::True );	// LS (carry clear)
+	}
+	
+	#[test]
+	fn condition_relations()
+	{
+		assert_eq!( CPU_STRUCT.condition_relation(0x3), Some(Relation::Below) );
+		assert_eq!( CPU_STRUCT.condition_relation(0xA), Some(Relation::GreaterEqual) );
+		assert_eq!( CPU_STRUCT.condition_relation(0x4), None );	// MI
+	}
+	
+	#[test]
+	fn unaligned_reads()
+	{
+		let mut mem = ::memory::MemoryState::new();
+		mem.set_endian(::memory::Endian::Little);
+		mem.add_rom_data(0x1000, vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+		let mut state = State::null(RunMode::Parse, &CPU_STRUCT, &mem);
+		// - LDR rotates the aligned word
+		assert_eq!( state.read::<u32>(&Value::known(0x1001)).val_known(), Some(0x11443322) );
+		assert_eq!( state.read::<u32>(&Value::known(0x1004)).val_known(), Some(0x88776655) );
+		// - LDRH from an odd address is unpredictable, bytes can be read from anywhere
+		assert!( state.read::<u16>(&Value::known(0x1001)).is_unknown() );
+		assert_eq!( state.read::<u8>(&Value::known(0x1003)).val_known(), Some(0x44) );
+	}
+
+	#[test]
+	fn mov_flags()
+	{
+		let mut mem = ::memory::MemoryState::new();
+		mem.set_endian(::memory::Endian::Little);
+		// MOV r0, #4 ; MOVS r0, #4
+		mem.add_rom_data(0x1000, vec![0x01,0x0F,0xA0,0xE3, 0x01,0x0F,0xB0,0xE3]);
+		let mov = CPU_STRUCT.disassemble(&mem, 0x1000, 0, 0).unwrap();
+		let movs = CPU_STRUCT.disassemble(&mem, 0x1004, 0, 0).unwrap();
+		assert!( !mov.sets_flags() );
+		assert!( movs.sets_flags() );
+
+		let mut state = State::null(RunMode::Parse, &CPU_STRUCT, &mem);
+		state.flag_set(StatusFlags::Zero, ValueBool::True);
+		// - Without the S bit the flags are left alone
+		state.run(&mov);
+		assert_eq!( state.flag_get(StatusFlags::Zero), ValueBool::True );
+		state.run(&movs);
+		assert_eq!( state.flag_get(StatusFlags::Zero), ValueBool::False );
+	}
+}
+
 // vim: ft=rust
@@ -3,6 +3,10 @@
 //
 use value::Value;
 use disasm::instruction::Instruction;
+use disasm::CallingConv;
+
+// Registers are numbered by their encoding (EAX=0, ECX, EDX, EBX, ESP, EBP, ESI, EDI, then R8-R15)
+const REG_ESP: u8 = 4;
 
 pub struct Intel32CPU;
 
@@ -16,8 +20,47 @@ impl ::disasm::CPU for Intel32CPU
 	fn prep_state(&self, _state: &mut ::disasm::state::State, _addr: u64, _mode: u32) {
 		// X86 doesn't need any pre-instruction prep
 	}
+	fn condition_relation(&self, _cond: u8) -> Option<::value::Relation> {
+		None
+	}
+	fn check_condition(&self, _state: &mut ::disasm::state::State, _cond: u8) -> ::value::ValueBool {
+		::value::ValueBool::Unknown
+	}
+	fn unaligned_read(&self, _size: u8) -> ::disasm::UnalignedRead {
+		::disasm::UnalignedRead::Split
+	}
+	fn default_endian(&self) -> ::memory::Endian {
+		::memory::Endian::Little
+	}
+	fn calling_convention(&self, name: &str) -> Option<CallingConv> {
+		// - "stdcall@<n>" gives the number of argument bytes the callee removes (e.g. from a decorated name)
+		let (name, arg_bytes) = match name.find('@')
+			{
+			Some(pos) => match name[pos+1 ..].parse::<u64>()
+				{
+				Ok(n) => (&name[.. pos], Some(n)),
+				Err(_) => return None,
+				},
+			None => (name, None),
+			};
+		// (clobbered registers, stack pointer offset on return)
+		let (clobbers, sp_ofs): (&[u8], u64) = match name
+			{
+			// Caller removes arguments, so only the return address is popped
+			"cdecl" if arg_bytes.is_none() => (&[0,1,2], 4),
+			// Callee removes arguments, if their size isn't known none are assumed (keeps ESP relative to its entry value)
+			"stdcall" => (&[0,1,2], 4 + arg_bytes.unwrap_or(0)),
+			// Microsoft x64: RAX, RCX, RDX, R8-R11 are volatile, caller removes arguments
+			"win64" if arg_bytes.is_none() => (&[0,1,2,8,9,10,11], 8),
+			_ => return None,
+			};
+		let mut cc = CallingConv { clobbers: clobbers.iter().map(|&r| r as usize).collect(), offsets: Vec::new() };
+		cc.clobbers.insert(REG_ESP as usize);
+		cc.offsets.push( (REG_ESP, sp_ofs) );
+		Some(cc)
+	}
 	
-	fn disassemble(&self, mem: &::memory::MemoryState, addr: u64, mode: u32) -> Result<Instruction,()>
+	fn disassemble(&self, mem: &::memory::MemoryState, addr: u64, mode: u32, _bank: ::memory::BankId) -> Result<Instruction,()>
 	{
 		assert!( mode == 0 );
 		let val = match mem.read_u8(addr)
@@ -37,4 +80,26 @@ impl ::disasm::CPU for Intel32CPU
 }
 
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use disasm::CPU;
+	
+	#[test]
+	fn calling_conventions()
+	{
+		let sp_ofs = |name: &str| CPU_STRUCT.calling_convention(name).map(|cc| cc.offsets);
+		assert_eq!( sp_ofs("cdecl"), Some(vec![(REG_ESP, 4)]) );
+		// - stdcall callees remove their arguments
+		assert_eq!( sp_ofs("stdcall@8"), Some(vec![(REG_ESP, 12)]) );
+		assert_eq!( sp_ofs("stdcall"), Some(vec![(REG_ESP, 4)]) );
+		assert_eq!( sp_ofs("win64"), Some(vec![(REG_ESP, 8)]) );
+		assert!( CPU_STRUCT.calling_convention("win64").unwrap().clobbers.contains(11) );
+		assert!( CPU_STRUCT.calling_convention("cdecl@4").is_none() );
+		assert!( CPU_STRUCT.calling_convention("stdcall@x").is_none() );
+		assert!( CPU_STRUCT.calling_convention("fastcall").is_none() );
+	}
+}
+
 // vim: ft=rust
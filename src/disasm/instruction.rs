@@ -15,12 +15,37 @@ pub struct Instruction
 	pub len: u8,
 	
 	condition: u8,
+	/// Outcome of the condition when the block is entered at its start (only an annotation, the
+	/// condition is still checked against each state)
+	static_outcome: ::value::ValueBool,
 	opsize: InstrSize,
+	sets_flags: bool,
 	pub class: &'static InstructionClass,
 	params: Vec<InstrParam>,
 	
 	is_target: bool,
 	is_call_target: bool,
+	
+	/// Problems found while disassembling or running the instruction
+	diagnostics: Vec<Diagnostic>,
+}
+
+/// An access by an instruction that the memory map doesn't permit
+#[derive(PartialEq,Clone,Debug)]
+pub enum Diagnostic
+{
+	/// Decoded from memory that isn't executable (e.g. MMIO)
+	ExecuteDenied(u64),
+	/// Decoded from unmapped memory
+	ExecuteUnmapped(u64),
+	/// Read from memory that isn't readable
+	ReadDenied(u64),
+	/// Read from unmapped memory
+	ReadUnmapped(u64),
+	/// Write to memory that isn't writable (e.g. ROM)
+	WriteDenied(u64),
+	/// Write to unmapped memory
+	WriteUnmapped(u64),
 }
 
 /// Instruction parameter
@@ -71,13 +96,25 @@ impl Instruction
 			ip: CodePtr::new(0, 0),
 			len: len,
 			condition: condition,
+			static_outcome: ::value::ValueBool::Unknown,
 			opsize: opsize,
+			sets_flags: false,
 			class: class,
 			params: params,
 			is_target: false,
 			is_call_target: false,
+			diagnostics: Vec::new(),
 		}
 	}
+	/// Mark the instruction as updating the status flags
+	pub fn set_flags(mut self) -> Instruction {
+		self.sets_flags = true;
+		self
+	}
+	/// Record the outcome of the condition when the block is entered at its start
+	pub fn set_static_outcome(&mut self, outcome: ::value::ValueBool) {
+		self.static_outcome = outcome;
+	}
 	pub fn set_addr(&mut self, addr: CodePtr) {
 		self.ip = addr;
 	}
@@ -88,6 +125,13 @@ impl Instruction
 		self.is_call_target = true;
 	}
 	
+	/// Attach a diagnostic (ignored if already attached)
+	pub fn add_diagnostic(&mut self, diag: Diagnostic) {
+		if ! self.diagnostics.contains(&diag) {
+			self.diagnostics.push(diag);
+		}
+	}
+	
 	pub fn is_target(&self) -> bool { self.is_target }
 	pub fn is_call_target(&self) -> bool { self.is_call_target }
 	
@@ -97,14 +141,26 @@ impl Instruction
 	pub fn is_terminal(&self) -> bool {
 		self.condition == COND_ALWAYS && self.class.is_terminal(&self.params)
 	}
+	/// Check if the instruction transfers control elsewhere when its condition passes (e.g. a branch)
+	pub fn is_jump(&self) -> bool {
+		self.class.is_terminal(&self.params)
+	}
 	pub fn is_conditional(&self) -> bool {
 		self.condition != COND_ALWAYS
 	}
+	/// Check if the condition has a statically known outcome (so the instruction doesn't split its block)
+	pub fn is_resolved(&self) -> bool {
+		self.static_outcome != ::value::ValueBool::Unknown
+	}
+	pub fn sets_flags(&self) -> bool { self.sets_flags }
 
 	pub fn addr(&self) -> CodePtr { self.ip }
 	pub fn mode(&self) -> super::CPUMode { self.ip.mode() }
 	pub fn opsize(&self) -> InstrSize { self.opsize }
+	pub fn condition(&self) -> u8 { self.condition }
+	pub fn static_outcome(&self) -> ::value::ValueBool { self.static_outcome }
 	pub fn params(&self) -> &[InstrParam] { &self.params }
+	pub fn diagnostics(&self) -> &[Diagnostic] { &self.diagnostics }
 }
 
 impl ::std::fmt::Debug for Instruction
@@ -129,6 +185,22 @@ impl ::std::fmt::Display for Instruction
 	}
 }
 
+impl ::std::fmt::Display for Diagnostic
+{
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+	{
+		match self
+		{
+		&Diagnostic::ExecuteDenied(a) => write!(f, "executing non-executable memory {:#x}", a),
+		&Diagnostic::ExecuteUnmapped(a) => write!(f, "executing unmapped memory {:#x}", a),
+		&Diagnostic::ReadDenied(a) => write!(f, "reading non-readable memory {:#x}", a),
+		&Diagnostic::ReadUnmapped(a) => write!(f, "reading unmapped memory {:#x}", a),
+		&Diagnostic::WriteDenied(a) => write!(f, "writing non-writable memory {:#x}", a),
+		&Diagnostic::WriteUnmapped(a) => write!(f, "writing unmapped memory {:#x}", a),
+		}
+	}
+}
+
 // --------------------------------------------------------------------
 impl InstrParam
 {
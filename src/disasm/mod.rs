@@ -5,11 +5,12 @@
 // - Disassembly core
 use self::state::{State,RunMode};
 use self::block::Block;
+use self::instruction::Diagnostic;
 //use sortedlist::SortedList;	// Allows treating of collection types as sorted lists
-use std::collections::{HashSet,HashMap};
+use std::collections::{HashSet,HashMap,BTreeSet};
 use bit_set::BitSet;
 use std::default::Default;
-use value::Value;
+use value::{Value,ValueBool};
 
 #[macro_use] mod common_instrs;
 mod state;
@@ -18,25 +19,65 @@ mod instruction;
 mod block;
 pub mod cpus;
 
+/// Number of times a block's entry state is merged normally before values are widened
+const WIDEN_AFTER: usize = 4;
+/// Maximum number of times a block is re-run while looking for a fixed point
+const MAX_BLOCK_VISITS: usize = 64;
+
 pub type CPUMode = u32;
+/// Address of code (with the bank it's in, 0 outside banked regions)
 #[derive(Copy,PartialEq,PartialOrd,Eq,Ord,Clone,Hash)]
-pub struct CodePtr(CPUMode, u64);
+pub struct CodePtr(CPUMode, ::memory::BankId, u64);
 
 #[derive(Copy,PartialEq,Eq,Clone,Hash)]
 pub struct CodeRange(CodePtr, CodePtr);
 
+/// Behaviour of a multi-byte read from an address that isn't a multiple of the read size
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum UnalignedRead
+{
+	/// Bytes are read from consecutive addresses
+	Split,
+	/// The aligned value is read, then rotated so the addressed byte is lowest (e.g. ARMv4 LDR)
+	Rotate,
+	/// The result is unpredictable
+	Unpredictable,
+}
+
+/// Register usage of a calling convention, used for functions that can't be analysed (e.g. imports)
+///
+/// NOTE: The number of arguments isn't known, so no registers are taken as inputs
+pub struct CallingConv
+{
+	pub clobbers: BitSet,
+	/// Clobbered registers that only have a constant offset applied (e.g. the stack pointer)
+	pub offsets: Vec<(u8,u64)>,
+}
+
 pub trait CPU
 {
 	/// Return the number of CPU-defined registers
 	fn num_regs(&self) -> u16;
 	
-	/// Disassemble a single instruction
-	fn disassemble(&self, &::memory::MemoryState, u64, CPUMode) -> Result<instruction::Instruction,()>;
+	/// Disassemble a single instruction (from the given bank, if the address is within a banked region)
+	fn disassemble(&self, &::memory::MemoryState, u64, CPUMode, ::memory::BankId) -> Result<instruction::Instruction,()>;
 	/// Prepare state for exection of an instruction at the specified address
 	fn prep_state(&self, &mut state::State, u64, CPUMode);
 	
-	//// Check the outcome of a condition code check
-	//fn check_condition(&self, &mut state::State, u8) -> ValueBool;
+	/// Get the relation between the last compared values that a condition code tests
+	///
+	/// Returns None if the condition isn't a simple comparison (e.g. tests the sign flag)
+	fn condition_relation(&self, u8) -> Option<::value::Relation>;
+	
+	/// Check the outcome of a condition code check
+	fn check_condition(&self, &mut state::State, u8) -> ::value::ValueBool;
+	
+	/// Get the behaviour of an unaligned read of the specified size (in bytes)
+	fn unaligned_read(&self, u8) -> UnalignedRead;
+	/// Byte order used if the memory map doesn't specify one
+	fn default_endian(&self) -> ::memory::Endian;
+	/// Get a calling convention by name (e.g. "cdecl"), None if the CPU doesn't have it
+	fn calling_convention(&self, &str) -> Option<CallingConv>;
 }
 
 pub struct Disassembled<'a>
@@ -48,6 +89,14 @@ pub struct Disassembled<'a>
 	todo_list: HashSet<CodePtr>,
 	// TODO: Store is_call flag
 	method_list: HashMap<CodePtr,block::Function>,
+	/// Addresses execution starts from (e.g. the reset vector), their blocks are run from a null state
+	entrypoints: HashSet<CodePtr>,
+	/// Set when blocks or edges are added, so block states need recomputing
+	cfg_changed: bool,
+	/// Memory generation that block states were last computed against
+	mem_generation: usize,
+	/// Annotations for instructions (e.g. names of accessed hardware registers)
+	annotations: HashMap<CodePtr, Vec<String>>,
 }
 
 impl<'a> Disassembled<'a>
@@ -60,6 +109,10 @@ impl<'a> Disassembled<'a>
 			blocks: Vec::new(),
 			todo_list: Default::default(),
 			method_list: Default::default(),
+			entrypoints: Default::default(),
+			cfg_changed: false,
+			mem_generation: 0,
+			annotations: Default::default(),
 		}
 	}
 	/// Count total instructions converted
@@ -81,6 +134,14 @@ impl<'a> Disassembled<'a>
 			{
 				try!(write!(f, "\n"));
 				try!(write!(f, "{}\n", info));
+				for (base, fields) in info.field_layouts()
+				{
+					try!(write!(f, "; {:?}:", base));
+					for (ofs, size) in fields {
+						try!(write!(f, " +{:#x}:{}", ofs, size));
+					}
+					try!(write!(f, "\n"));
+				}
 				try!(write!(f, "@"));
 			}
 			else
@@ -89,7 +150,20 @@ impl<'a> Disassembled<'a>
 			}
 			for i in block.instrs().iter()
 			{
-				try!(write!(f, "{}\n ", i));
+				try!(write!(f, "{}", i));
+				if i.is_resolved() {
+					try!(write!(f, "\t; condition {:?} here", i.static_outcome()));
+				}
+				if let Some(notes) = self.annotations.get( &i.addr() )
+				{
+					for n in notes {
+						try!(write!(f, "\t; {}", n));
+					}
+				}
+				for d in i.diagnostics() {
+					try!(write!(f, "\t; !! {}", d));
+				}
+				try!(write!(f, "\n "));
 			}
 			if let Some(end_state_data) = block.end_state()
 			{
@@ -99,6 +173,12 @@ impl<'a> Disassembled<'a>
 		Ok( () )
 	}
 	
+	/// Get the accesses made by a method through pointers with an unknown base
+	pub fn method_accesses(&self, addr: CodePtr) -> Option<&[state::Access]>
+	{
+		self.method_list.get(&addr).map(|m| m.accesses())
+	}
+	
 	/// Run disassembly on the todo list
 	pub fn convert_queue(&mut self) -> usize
 	{
@@ -118,52 +198,177 @@ impl<'a> Disassembled<'a>
 
 	/// "Blockify" Pass
 	///
-	/// Breaks the code into blocks, separated by jump instructions and jump targets
-	/// Also handles marking of instructions as call targets for later passes	
+	/// Runs every block, starting each from the merge of its predecessors' end states (a forward
+	/// dataflow pass over the whole CFG). Entrypoints, method entry points and blocks without predecessors
+	/// start from a null state. Only re-run when the CFG or the contents of RAM have changed since the last run.
 	pub fn pass_block_run(&mut self) -> usize
 	{
 		//info!("pass_blockify()");
+		if ! self.cfg_changed && self.memory.generation() == self.mem_generation {
+			trace!("CFG and memory unchanged, block states are current");
+			return 0;
+		}
+		self.cfg_changed = false;
+		// NOTE: Stores made during this run are folded into memory once it converges, so they cause one more run
+		self.mem_generation = self.memory.generation();
+		
+		let (mut entry_states, mut end_states, new_targets, annotations, diagnostics, stale) = self.pass_block_run_fixpoint();
+		self.annotations = annotations;
+		for (idx, ip, diag) in diagnostics
+		{
+			self.blocks[idx].add_diagnostic(ip, diag);
+		}
+		
 		let mut count = 0;
-		for block in self.blocks.iter_mut()
+		for (idx, block) in self.blocks.iter_mut().enumerate()
 		{
-			// Execute block
-			if block.end_state().is_some()
+			if let Some(sd) = entry_states.remove(&idx) {
+				block.set_entry_state(sd);
+			}
+			if let Some(sd) = end_states.remove(&idx)
 			{
-				trace!("Block {} already has state: {}", block.range(), block.end_state().unwrap());
-				continue ;
+				let changed = match block.end_state()
+					{
+					Some(prev) => !prev.is_identical(&sd),
+					None => true,
+					};
+				if changed {
+					block.set_state(sd);
+					debug!("Block {}: New state {}", block.range(), block.end_state().unwrap());
+					count += 1;
+				}
 			}
-			
-			let mut state = State::null(RunMode::Blockify, self.cpu, self.memory);
-			for instr in block.instrs().iter()
+		}
+		
+		// Queue targets that only resolved with propagated values (e.g. indirect jumps through a loaded base)
+		count += self.queue_new_targets(new_targets);
+		// Conditions that didn't have their static outcome (the blocks are re-run on the next pass)
+		for addr in stale
+		{
+			self.forget_static_outcome(addr);
+			count += 1;
+		}
+		count
+	}
+	
+	/// (internal) Compute the entry and end states of every block
+	///
+	/// Returns (entry_states, end_states, new_targets, annotations, diagnostics, stale_conditions)
+	fn pass_block_run_fixpoint(&self) -> (HashMap<usize, state::StateData>, HashMap<usize, state::StateData>, Vec<(usize,CodePtr,bool)>, HashMap<CodePtr, Vec<String>>, Vec<(usize,CodePtr,Diagnostic)>, Vec<CodePtr>)
+	{
+		let mut entry_states = HashMap::<usize, state::StateData>::new();
+		let mut end_states = HashMap::<usize, state::StateData>::new();
+		let mut new_targets = Vec::new();
+		let mut annotations = HashMap::<CodePtr, Vec<String>>::new();
+		let mut diagnostics = Vec::new();
+		let mut stale = Vec::new();
+		let mut visits = HashMap::<usize, usize>::new();
+		let mut worklist = BTreeSet::<usize>::new();
+		
+		// - Blocks that are entered from elsewhere (entrypoints, method entries, or no known predecessors) start from a null state
+		let mut has_preds = HashSet::new();
+		for block in self.blocks.iter()
+		{
+			for &addr in block.refs()
 			{
-				state.run(&*instr);
-				
-				// Sanity check that jumps are the last instruction in the block
-				let mut was_jump = false;
-				for &(_, iscall) in state.todo_list().iter()
+				if let Ok(i) = self.find_block_for(addr) {
+					has_preds.insert(i);
+				}
+			}
+		}
+		for (idx, block) in self.blocks.iter().enumerate()
+		{
+			let first = block.range().first();
+			if ! has_preds.contains(&idx) || self.method_list.contains_key(&first) || self.entrypoints.contains(&first)
+			{
+				let null_state = State::null(RunMode::Blockify, self.cpu, self.memory).unwrap_data();
+				self.merge_entry_state(&mut entry_states, &mut visits, &mut worklist, idx, null_state);
+			}
+		}
+		
+		// - Calls to external functions apply their calling convention, other calls clobber everything
+		let mut external_lookup = |state: &mut State, tgt_addr: CodePtr| {
+			match self.method_list.get(&tgt_addr)
+			{
+			Some(f) if f.is_external() => {
+				trace!("Call to external {}", f);
+				apply_clobbers(state, f);
+				},
+			_ => state.clobber_everything(),
+			}
+			};
+		while let Some(block_idx) = worklist.iter().next().cloned()
+		{
+			worklist.remove(&block_idx);
+			let block = &*self.blocks[block_idx];
+			let mut state = State::from_data(RunMode::Blockify, self.cpu, self.memory, entry_states[&block_idx].clone(), &mut external_lookup);
+			let ninstrs = block.instrs().len();
+			let mut is_stale = false;
+			for (i, instr) in block.instrs().iter().enumerate()
+			{
+				// - A condition resolved during disassembly has to have the same outcome with this state
+				if instr.is_resolved() && self.cpu.check_condition(&mut state, instr.condition()) != instr.static_outcome()
 				{
-					if iscall {
-					}
-					else {
-						was_jump = true;
+					warn!("Condition at {} doesn't match its static outcome ({:?})", instr.addr(), instr.static_outcome());
+					if ! stale.contains(&instr.addr()) {
+						stale.push( instr.addr() );
 					}
+					is_stale = true;
+					break ;
+				}
+				state.run(instr);
+				
+				// Sanity check that jumps are the last instruction in the block
+				if i + 1 < ninstrs {
+					assert!( !state.todo_list().iter().any(|&(_, iscall)| !iscall), "Jump before the end of block {}", block.range() );
+				}
+			}
+			if is_stale {
+				// - Writes from a run that's abandoned aren't kept
+				state.take_data();
+				continue ;
+			}
+			self.collect_new_targets(block_idx, &state, &mut new_targets);
+			for &(ip, ref desc) in state.hw_accesses()
+			{
+				let notes = annotations.entry(ip).or_insert_with(Vec::new);
+				if ! notes.contains(desc) {
+					notes.push( desc.clone() );
 				}
-				assert!(was_jump);
+			}
+			for &(ip, ref diag) in state.diagnostics()
+			{
+				diagnostics.push( (block_idx, ip, diag.clone()) );
 			}
 			
-			count += 1;
-			block.set_state( state.unwrap_data() );
-			debug!("Block {}: New state {}", block.range(), block.end_state().unwrap());
+			let edges = self.block_edges(block, &mut state);
+			// - Writes are only kept from the final run of each block (see below)
+			end_states.insert(block_idx, state.take_data());
+			for (next_block_idx, edge_data) in edges
+			{
+				self.merge_entry_state(&mut entry_states, &mut visits, &mut worklist, next_block_idx, edge_data);
+			}
 		}
-		count
+		
+		// - Blocks can be re-run with different entry states, so writes are only folded into memory once every state has converged
+		for sd in end_states.values()
+		{
+			self.memory.apply_snapshot(sd.written_memory());
+		}
+		
+		(entry_states, end_states, new_targets, annotations, diagnostics, stale)
 	}
 	
 	/// Run a single function, determining what registers it uses and clobbers
 	///
-	/// Returns (will_be_fully_known, clobbers, inputs)
-	fn pass_callingconv_runfcn(&self, addr: CodePtr) -> (bool, BitSet, BitSet)
+	/// Returns (will_be_fully_known, clobbers, inputs, offsets, new_targets, accesses)
+	///
+	/// `new_targets` is a list of (block, target, is_call) for jumps/calls only resolved with the refined state
+	fn pass_callingconv_runfcn(&self, addr: CodePtr) -> (bool, BitSet, BitSet, Vec<(u8,u64)>, Vec<(usize,CodePtr,bool)>, Vec<state::Access>)
 	{
-		let mut end_states = Vec::new();
+		let mut end_states = HashMap::<usize, state::StateData>::new();
+		let mut new_targets = Vec::new();
+		let mut accesses = Vec::new();
 		let mut will_be_fully_known = true;	// cleared if non-fully-known method is called
 		
 		// Open scope to properly end the borrows owned by callee_lookup
@@ -193,9 +398,7 @@ impl<'a> Disassembled<'a>
 						for r in i.inputs().iter() {
 							state.data_mut().read_reg(r as u8);
 						}
-						for r in i.clobbers().iter() {
-							state.data_mut().write_reg(r as u8, Value::Unknown);
-						}
+						apply_clobbers(state, i);
 						// - Flag callee as only being partially known
 						will_be_fully_known = false;
 						},
@@ -206,9 +409,7 @@ impl<'a> Disassembled<'a>
 						for r in i.inputs().iter() {
 							state.data_mut().read_reg(r as u8);
 						}
-						for r in i.clobbers().iter() {
-							state.data_mut().write_reg(r as u8, Value::Unknown);
-						}
+						apply_clobbers(state, i);
 						},
 					},
 				None => {
@@ -225,84 +426,192 @@ impl<'a> Disassembled<'a>
 				state.unwrap_data()
 				};
 			let block_idx = self.find_block_for(addr).ok().expect("Method code not disassembled");
-			let mut stack = Vec::<(usize, state::StateData, Vec<usize>)>::new();
-			stack.push( (block_idx, init_state, Vec::new()) );
-			// - Execute (branching state at conditional/multitarget jumps)
-			while let Some( (block_idx, data, history) ) = stack.pop()
+			// - Worklist fixpoint: each block has an entry state, merged from all incoming edges.
+			//   Blocks are re-run whenever their entry state changes (lowest address first)
+			let mut entry_states = HashMap::<usize, state::StateData>::new();
+			let mut visits = HashMap::<usize, usize>::new();
+			let mut worklist = BTreeSet::<usize>::new();
+			entry_states.insert(block_idx, init_state);
+			worklist.insert(block_idx);
+			while let Some(block_idx) = worklist.iter().next().cloned()
 			{
-				let mut state = State::from_data(RunMode::CallingConv, self.memory, data, &mut callee_lookup);
+				worklist.remove(&block_idx);
+				let data = entry_states[&block_idx].clone();
+				let mut state = State::from_data(RunMode::CallingConv, self.cpu, self.memory, data, &mut callee_lookup);
 				let block = &*self.blocks[block_idx];
 				//  > Run block to completion off 'current' state
 				for i in block.instrs()
 				{
 					state.run(i);
 				}
-				// - Spot reverse jumps and (TODO) [Run until stable] [Stop]
-				//  > If only one target, push current state to stack (along with target)
-				if block.refs().len() == 0
+				self.collect_new_targets(block_idx, &state, &mut new_targets);
+				// - Save accesses through unknown pointers
+				for a in state.accesses()
 				{
-					// - When end of method is hit, save state.
-					trace!("- Reached end of method");
-					end_states.push( state.unwrap_data() );
+					if !accesses.contains(a) {
+						accesses.push( a.clone() );
+					}
 				}
-				else if block.refs().len() == 1
+				if block.refs().len() == 0
 				{
-					let addr = block.refs()[0];
-					trace!("- Only option is {}", addr);
-					let mut newhist = history;
-					newhist.push(block_idx);
-					let block_idx = self.find_block_for(addr).ok().expect("Target block isn't disassembled");
-					if newhist.contains(&block_idx)
-					{
-						trace!("- Loopback, ignoring (TODO: Handle)");
-						continue ;
-					}
-					stack.push( (block_idx, state.unwrap_data(), newhist) );
+					// - When end of method is hit, save state (replacing the state from an earlier, less general, run)
+					trace!("- Reached end of method");
+					end_states.insert( block_idx, state.unwrap_data() );
+					continue ;
 				}
-				//  > If multiples, clone state with branch condition
-				else
+				
+				for (next_block_idx, edge_data) in self.block_edges(block, &mut state)
 				{
-					let refs = block.refs();
-					trace!("- Options are {:?}", refs);
-					let mut newhist = history;
-					newhist.push(block_idx);
-					let data = state.unwrap_data();
-					for &addr in &refs[..refs.len()-1]	// all but last
-					{
-						let next_block_idx = self.find_block_for(addr).ok().expect("Target block isn't disassembled");
-						if newhist.contains(&next_block_idx)
-						{
-							trace!("- Loopback, ignoring (TODO: Handle)");
-							continue ;
-						}
-						stack.push( (next_block_idx, data.clone(), newhist.clone()) );
-					}
-					
-					let &addr = refs.last().unwrap();
-					let next_block_idx = self.find_block_for(addr).ok().expect("Target block isn't disassembled");
-					if newhist.contains(&next_block_idx)
-					{
-						trace!("- Loopback, ignoring (TODO: Handle)");
-						continue ;
-					}
-					stack.push( (next_block_idx, data, newhist) );
+					self.merge_entry_state(&mut entry_states, &mut visits, &mut worklist, next_block_idx, edge_data);
 				}
-				//debug!("New Stack = {:?}", stack);
 			}
 		}
 		// Collate end states
 		debug!("end_states = {:?}", end_states);
 		let mut clobbers = BitSet::default();
 		let mut inputs = BitSet::default();
-		for sd in end_states
+		for sd in end_states.values()
 		{
 			trace!("Clobbers: {:?} |= {:?}", clobbers, sd.get_clobbers());
 			clobbers.union_with( &sd.get_clobbers() );
 			trace!("Inputs: {:?} |= {:?}", inputs, sd.get_inputs());
 			inputs.union_with( &sd.get_inputs() );
 		}
+		// - Clobbered registers that always end up at the same offset from their input
+		let mut offsets = Vec::new();
+		for r in clobbers.iter()
+		{
+			let mut it = end_states.values().map(|sd| sd.reg_input_offset(r as u8));
+			if let Some(Some(ofs)) = it.next()
+			{
+				if it.all(|v| v == Some(ofs)) {
+					offsets.push( (r as u8, ofs) );
+				}
+			}
+		}
 		
-		(will_be_fully_known, clobbers, inputs)
+		(will_be_fully_known, clobbers, inputs, offsets, new_targets, accesses)
+	}
+	
+	/// (internal) Save targets from a block's run that weren't visible when the block was converted
+	fn collect_new_targets(&self, block_idx: usize, state: &State, new_targets: &mut Vec<(usize,CodePtr,bool)>)
+	{
+		let block = &*self.blocks[block_idx];
+		for &(tgt, iscall) in state.todo_list()
+		{
+			let is_new = if iscall { !self.method_list.contains_key(&tgt) } else { !block.refs().contains(&tgt) };
+			if is_new && !new_targets.contains( &(block_idx, tgt, iscall) ) {
+				debug!("- New target {} (call={}) from block {}", tgt, iscall, block.range());
+				new_targets.push( (block_idx, tgt, iscall) );
+			}
+		}
+	}
+	
+	/// (internal) Get the state passed along each outgoing edge of a block, given the state at its end
+	///
+	/// A conditional branch constrains the compared values differently on each edge, and edges that can't be taken are dropped
+	///
+	/// NOTE: Other conditional instructions (e.g. a conditional move or call) fall through whether or not
+	/// their condition passes, so the state is passed unchanged.
+	fn block_edges(&self, block: &Block, state: &mut State) -> Vec<(usize, state::StateData)>
+	{
+		let mut rv = Vec::new();
+		let refs = block.refs();
+		trace!("- Options are {:?}", refs);
+		let last = block.instrs().last().unwrap();
+		let fallthrough = self.ptr_near(last.addr(), last.addr().addr() + last.len as u64);
+		let (outcome, rel) = if last.is_conditional() && last.is_jump() {
+				(self.cpu.check_condition(state, last.condition()), self.cpu.condition_relation(last.condition()))
+			}
+			else {
+				(::value::ValueBool::Unknown, None)
+			};
+		let data = state.data();
+		for &addr in refs
+		{
+			// - Skip edges that the flags rule out
+			if (addr == fallthrough && outcome == ::value::ValueBool::True) || (addr != fallthrough && outcome == ::value::ValueBool::False)
+			{
+				trace!("- Edge to {} never taken, dropping", addr);
+				continue ;
+			}
+			let edge_data = match rel
+				{
+				Some(rel) if addr == fallthrough => data.refine(rel.negate()),
+				Some(rel) => data.refine(rel),
+				None => Some(data.clone()),
+				};
+			match edge_data
+			{
+			Some(d) => rv.push( (self.find_block_for(addr).ok().expect("Target block isn't disassembled"), d) ),
+			None => trace!("- Edge to {} is infeasible, dropping", addr),
+			}
+		}
+		rv
+	}
+	
+	/// (internal) Merge an incoming edge's state into a block's entry state, and queue the block if anything changed
+	///
+	/// If the entry state hasn't converged after `MAX_BLOCK_VISITS` runs, the block is run from a state with
+	/// no values known (which later edges can't change).
+	fn merge_entry_state(&self,
+		entry_states: &mut HashMap<usize, state::StateData>,
+		visits: &mut HashMap<usize, usize>,
+		worklist: &mut BTreeSet<usize>,
+		block_idx: usize, edge_data: state::StateData
+		)
+	{
+		let count = visits.entry(block_idx).or_insert(0);
+		let new_state = match entry_states.get(&block_idx)
+			{
+			None => edge_data,
+			Some(prev) => {
+				let merged = if *count >= MAX_BLOCK_VISITS {
+						prev.join(&edge_data, self.memory).generalise()
+					}
+					else if *count >= WIDEN_AFTER {
+						prev.widen(&edge_data, self.memory)
+					}
+					else {
+						prev.join(&edge_data, self.memory)
+					};
+				if merged.is_identical(prev) {
+					return ;
+				}
+				merged
+				},
+			};
+		*count += 1;
+		let new_state = if *count == MAX_BLOCK_VISITS {
+				warn!("Entry state for block {} didn't converge, running it with nothing known", self.blocks[block_idx].range());
+				new_state.generalise()
+			}
+			else {
+				new_state
+			};
+		entry_states.insert(block_idx, new_state);
+		worklist.insert(block_idx);
+	}
+	
+	/// (internal) Add jump/call targets that were only found once values were propagated
+	///
+	/// Returns the number of new targets
+	fn queue_new_targets(&mut self, new_targets: Vec<(usize,CodePtr,bool)>) -> usize
+	{
+		let mut ret = 0;
+		for (block_idx, tgt, iscall) in new_targets
+		{
+			if iscall {
+				self.method_list.insert(tgt, Default::default());
+			}
+			else {
+				self.blocks[block_idx].add_ref(tgt);
+			}
+			self.todo_list.insert(tgt);
+			self.cfg_changed = true;
+			ret += 1;
+		}
+		ret
 	}
 	
 	/// Determine the calling convention for methods
@@ -325,8 +634,12 @@ impl<'a> Disassembled<'a>
 			}
 			
 	
-			let (fully_known, clobbers, inputs) = self.pass_callingconv_runfcn(addr);
-			self.method_list.get_mut(&addr).unwrap().set_reg_usage(fully_known, inputs, clobbers);
+			let (fully_known, clobbers, inputs, offsets, new_targets, accesses) = self.pass_callingconv_runfcn(addr);
+			self.method_list.get_mut(&addr).unwrap().set_reg_usage(fully_known, inputs, clobbers, offsets);
+			self.method_list.get_mut(&addr).unwrap().set_accesses(accesses);
+			
+			// Queue targets found using refined values (e.g. bounded jump tables)
+			ret += self.queue_new_targets(new_targets);
 			
 			// Only increment count if the state changed
 			let new_state = self.method_list[&addr].cc_state();
@@ -341,10 +654,69 @@ impl<'a> Disassembled<'a>
 		ret
 	}
 	
+	/// Add a named method (e.g. from a symbol table), queuing it for disassembly
+	pub fn add_method(&mut self, addr: CodePtr, name: &str)
+	{
+		let addr = self.ptr_near(addr, addr.addr());
+		debug!("add_method({}, {})", addr, name);
+		self.method_list.entry(addr).or_insert_with(Default::default).set_name(name);
+		self.todo_list.insert(addr);
+	}
+	
+	/// Add an address execution starts from, queuing it for disassembly
+	pub fn add_entrypoint(&mut self, addr: CodePtr)
+	{
+		let addr = self.ptr_near(addr, addr.addr());
+		debug!("add_entrypoint({})", addr);
+		self.entrypoints.insert(addr);
+		self.todo_list.insert(addr);
+	}
+	
+	/// Add an entrypoint run by a particular CPU (e.g. "arm9"), queuing it for disassembly
+	///
+	/// The entrypoint becomes a method, tagged with the CPU in the output
+	pub fn add_core_entrypoint(&mut self, addr: CodePtr, core: &'static str)
+	{
+		let addr = self.ptr_near(addr, addr.addr());
+		debug!("add_core_entrypoint({}, {})", addr, core);
+		self.method_list.entry(addr).or_insert_with(Default::default).set_core(core);
+		self.todo_list.insert(addr);
+	}
+	
+	/// Add an external function (e.g. an import), with register usage from a named calling convention
+	///
+	/// External functions aren't disassembled, calls to them only apply the convention's clobbers
+	pub fn add_external(&mut self, addr: CodePtr, name: &str, conv: &str)
+	{
+		let addr = self.ptr_near(addr, addr.addr());
+		debug!("add_external({}, {}, {})", addr, name, conv);
+		match self.cpu.calling_convention(conv)
+		{
+		Some(cc) => { self.method_list.insert(addr, block::Function::new_external(name, &cc)); },
+		None => error!("Unknown calling convention '{}' for external {}", conv, name),
+		}
+	}
+	
+	/// (internal) Get the pointer for code at `addr` reached from `base` without a bank switch (e.g. falling through)
+	///
+	/// NOTE: Code falling into a banked region from outside it is assumed to reach bank 0
+	fn ptr_near(&self, base: CodePtr, addr: u64) -> CodePtr
+	{
+		let addr = self.memory.canonicalise(addr);
+		let bank = if self.memory.is_banked(addr) { base.bank() } else { 0 };
+		CodePtr::new_banked(base.mode(), bank, addr)
+	}
+	
 	/// Disassemble starting from a given address
 	pub fn convert_from(&mut self, ip: CodePtr)
 	{
+		// Code reached through a mirror is the same code as at the aliased address
+		let ip = self.ptr_near(ip, ip.addr());
 		debug!("convert_from(ip={})", ip);
+		if self.method_list.get(&ip).map_or(false, |m| m.is_external()) {
+			debug!("- {} is an external function, not disassembling", ip);
+			return ;
+		}
 		let mut todo = HashSet::<CodePtr>::new();
 		
 		if let Ok(i) = self.find_block_for(ip)
@@ -360,6 +732,8 @@ impl<'a> Disassembled<'a>
 				
 				let newblock = Box::new( self.blocks[i].split_at(ip) );
 				self.blocks.insert(i+1, newblock);
+				self.cfg_changed = true;
+				self.recheck_conditions(i+1);
 			}
 			return ;
 		}
@@ -372,6 +746,7 @@ impl<'a> Disassembled<'a>
 			Ok(_) => panic!("Block at address {} already converted", block.range())
 			};
 		self.blocks.insert(i, block);
+		self.cfg_changed = true;
 		
 		// Disassembly pass (holds a mutable handle to the instruction list
 		// Convert local todo list into the 'global' list (pruning duplicate
@@ -400,12 +775,70 @@ impl<'a> Disassembled<'a>
 					assert!( self.blocks[i].range().contains(item) );
 					let newblock = Box::new( self.blocks[i].split_at(item) );
 					self.blocks.insert(i+1, newblock);
+					self.cfg_changed = true;
+					self.recheck_conditions(i+1);
 				}
 				},
 			}
 		}
 	}
 	
+	/// (internal) Re-evaluate statically known conditions in a block that now starts at a different instruction
+	///
+	/// Outcomes depend on flags set earlier in the block, so splitting a block can make them stale.
+	fn recheck_conditions(&mut self, block_idx: usize)
+	{
+		let stale = {
+			let mut state = State::null(RunMode::Parse, self.cpu, self.memory);
+			let mut stale = None;
+			for (i, instr) in self.blocks[block_idx].instrs().iter().enumerate()
+			{
+				if instr.is_resolved()
+				{
+					let outcome = if i == 0 { ValueBool::Unknown } else { self.cpu.check_condition(&mut state, instr.condition()) };
+					if outcome != instr.static_outcome() {
+						stale = Some(instr.addr());
+						break ;
+					}
+				}
+				self.cpu.prep_state(&mut state, instr.addr().addr(), instr.mode());
+				state.run(instr);
+			}
+			stale
+			};
+		if let Some(addr) = stale {
+			self.forget_static_outcome(addr);
+		}
+	}
+	
+	/// (internal) Clear a statically known condition outcome that no longer holds
+	///
+	/// The instruction is made to end its block (as an unresolved conditional would), so its edges are
+	/// checked against each incoming state.
+	fn forget_static_outcome(&mut self, addr: CodePtr)
+	{
+		let block_idx = self.find_block_for(addr).ok().expect("Instruction isn't disassembled");
+		let (next, was_terminal) = {
+			let instr = self.blocks[block_idx].instrs().iter().find(|i| i.addr() == addr).expect("Instruction not in its block");
+			(self.ptr_near(addr, addr.addr() + instr.len as u64), instr.is_jump() && instr.static_outcome() == ValueBool::True)
+			};
+		debug!("- Condition at {} is no longer statically known", addr);
+		self.blocks[block_idx].set_static_outcome(addr, ValueBool::Unknown);
+		self.cfg_changed = true;
+		if self.blocks[block_idx].range().last() != addr
+		{
+			let newblock = Box::new( self.blocks[block_idx].split_at(next) );
+			self.blocks.insert(block_idx+1, newblock);
+			self.recheck_conditions(block_idx+1);
+		}
+		else if was_terminal
+		{
+			// - The jump ended the block, so the fall-through path was never disassembled
+			self.blocks[block_idx].add_ref(next);
+			self.todo_list.insert(next);
+		}
+	}
+	
 	/// (internal) Does the actual disassembly
 	///
 	/// Holds a mutable handle to self.instructions, so can't be part of convert_from
@@ -417,51 +850,80 @@ impl<'a> Disassembled<'a>
 		let mut link_to_next = true;
 		
 		let mut addr = start.addr();
-		let mode = start.mode();
 		
 		// Keep processing until either a terminal instruction is located (break)
 		// or an already-processed instruction is hit (while cond)
 		loop
 		{
-			if instructions.len() > 0 && self.todo_list.contains( &CodePtr::new(mode, addr) )
+			if instructions.len() > 0 && self.todo_list.contains( &self.ptr_near(start, addr) )
 			{
 				trace!("- Hit target");
 				break;
 			}
 			
-			let mut instr = match self.cpu.disassemble(self.memory, addr, mode)
+			let ptr = self.ptr_near(start, addr);
+			// Code can only be run from executable memory
+			let exec_fault = match self.memory.permissions(addr)
 				{
-				Ok(i) => i,
-				Err(e) => {
-					error!("Disassembly of {:#x} [mode={}] failed: {:?}", addr, mode, e);
-					// Return a placeholder, simplifying later code
-					instruction::Instruction::invalid()
-					},
+				Some(p) if p.exec => None,
+				Some(_) => Some( Diagnostic::ExecuteDenied(addr) ),
+				None => Some( Diagnostic::ExecuteUnmapped(addr) ),
+				};
+			let mut instr = if let Some(diag) = exec_fault
+				{
+					error!("Disassembly of {} failed: {}", ptr, diag);
+					let mut i = instruction::Instruction::invalid();
+					i.add_diagnostic(diag);
+					i
+				}
+				else
+				{
+					match self.cpu.disassemble(self.memory, addr, ptr.mode(), ptr.bank())
+					{
+					Ok(i) => i,
+					Err(e) => {
+						error!("Disassembly of {} failed: {:?}", ptr, e);
+						// Return a placeholder, simplifying later code
+						instruction::Instruction::invalid()
+						},
+					}
 				};
 			
 			// Set common state on instruction
 			// - Straight out of the disassembler, it is just a bare instruction
-			instr.set_addr( CodePtr(mode, addr) );
+			instr.set_addr( ptr );
 			debug!("> {:?}", instr);
 			
+			// Conditions that can be evaluated using the current block's state don't need a separate block
+			// - The outcome is only recorded, as the instruction may later be reached with other flags (see `recheck_conditions`)
+			if instr.is_conditional() && ! instructions.is_empty() {
+				let outcome = self.cpu.check_condition(&mut state, instr.condition());
+				if outcome != ValueBool::Unknown {
+					trace!("- Condition {:x} statically {:?}", instr.condition(), outcome);
+					instr.set_static_outcome(outcome);
+				}
+			}
+			let is_conditional = instr.is_conditional() && ! instr.is_resolved();
+			
 			// Instruction was conditional, stop the current block and run with this instruction in a separate block
-			if instr.is_conditional() {
-				todo.insert( CodePtr::new(mode, addr + instr.len as u64) );
+			if is_conditional {
+				todo.insert( self.ptr_near(start, addr + instr.len as u64) );
 			
 				// If we're processing an instruction AFTER the first, break
 				if ! instructions.is_empty() {
 					// Leave link_to_next as true, it will lead to linking this block with the conditional
-					todo.insert( CodePtr::new(mode, addr) );
+					todo.insert( ptr );
 					trace!("- Conditional, breaking");
 					break;
 				}
 			}
 			
 			// Execute with minimal state
-			self.cpu.prep_state(&mut state, addr, mode);
+			self.cpu.prep_state(&mut state, addr, ptr.mode());
 			state.run(&instr);
 			
-			let is_terminal = instr.is_terminal();
+			// - A jump whose condition always passes ends the block like an unconditional one
+			let is_terminal = instr.is_terminal() || (instr.is_jump() && instr.static_outcome() == ValueBool::True);
 			
 			addr += instr.len as u64;
 			instructions.push(instr);
@@ -471,9 +933,15 @@ impl<'a> Disassembled<'a>
 				link_to_next = false;
 				break;
 			}
-			let found_tgt = self.find_block_for( CodePtr::new(mode, addr) ).is_ok();
+			// Conditional instructions end the block, so the block's refs are its branch edges
+			if is_conditional {
+				trace!("- Conditional, ending block");
+				break;
+			}
+			let next = self.ptr_near(start, addr);
+			let found_tgt = self.find_block_for(next).is_ok();
 			if found_tgt {
-				todo.insert( CodePtr::new(mode, addr) );
+				todo.insert(next);
 				break;
 			}
 		}
@@ -484,7 +952,7 @@ impl<'a> Disassembled<'a>
 		let mut refs = Vec::new();
 		
 		if link_to_next {
-			refs.push( CodePtr::new(mode, addr) );
+			refs.push( self.ptr_near(start, addr) );
 		}
 		
 		// Get list of jump targets from instruction
@@ -492,7 +960,7 @@ impl<'a> Disassembled<'a>
 		{
 			todo.insert( addr.clone() );
 			if iscall {
-				self.method_list.insert( addr.clone(), Default::default() );
+				self.method_list.entry( addr.clone() ).or_insert_with(Default::default);
 			}
 			else {
 				refs.push( addr.clone() );
@@ -500,7 +968,29 @@ impl<'a> Disassembled<'a>
 		}
 		
 		debug!("- Complete at IP={:#x}", addr);
-		Block::new(instructions, refs)
+		let mut block = Block::new(instructions, refs);
+		// - Accesses the memory map doesn't permit are flagged as soon as they're seen
+		for &(ip, ref diag) in state.diagnostics() {
+			block.add_diagnostic(ip, diag.clone());
+		}
+		block
+	}
+}
+
+/// Apply a called function's clobbers to the caller's state
+///
+/// Registers that the callee only offsets (e.g. the stack pointer) are adjusted instead of clobbered
+fn apply_clobbers(state: &mut State, fcn: &block::Function)
+{
+	for r in fcn.clobbers().iter()
+	{
+		let r = r as u8;
+		let val = match fcn.reg_offset(r)
+			{
+			Some(ofs) => state.data_mut().read_reg(r) + Value::known(ofs),
+			None => Value::Unknown,
+			};
+		state.data_mut().write_reg(r, val);
 	}
 }
 
@@ -508,25 +998,40 @@ impl CodePtr
 {
 	pub fn new(mode: CPUMode, addr: u64) -> CodePtr
 	{
-		CodePtr(mode, addr)
+		CodePtr(mode, 0, addr)
+	}
+	pub fn new_banked(mode: CPUMode, bank: ::memory::BankId, addr: u64) -> CodePtr
+	{
+		CodePtr(mode, bank, addr)
 	}
 	
 	pub fn mode(&self) -> CPUMode { self.0 }
-	pub fn addr(&self) -> u64 { self.1 }
+	pub fn bank(&self) -> ::memory::BankId { self.1 }
+	pub fn addr(&self) -> u64 { self.2 }
 }
 
 impl ::std::fmt::Display for CodePtr
 {
 	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
 	{
-		write!(f, "{}:{:#08x}", self.0, self.1)
+		if self.1 != 0 {
+			write!(f, "{}:{}/{:#08x}", self.0, self.1, self.2)
+		}
+		else {
+			write!(f, "{}:{:#08x}", self.0, self.2)
+		}
 	}
 }
 impl ::std::fmt::Debug for CodePtr
 {
 	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
 	{
-		write!(f, "{}:{:#x}", self.0, self.1)
+		if self.1 != 0 {
+			write!(f, "{}:{}/{:#x}", self.0, self.1, self.2)
+		}
+		else {
+			write!(f, "{}:{:#x}", self.0, self.2)
+		}
 	}
 }
 
@@ -571,4 +1076,134 @@ impl ::std::fmt::Display for CodeRange
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use memory::{MemoryState,Endian};
+	
+	/// Memory holding ARM code (32-bit little-endian words) from address 0
+	fn arm_rom(words: &[u32]) -> MemoryState
+	{
+		let mut mem = MemoryState::new();
+		mem.set_endian(Endian::Little);
+		mem.add_rom_data(0, words.iter().flat_map(|&w| (0 .. 4).map(move |i| (w >> (i * 8)) as u8)).collect());
+		mem
+	}
+	/// Run the disassembly passes until nothing changes
+	fn run_passes(disasm: &mut Disassembled)
+	{
+		for _ in 0 .. 16
+		{
+			let mut cont = disasm.convert_queue() > 0;
+			cont |= disasm.pass_block_run() > 0;
+			if !cont {
+				break ;
+			}
+		}
+	}
+	fn instr_at<'a>(disasm: &'a Disassembled, addr: u64) -> &'a instruction::Instruction
+	{
+		let idx = disasm.find_block_for(CodePtr::new(0, addr)).ok().expect("Address not disassembled");
+		disasm.blocks[idx].instrs().iter().find(|i| i.addr().addr() == addr).unwrap()
+	}
+	
+	#[test]
+	fn static_condition_forgotten()
+	{
+		let mem = arm_rom(&[
+			0xE3A00F01,	// 0x00: MOV r0, #4
+			0xE3500F01,	// 0x04: CMP r0, #4
+			0x03A02F01,	// 0x08: MOVEQ r2, #4
+			0x0A000000,	// 0x0C: BEQ 0x14
+			0xE3A03F01,	// 0x10: MOV r3, #4
+			0xE12FFF1E,	// 0x14: BX lr
+			]);
+		let mut disasm = Disassembled::new(&mem, cpus::pick("arm").unwrap());
+		disasm.convert_from(CodePtr::new(0, 0));
+		run_passes(&mut disasm);
+		// - The flags are known within the block, so the branch always passes
+		assert_eq!( instr_at(&disasm, 0x08).static_outcome(), ValueBool::True );
+		assert_eq!( instr_at(&disasm, 0x0C).static_outcome(), ValueBool::True );
+		assert!( disasm.find_block_for(CodePtr::new(0, 0x10)).is_err() );
+		
+		// - Entering after the MOV, the outcome isn't known any more
+		disasm.convert_from(CodePtr::new(0, 0x04));
+		run_passes(&mut disasm);
+		assert!( !instr_at(&disasm, 0x08).is_resolved() );
+		assert!( !instr_at(&disasm, 0x0C).is_resolved() );
+		assert!( disasm.find_block_for(CodePtr::new(0, 0x10)).is_ok() );
+	}
+	
+	fn entry_reg(disasm: &Disassembled, addr: u64, reg: u8) -> Option<::value::Value<u64>>
+	{
+		let idx = disasm.find_block_for(CodePtr::new(0, addr)).ok().expect("Address not disassembled");
+		disasm.blocks[idx].entry_state().map(|sd| sd.clone().read_reg(reg))
+	}
+	
+	const EDGE_CODE: [u32; 8] = [
+		0xE3A00F01,	// 0x00: MOV r0, #4
+		0xEA000000,	// 0x04: B 0x0C
+		0xE12FFF1E,	// 0x08: BX lr
+		0xE3500F01,	// 0x0C: CMP r0, #4
+		0x0A000001,	// 0x10: BEQ 0x1C
+		0xE3A03F01,	// 0x14: MOV r3, #4
+		0xE12FFF1E,	// 0x18: BX lr
+		0xE12FFF1E,	// 0x1C: BX lr
+		];
+	
+	#[test]
+	fn entry_state_propagation()
+	{
+		let mem = arm_rom(&EDGE_CODE);
+		let mut disasm = Disassembled::new(&mem, cpus::pick("arm").unwrap());
+		disasm.convert_from(CodePtr::new(0, 0));
+		run_passes(&mut disasm);
+		// - The constant crosses into the successor, and the fallthrough can never be taken
+		assert_eq!( entry_reg(&disasm, 0x0C, 0).and_then(|v| v.val_known()), Some(4) );
+		assert_eq!( entry_reg(&disasm, 0x1C, 0).and_then(|v| v.val_known()), Some(4) );
+		assert!( entry_reg(&disasm, 0x14, 0).is_none() );
+	}
+	
+	#[test]
+	fn entrypoint_seeded()
+	{
+		let mem = arm_rom(&EDGE_CODE);
+		let mut disasm = Disassembled::new(&mem, cpus::pick("arm").unwrap());
+		disasm.add_entrypoint(CodePtr::new(0, 0));
+		// - Also entered directly, so the jump from 0x04 isn't the only way in
+		disasm.add_entrypoint(CodePtr::new(0, 0x0C));
+		run_passes(&mut disasm);
+		assert_eq!( entry_reg(&disasm, 0x0C, 0).and_then(|v| v.val_known()), None );
+		assert!( entry_reg(&disasm, 0x14, 0).is_some() );
+	}
+	
+	#[test]
+	fn entry_state_refinement()
+	{
+		let mem = arm_rom(&EDGE_CODE);
+		let mut disasm = Disassembled::new(&mem, cpus::pick("arm").unwrap());
+		disasm.convert_from(CodePtr::new(0, 0x0C));
+		run_passes(&mut disasm);
+		// - Both edges are possible, and the taken edge knows the compared value
+		assert_eq!( entry_reg(&disasm, 0x1C, 0).and_then(|v| v.val_known()), Some(4) );
+		let fallthrough = entry_reg(&disasm, 0x14, 0).expect("Fallthrough not run");
+		assert_eq!( fallthrough.val_known(), None );
+	}
+	
+	#[test]
+	fn execute_permissions()
+	{
+		let mut mem = arm_rom(&[
+			0xE3A00F01,	// 0x00: MOV r0, #4
+			0xE12FFF1E,	// 0x04: BX lr
+			]);
+		mem.set_permissions(0, ::memory::Permissions { read: true, write: false, exec: false }).unwrap();
+		let mut disasm = Disassembled::new(&mem, cpus::pick("arm").unwrap());
+		disasm.convert_from(CodePtr::new(0, 0));
+		run_passes(&mut disasm);
+		assert_eq!( instr_at(&disasm, 0).diagnostics(), &[instruction::Diagnostic::ExecuteDenied(0)] );
+	}
+}
+
 // vim: ft=rust
@@ -1,15 +1,18 @@
 //
 //
 //
-use value::{Value,ValueBool,ValueType};
-use memory::MemoryStateAccess;
-use disasm::instruction::InstrParam;
-use disasm::CodePtr;
+use value::{Value,ValueBool,ValueType,Relation,Provenance,MergeOp};
+use memory::{MemoryStateAccess,Snapshot,BankSelection};
+use disasm::instruction::{InstrParam,InstrSize,Diagnostic};
+use disasm::{CodePtr,UnalignedRead};
 use bit_set::BitSet;
 use std::default::Default;
 
 const NUM_TMPREGS: usize = 4;
 
+/// Source of unique store generations (see `StateData::store_gen`)
+static STORE_GENERATION: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(1);
+
 pub type CallHandler<'call> = &'call mut (FnMut(&mut State, CodePtr)->() + 'call);
 
 /// Emulated CPU state during pseudo-execution
@@ -20,6 +23,8 @@ pub struct State<'mem,'call>
 	
 	/// Reference to system memory
 	memory: &'mem ::memory::MemoryState,
+	/// CPU being emulated
+	cpu: &'mem ::disasm::CPU,
 	
 	/// List of addresses to be processed on next pass
 	todo_list: Vec<(CodePtr, bool)>,
@@ -28,6 +33,33 @@ pub struct State<'mem,'call>
 	data: StateData,
 	
 	call_handler: Option<CallHandler<'call>>,
+	
+	/// Set while running an instruction that doesn't update the flags
+	flags_locked: bool,
+	
+	/// Address of the instruction being executed
+	cur_instr: CodePtr,
+	/// Memory accesses made through pointers with an unknown base
+	accesses: Vec<Access>,
+	/// Descriptions of accesses to named hardware registers (instruction, description)
+	hw_accesses: Vec<(CodePtr, String)>,
+	/// Accesses not permitted by the memory map
+	diagnostics: Vec<(CodePtr, Diagnostic)>,
+}
+
+/// A memory access through a pointer with an unknown (input-derived or loaded) base
+#[derive(Clone,PartialEq,Debug)]
+pub struct Access
+{
+	/// Instruction that made the access
+	pub instr: CodePtr,
+	/// Origin of the pointer
+	pub base: Value<u64>,
+	/// Offset from the base
+	pub offset: u64,
+	/// Size of the access in bytes
+	pub size: u8,
+	pub is_write: bool,
 }
 
 pub enum RunMode
@@ -58,14 +90,43 @@ pub struct StateData
 	/// Stack - Dynamic vector
 	stack: Vec<Value<u64>>,
 	
-	/// Carry flag
+	/// Negative flag
+	flag_n: ValueBool,
+	/// Zero flag
+	flag_z: ValueBool,
+	/// Carry flag (set when no borrow occurs for subtraction)
 	flag_c: ValueBool,
 	/// Overflow flag
 	flag_v: ValueBool,
+	
+	/// Operands of the last comparison (used to refine values on conditional branches)
+	compare: Option<Comparison>,
+	
+	/// Memory written on this path
+	mem: Snapshot,
+	/// Bytes written on this path through pointers with an unknown base (base, offset, value)
+	sym_mem: Vec<(Value<u64>, u64, Value<u8>)>,
+	/// Banks mapped in banked regions
+	banks: BankSelection,
+	/// Identifies the stores made on this path (values loaded through unknown pointers are tagged with it)
+	///
+	/// Replaced by a new value after every store, and where paths with different stores join.
+	store_gen: usize,
+}
+
+/// Operands of a comparison that set the flags
+#[derive(Clone,Debug,PartialEq)]
+struct Comparison
+{
+	lhs: InstrParam,
+	rhs: InstrParam,
+	size: InstrSize,
 }
 
 pub enum StatusFlags
 {
+	Negative,
+	Zero,
 	Carry,
 	Overflow,
 }
@@ -78,19 +139,48 @@ impl<'mem,'call> State<'mem,'call>
 		State {
 			mode: mode,	// TODO: Receive as an argument
 			memory: mem,
+			cpu: cpu,
 			data: StateData::new(cpu),
 			todo_list: Vec::new(),	
 			call_handler: None,
+			flags_locked: false,
+			cur_instr: CodePtr::new(0, 0),
+			accesses: Vec::new(),
+			hw_accesses: Vec::new(),
+			diagnostics: Vec::new(),
 		}
 	}
-	pub fn from_data<'a,'c>(mode: RunMode, mem: &'a ::memory::MemoryState, data: StateData, ch: CallHandler<'c>) -> State<'a,'c>
+	pub fn from_data<'a,'c>(mode: RunMode, cpu: &'a ::disasm::CPU, mem: &'a ::memory::MemoryState, data: StateData, ch: CallHandler<'c>) -> State<'a,'c>
 	{
 		State {
 			mode: mode,
 			memory: mem,
+			cpu: cpu,
 			data: data,
 			todo_list: Vec::new(),
 			call_handler: Some(ch),
+			flags_locked: false,
+			cur_instr: CodePtr::new(0, 0),
+			accesses: Vec::new(),
+			hw_accesses: Vec::new(),
+			diagnostics: Vec::new(),
+		}
+	}
+	/// Create a state from existing data, for modes that don't need a call handler
+	pub fn from_data_nocall<'a>(mode: RunMode, cpu: &'a ::disasm::CPU, mem: &'a ::memory::MemoryState, data: StateData) -> State<'a,'static>
+	{
+		State {
+			mode: mode,
+			memory: mem,
+			cpu: cpu,
+			data: data,
+			todo_list: Vec::new(),
+			call_handler: None,
+			flags_locked: false,
+			cur_instr: CodePtr::new(0, 0),
+			accesses: Vec::new(),
+			hw_accesses: Vec::new(),
+			diagnostics: Vec::new(),
 		}
 	}
 	
@@ -102,14 +192,29 @@ impl<'mem,'call> State<'mem,'call>
 		}
 	}
 	
-	//pub fn data(&self) -> &StateData {
-	//	&self.data
-	//}
+	pub fn data(&self) -> &StateData {
+		&self.data
+	}
 	pub fn data_mut(&mut self) -> &mut StateData {
 		&mut self.data
 	}
-	pub fn unwrap_data(self) -> StateData {
-		self.data
+	pub fn unwrap_data(mut self) -> StateData {
+		self.retire_writes();
+		::std::mem::replace(&mut self.data, Default::default())
+	}
+	/// Take the state's data without folding its writes back into memory (e.g. if the state could be re-run)
+	pub fn take_data(mut self) -> StateData {
+		::std::mem::replace(&mut self.data, Default::default())
+	}
+	/// (internal) Fold this state's writes back into memory (merged with whatever else was stored there)
+	fn retire_writes(&mut self)
+	{
+		match self.mode
+		{
+		RunMode::Blockify | RunMode::Full => self.memory.apply_snapshot(&self.data.mem),
+		// - Calling convention runs start from placeholder inputs, so their writes are only meaningful locally
+		_ => {},
+		}
 	}
 
 	/// Retrive the contents of the todo list
@@ -119,12 +224,38 @@ impl<'mem,'call> State<'mem,'call>
 	pub fn clear_todo_list(&mut self) {
 		self.todo_list.clear()
 	}
+	/// Retrieve descriptions of the accesses to hardware registers
+	pub fn hw_accesses(&self) -> &[(CodePtr, String)] {
+		&self.hw_accesses
+	}
+	/// Retrieve the accesses that the memory map doesn't permit
+	pub fn diagnostics(&self) -> &[(CodePtr, Diagnostic)] {
+		&self.diagnostics
+	}
+	/// Retrieve the accesses made through pointers with an unknown base
+	pub fn accesses(&self) -> &[Access] {
+		&self.accesses
+	}
 	
 	/// Execute a single instruction
 	pub fn run(&mut self, instr: &::disasm::instruction::Instruction)
 	{
 		debug!("--- {}", instr);
+		self.cur_instr = instr.addr();
+		// - Code running from a banked region means its bank is mapped
+		if let Some(base) = self.memory.banked_region(instr.addr().addr()) {
+			self.data.banks.select(base, Some(instr.addr().bank()));
+		}
+		if instr.is_conditional() {
+			let cpu = self.cpu;
+			if cpu.check_condition(self, instr.condition()) == ValueBool::False {
+				trace!("- Condition doesn't pass, skipping");
+				return ;
+			}
+		}
+		self.flags_locked = !instr.sets_flags();
 		instr.class.forwards(self, instr);
+		self.flags_locked = false;
 	}
 	
 	/// Get the value of a parameter (register)
@@ -154,24 +285,47 @@ impl<'mem,'call> State<'mem,'call>
 	/// Read from emulated memory
 	pub fn read<T:ValueType+MemoryStateAccess>(&mut self, addr: &Value<u64>) -> Value<T>
 	{
-		// TODO: Tag unknown values such that accesses to an unknown base can be tracked
-		// > Tag with origin of unknown? Probably
-		// > Tagging will allow types of object fields to be tracked
-		let ret = if let Some(addr_val) = addr.val_known()
+		let ret = if let Some( (base, ofs) ) = addr.pointer_base()
 			{
-				match MemoryStateAccess::read(self.memory, addr_val)
-				{
-				Some(x) => x,
-				None => {
-					warn!("Reading unmapped memory {}", addr_val);
+				// Unknown base, tag the value with its origin (allows types of object fields to be tracked)
+				let size = ::std::mem::size_of::<T>() as u8;
+				self.record_access(base.clone(), ofs, size, false);
+				// - Check for a store through the same pointer first
+				let stored: Vec<_> = (0 .. size as u64).filter_map(|i| self.data.sym_get(&base, ofs.wrapping_add(i)).cloned()).collect();
+				if stored.len() == size as usize {
+					self.memory.join_bytes(&stored)
+				}
+				else if ! stored.is_empty() {
+					// Partially overwritten
 					Value::unknown()
-					}
 				}
+				else {
+					let prov = Provenance { base: base, offset: ofs, size: size, generation: self.data.store_gen };
+					Value::Loaded( ::std::rc::Rc::new(prov), T::zero() )
+				}
+			}
+			else if let Some(addr_val) = addr.val_known()
+			{
+				self.note_hw_access(addr_val, false, None);
+				self.read_mem(addr_val)
 			}
-			else if addr.is_fixed_set()
+			else if addr.is_enumerable()
 			{
-				panic!("TODO: Support generating set of data from read");
-				//Value::<T>::unknown()
+				// Read from every possible address (e.g. a bounded jump table), merging the results
+				let mut ret: Option<Value<T>> = None;
+				for addr_val in addr.possibilities()
+				{
+					let v = self.read_mem(addr_val);
+					ret = Some(match ret
+						{
+						None => v,
+						Some(r) => r.union(&v),
+						});
+					if ret.as_ref().unwrap().is_unknown() {
+						break;
+					}
+				}
+				ret.expect("Empty address set in State::read")
 			}
 			else
 			{
@@ -181,28 +335,205 @@ impl<'mem,'call> State<'mem,'call>
 		debug!("read({:?}) = {:?}", addr, ret);
 		ret
 	}
+	/// (internal) Read a single address from emulated memory
+	fn read_mem<T:ValueType+MemoryStateAccess>(&mut self, addr: u64) -> Value<T>
+	{
+		let size = ::std::mem::size_of::<T>() as u64;
+		if ! self.check_access(addr, size, false) {
+			return Value::unknown();
+		}
+		if addr % size != 0
+		{
+			match self.cpu.unaligned_read(size as u8)
+			{
+			UnalignedRead::Split => {},
+			UnalignedRead::Rotate => {
+				let (extra, v) = self.read_mem_aligned::<T>(addr - addr % size) >> (addr % size * 8) as usize;
+				return v | extra;
+				},
+			UnalignedRead::Unpredictable => {
+				warn!("Unaligned {}-byte read from {:#x} at {:?}, result unpredictable", size, addr, self.cur_instr);
+				return Value::unknown();
+				},
+			}
+		}
+		self.read_mem_aligned(addr)
+	}
+	/// (internal) Read from emulated memory, without applying the CPU's unaligned access behaviour
+	fn read_mem_aligned<T:ValueType+MemoryStateAccess>(&self, addr: u64) -> Value<T>
+	{
+		// - Banked regions are read from the bank this state has selected
+		if self.memory.is_banked(addr)
+		{
+			return match self.memory.bank_at(&self.data.banks, addr)
+				{
+				Some(bank) => self.memory.read_in_bank(bank, addr).unwrap_or(Value::unknown()),
+				None => {
+					debug!("Read from {:#x} with an unknown bank selected", addr);
+					Value::unknown()
+					},
+				};
+		}
+		// - This state's writes are checked before memory
+		match self.data.mem.read(self.memory, addr)
+		{
+		Some(x) => x,
+		None => Value::unknown(),
+		}
+	}
+	/// (internal) Check that the memory map permits an access, recording a diagnostic if it doesn't
+	fn check_access(&mut self, addr: u64, size: u64, is_write: bool) -> bool
+	{
+		for a in addr .. addr + size
+		{
+			let diag = match self.memory.permissions(a)
+				{
+				None if is_write => Diagnostic::WriteUnmapped(a),
+				None => Diagnostic::ReadUnmapped(a),
+				Some(p) if is_write && !p.write => Diagnostic::WriteDenied(a),
+				Some(p) if !is_write && !p.read => Diagnostic::ReadDenied(a),
+				Some(_) => continue,
+				};
+			debug!("{}: {}", self.cur_instr, diag);
+			let ent = (self.cur_instr, diag);
+			if ! self.diagnostics.contains(&ent) {
+				self.diagnostics.push(ent);
+			}
+			return false;
+		}
+		true
+	}
+	/// (internal) Record an access through a pointer with an unknown base
+	fn record_access(&mut self, base: Value<u64>, offset: u64, size: u8, is_write: bool)
+	{
+		let a = Access { instr: self.cur_instr, base: base, offset: offset, size: size, is_write: is_write };
+		trace!("Access {:?}", a);
+		if ! self.accesses.contains(&a) {
+			self.accesses.push(a);
+		}
+	}
+	/// (internal) Record an access to a named hardware register (used to annotate the disassembly)
+	fn note_hw_access(&mut self, addr: u64, is_write: bool, val: Option<u64>)
+	{
+		let desc = match self.memory.mmio_register(addr)
+			{
+			Some( (r, idx) ) => {
+				let name = if idx == 0 { r.name.clone() } else { format!("{}+{}", r.name, idx) };
+				match (is_write, val)
+				{
+				(false, _) => name,
+				(true, Some(v)) if idx == 0 => format!("{} <= {}", name, r.describe_value(v)),
+				(true, _) => format!("{} <= ?", name),
+				}
+				},
+			None => return,
+			};
+		let ent = (self.cur_instr, desc);
+		if ! self.hw_accesses.contains(&ent) {
+			self.hw_accesses.push(ent);
+		}
+	}
 	/// Write to emulated memory
 	pub fn write<T:ValueType+MemoryStateAccess>(&mut self, addr: &Value<u64>, val: Value<T>)
 	{
 		debug!("write({:?} <= {:?})", addr, val);
-		match self.mode
+		if let Some( (base, ofs) ) = addr.pointer_base() {
+			self.record_access(base, ofs, ::std::mem::size_of::<T>() as u8, true);
+		}
+		// - Values loaded before the store may not match memory any more
+		self.data.store_gen = next_store_gen();
+		// - Bank switches are tracked in all modes (they change which code is reached), then stored as normal
+		let is_select = match addr.val_known().and_then(|a| self.memory.bank_select(a))
+			{
+			Some( (region, count) ) => {
+				let bank = val.zero_extend::<u64>().val_known().map(|v| (v % count as u64) as ::memory::BankId);
+				debug!("Bank select {:#x} = {:?}", region, bank);
+				self.data.banks.select(region, bank);
+				true
+				},
+			None => false,
+			};
+		// - Accesses are checked in every mode, but Parse states don't keep the written values
+		let store = match self.mode
+			{
+			RunMode::Parse => false,
+			_ => true,
+			};
+		// Writes are stored in the state's snapshot (reads check it first), and applied to memory when the state is dropped
+		let bytes = self.memory.split_bytes(&val);
+		let size = bytes.len() as u64;
+		if let Some(addr_val) = addr.val_known()
 		{
-		RunMode::Full => {
-			error!("TODO: Support write access to simulated memory");
-			// Requirements:
-			// - Store locally a set of changes applied by this state
-			//  > Read should query this first.
-			// - This list is accessed by disasm code and applied to main
-			//   memory as a value set once state is destroyed.
-			},
-		_ => {},
+			// - Writes the memory map doesn't permit are dropped (e.g. ROM ignores them)
+			// - Bank selects to memory that can't be written only switch the bank (e.g. a mapper in ROM)
+			let select_only = is_select && ! self.memory.permissions(addr_val).map_or(false, |p| p.write);
+			if ! select_only && self.check_access(addr_val, size, true) && store
+			{
+				self.note_hw_access(addr_val, true, val.zero_extend::<u64>().val_known());
+				self.data.mem.write_bytes(self.memory, addr_val, &bytes, true);
+			}
+			// - Pointers with an unknown base could point here
+			self.data.sym_mem.clear();
+		}
+		else if addr.is_enumerable()
+		{
+			// Any one of the addresses could have been written, so merge with the existing contents
+			for addr_val in addr.possibilities()
+			{
+				if self.check_access(addr_val, size, true) && store {
+					self.data.mem.write_bytes(self.memory, addr_val, &bytes, false);
+				}
+			}
+			self.data.sym_mem.clear();
+		}
+		else if let Some( (base, ofs) ) = addr.pointer_base()
+		{
+			if store {
+				// - The pointer could point at anything written on this path
+				self.data.mem.clobber(self.memory, None);
+				self.data.sym_write(base, ofs, &bytes);
+			}
+			else {
+				self.data.sym_mem.clear();
+			}
+		}
+		else
+		{
+			// Any byte in the address range could have been written, so they are no longer known
+			let range = addr.as_range().map(|(lo, hi, _)| (lo, hi.saturating_add(size)));
+			debug!("Write to unknown address {:?}, forgetting memory written in {:?}", addr, range);
+			if store {
+				self.data.mem.clobber(self.memory, range);
+			}
+			self.data.sym_mem.clear();
 		}
 	}
+	/// Record the operands of a comparison, for use by `StateData::refine`
+	pub fn set_compare(&mut self, lhs: InstrParam, rhs: InstrParam, size: InstrSize)
+	{
+		if self.flags_locked {
+			return ;
+		}
+		self.data.compare = Some(Comparison { lhs: lhs, rhs: rhs, size: size });
+	}
+	/// Flags were set by something other than a comparison
+	pub fn clear_compare(&mut self)
+	{
+		if self.flags_locked {
+			return ;
+		}
+		self.data.compare = None;
+	}
 
 	pub fn flag_set(&mut self, flag: StatusFlags, val: ValueBool)
 	{
+		if self.flags_locked {
+			return ;
+		}
 		match flag
 		{
+		StatusFlags::Negative => { self.data.flag_n = val; },
+		StatusFlags::Zero     => { self.data.flag_z = val; },
 		StatusFlags::Carry    => { self.data.flag_c = val; },
 		StatusFlags::Overflow => { self.data.flag_v = val; },
 		}
@@ -211,6 +542,8 @@ impl<'mem,'call> State<'mem,'call>
 	{
 		match flag
 		{
+		StatusFlags::Negative => self.data.flag_n,
+		StatusFlags::Zero     => self.data.flag_z,
 		StatusFlags::Carry    => self.data.flag_c,
 		StatusFlags::Overflow => self.data.flag_v,
 		}
@@ -243,6 +576,20 @@ impl<'mem,'call> State<'mem,'call>
 		rv
 	}
 
+	/// (internal) Get the code pointer for a jump/call target (resolving mirrors and the selected bank)
+	fn code_ptr(&self, mode: super::CPUMode, addr: u64) -> Option<CodePtr>
+	{
+		let addr = self.memory.canonicalise(addr);
+		match self.memory.bank_at(&self.data.banks, addr)
+		{
+		Some(bank) => Some( CodePtr::new_banked(mode, bank, addr) ),
+		None => {
+			warn!("Target {:#x} is in a banked region with an unknown bank selected", addr);
+			None
+			},
+		}
+	}
+	
 	/// Add an address to be processed	
 	pub fn jump(&mut self, val: Value<u64>, mode: super::CPUMode)
 	{
@@ -251,7 +598,9 @@ impl<'mem,'call> State<'mem,'call>
 		{
 			for addr in val.possibilities()
 			{
-				self.todo_list.push( (CodePtr::new(mode, addr),false) );
+				if let Some(ptr) = self.code_ptr(mode, addr) {
+					self.todo_list.push( (ptr,false) );
+				}
 			}
 		}
 	}
@@ -262,22 +611,28 @@ impl<'mem,'call> State<'mem,'call>
 		{
 			for addr in val.possibilities()
 			{
-				self.todo_list.push( (CodePtr::new(mode, addr),true) );
+				if let Some(ptr) = self.code_ptr(mode, addr) {
+					self.todo_list.push( (ptr,true) );
+				}
 			}
-			match self.mode
+			// need to take, as CallHandler is a &mut, and we're lending self to the handler
+			match (&self.mode, self.call_handler.take())
 			{
-			RunMode::CallingConv => {
-				// need to take, as CallHandler is a &mut, and we're lending self to the handler
-				let handler = self.call_handler.take().expect("Running RunMode::CallingConv with no call handler");
+			(_, Some(handler)) => {
 				for addr in val.possibilities()
 				{
-					let ptr = CodePtr::new(mode, addr);
-					handler(self, ptr);
+					if let Some(ptr) = self.code_ptr(mode, addr) {
+						handler(self, ptr);
+					}
 				}
 				// - Restore handler once we're done
 				self.call_handler = Some(handler);
+				// - Called code doesn't preserve flags, and can write to memory
+				self.data.clobber_flags();
+				self.data.clobber_stores();
 				},
-			_ => {
+			(&RunMode::CallingConv, None) => panic!("Running RunMode::CallingConv with no call handler"),
+			(_, None) => {
 				// Fallback, clobber everything!
 				self.clobber_everything();
 				}
@@ -290,16 +645,24 @@ impl<'mem,'call> State<'mem,'call>
 		}
 	}
 	
-	/// Clobber every register
+	/// Clobber every register (and the flags)
 	pub fn clobber_everything(&mut self)
 	{
 		for r in self.data.registers.iter_mut()
 		{
 			*r = Value::unknown();
 		}
+		self.data.clobber_flags();
+		self.data.clobber_stores();
 	}
 }
 
+/// Get a store generation that hasn't been used before
+fn next_store_gen() -> usize
+{
+	STORE_GENERATION.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed)
+}
+
 impl StateData
 {
 	fn new(cpu: &::disasm::CPU) -> StateData
@@ -328,6 +691,220 @@ impl StateData
 		ret
 	}
 	
+	/// Get the memory written on this path
+	pub fn written_memory(&self) -> &Snapshot
+	{
+		&self.mem
+	}
+	
+	/// Get the constant offset of a register from its input value (e.g. the stack pointer after a frame is allocated)
+	pub fn reg_input_offset(&self, idx: u8) -> Option<u64>
+	{
+		match self.registers[idx as usize].input_offset()
+		{
+		Some( (i, ofs) ) if i == idx => Some(ofs),
+		_ => None,
+		}
+	}
+	
+	/// Merge with the state from another path (e.g. where control flow joins)
+	pub fn join(&self, other: &StateData, mem: &::memory::MemoryState) -> StateData
+	{
+		self.merge(other, mem, MergeOp::Join)
+	}
+	/// Merge with a newer state for the same block, widening values that keep changing
+	///
+	/// Used once a block has been revisited several times, so loops converge
+	pub fn widen(&self, next: &StateData, mem: &::memory::MemoryState) -> StateData
+	{
+		self.merge(next, mem, MergeOp::Widen)
+	}
+	/// Get a state with the same shape, but with nothing known about any value
+	///
+	/// Registers, the stack and flags become unknown, memory written on this path is dropped (reads use the
+	/// merged contents of memory), and selected banks become unknown.
+	pub fn generalise(&self) -> StateData
+	{
+		StateData {
+			inputs: self.inputs.clone(),
+			writtens: BitSet::default(),
+			
+			registers: self.registers.iter().map(|_| Value::unknown()).collect(),
+			tmpregs: [Value::unknown(), Value::unknown(), Value::unknown(), Value::unknown()],
+			stack: self.stack.iter().map(|_| Value::unknown()).collect(),
+			
+			flag_n: ValueBool::Unknown,
+			flag_z: ValueBool::Unknown,
+			flag_c: ValueBool::Unknown,
+			flag_v: ValueBool::Unknown,
+			
+			compare: None,
+			
+			mem: Snapshot::new(),
+			sym_mem: Vec::new(),
+			banks: self.banks.generalise(),
+			store_gen: next_store_gen(),
+		}
+	}
+	/// Check if two states are the same (i.e. merging changed nothing)
+	pub fn is_identical(&self, other: &StateData) -> bool
+	{
+		self.inputs == other.inputs
+			&& self.writtens == other.writtens
+			&& self.registers.iter().zip(other.registers.iter()).all(|(a,b)| a.is_identical(b))
+			&& self.tmpregs.iter().zip(other.tmpregs.iter()).all(|(a,b)| a.is_identical(b))
+			&& self.stack.len() == other.stack.len()
+			&& self.stack.iter().zip(other.stack.iter()).all(|(a,b)| a.is_identical(b))
+			&& (self.flag_n, self.flag_z, self.flag_c, self.flag_v) == (other.flag_n, other.flag_z, other.flag_c, other.flag_v)
+			&& self.compare == other.compare
+			&& self.mem.is_identical(&other.mem)
+			&& self.sym_mem.len() == other.sym_mem.len()
+			&& self.sym_mem.iter().all(|&(ref b, o, ref v)| other.sym_get(b, o).map_or(false, |ov| v.is_identical(ov)))
+			&& self.banks == other.banks
+	}
+	/// (internal) Merge two states, using the provided operation on values (including memory written on either path)
+	///
+	/// Memory written on only one path is merged with the contents of `mem`
+	fn merge(&self, other: &StateData, mem: &::memory::MemoryState, op: MergeOp) -> StateData
+	{
+		assert_eq!(self.registers.len(), other.registers.len());
+		if self.stack.len() != other.stack.len() {
+			warn!("Merging states with different stack depths ({} != {})", self.stack.len(), other.stack.len());
+		}
+		// - A register is an input if it was read before being written on any path,
+		//   and is only known to be written if it was written on all paths
+		let mut inputs = self.inputs.clone();
+		inputs.union_with(&other.inputs);
+		let mut writtens = self.writtens.clone();
+		writtens.intersect_with(&other.writtens);
+		StateData {
+			inputs: inputs,
+			writtens: writtens,
+			
+			registers: self.registers.iter().zip(other.registers.iter()).map(|(a,b)| op.apply(a,b)).collect(),
+			tmpregs: [
+				op.apply(&self.tmpregs[0], &other.tmpregs[0]), op.apply(&self.tmpregs[1], &other.tmpregs[1]),
+				op.apply(&self.tmpregs[2], &other.tmpregs[2]), op.apply(&self.tmpregs[3], &other.tmpregs[3]),
+				],
+			// - Only the common depth of the stack is kept
+			stack: self.stack.iter().zip(other.stack.iter()).map(|(a,b)| op.apply(a,b)).collect(),
+			
+			flag_n: self.flag_n.union(other.flag_n),
+			flag_z: self.flag_z.union(other.flag_z),
+			flag_c: self.flag_c.union(other.flag_c),
+			flag_v: self.flag_v.union(other.flag_v),
+			
+			compare: if self.compare == other.compare { self.compare.clone() } else { None },
+			
+			mem: self.mem.merge(&other.mem, mem, op),
+			// - Bytes only written through a pointer on one path are left to memory
+			sym_mem: self.sym_mem.iter()
+				.filter_map(|&(ref b, o, ref v)| other.sym_get(b, o).map(|ov| (b.clone(), o, op.apply(v, ov))))
+				.collect(),
+			banks: self.banks.merge(&other.banks, mem),
+			store_gen: if self.store_gen == other.store_gen { self.store_gen } else { next_store_gen() },
+		}
+	}
+	
+	/// Constrain the compared values assuming the last comparison satisfied `rel`
+	///
+	/// Returns None if the relation can't hold (i.e. the branch edge is infeasible)
+	pub fn refine(&self, rel: Relation) -> Option<StateData>
+	{
+		let cmp = match self.compare
+			{
+			Some(ref c) => c.clone(),
+			None => return Some(self.clone()),
+			};
+		let max = match cmp.size
+			{
+			InstrSize::SizeNA => return Some(self.clone()),
+			InstrSize::Size8  => 0xFF,
+			InstrSize::Size16 => 0xFFFF,
+			InstrSize::Size32 => 0xFFFFFFFF,
+			InstrSize::Size64 => !0,
+			};
+		let lhs = self.peek_param(cmp.lhs);
+		let rhs = self.peek_param(cmp.rhs);
+		let mut rv = self.clone();
+		match lhs.restrict(rel, &rhs, max)
+		{
+		Some(v) => rv.poke_param(cmp.lhs, v),
+		None => return None,
+		}
+		match rhs.restrict(rel.swap(), &lhs, max)
+		{
+		Some(v) => rv.poke_param(cmp.rhs, v),
+		None => return None,
+		}
+		trace!("refine({:?}): {:?}={:?}, {:?}={:?}", rel, cmp.lhs, rv.peek_param(cmp.lhs), cmp.rhs, rv.peek_param(cmp.rhs));
+		Some(rv)
+	}
+	/// (internal) Get a byte written through a pointer with an unknown base
+	fn sym_get(&self, base: &Value<u64>, ofs: u64) -> Option<&Value<u8>>
+	{
+		self.sym_mem.iter().find(|e| e.0 == *base && e.1 == ofs).map(|e| &e.2)
+	}
+	/// (internal) Store bytes through a pointer with an unknown base
+	///
+	/// Bytes written through other bases are dropped, as the pointers could alias
+	fn sym_write(&mut self, base: Value<u64>, ofs: u64, bytes: &[Value<u8>])
+	{
+		let end = ofs.wrapping_add(bytes.len() as u64);
+		self.sym_mem.retain(|e| e.0 == base && (e.1 < ofs || e.1 >= end));
+		for (i,b) in bytes.iter().enumerate()
+		{
+			self.sym_mem.push( (base.clone(), ofs.wrapping_add(i as u64), b.clone()) );
+		}
+	}
+	/// (internal) Forget values stored or loaded through pointers (e.g. after a call, which could write anywhere)
+	fn clobber_stores(&mut self)
+	{
+		self.sym_mem.clear();
+		self.store_gen = next_store_gen();
+	}
+	/// (internal) Set all flags to unknown
+	fn clobber_flags(&mut self)
+	{
+		self.flag_n = ValueBool::Unknown;
+		self.flag_z = ValueBool::Unknown;
+		self.flag_c = ValueBool::Unknown;
+		self.flag_v = ValueBool::Unknown;
+		self.compare = None;
+	}
+	/// (internal) Get the value of a parameter without marking it as an input
+	fn peek_param(&self, param: InstrParam) -> Value<u64>
+	{
+		match param
+		{
+		InstrParam::TrueReg(r) => self.registers[r as usize].clone(),
+		InstrParam::TmpReg(r) => self.tmpregs[r as usize].clone(),
+		InstrParam::Immediate(v) => Value::known(v),
+		}
+	}
+	/// (internal) Replace the value of a parameter without marking it as written
+	fn poke_param(&mut self, param: InstrParam, val: Value<u64>)
+	{
+		match param
+		{
+		InstrParam::TrueReg(r) => self.registers[r as usize] = val,
+		InstrParam::TmpReg(r) => self.tmpregs[r as usize] = val,
+		InstrParam::Immediate(_) => {},
+		}
+	}
+	/// (internal) Forget the last comparison if it used the specified parameter
+	fn invalidate_compare(&mut self, param: InstrParam)
+	{
+		let used = match self.compare
+			{
+			Some(ref c) => c.lhs == param || c.rhs == param,
+			None => false,
+			};
+		if used {
+			self.compare = None;
+		}
+	}
+	
 	pub fn read_reg(&mut self, idx: u8) -> Value<u64>
 	{
 		assert!( (idx as usize) < self.registers.len(), "Register index out of range");
@@ -346,15 +923,25 @@ impl StateData
 	{
 		assert!( (idx as usize) < self.registers.len(), "Register index out of range");
 		self.writtens.insert( idx as usize );
+		self.invalidate_compare( InstrParam::TrueReg(idx) );
 		self.registers[idx as usize] = val
 	}
 	fn write_tmp(&mut self, idx: u8, val: Value<u64>)
 	{
 		assert!( (idx as usize) < NUM_TMPREGS, "Temp register index out of range" );
+		self.invalidate_compare( InstrParam::TmpReg(idx) );
 		self.tmpregs[idx as usize] = val
 	}
 }
 
+impl<'mem,'call> Drop for State<'mem,'call>
+{
+	fn drop(&mut self)
+	{
+		self.retire_writes();
+	}
+}
+
 impl ::std::default::Default for StateData
 {
 	fn default() -> StateData
@@ -367,8 +954,17 @@ impl ::std::default::Default for StateData
 			tmpregs: [Value::unknown(), Value::unknown(), Value::unknown(), Value::unknown()],
 			stack: Vec::new(),
 			
+			flag_n: ValueBool::Unknown,
+			flag_z: ValueBool::Unknown,
 			flag_c: ValueBool::Unknown,
 			flag_v: ValueBool::Unknown,
+			
+			compare: None,
+			
+			mem: Snapshot::new(),
+			sym_mem: Vec::new(),
+			banks: BankSelection::default(),
+			store_gen: 0,
 		}
 	}
 }
@@ -386,8 +982,17 @@ impl ::std::clone::Clone for StateData
 			tmpregs: [self.tmpregs[0].clone(), self.tmpregs[1].clone(), self.tmpregs[2].clone(), self.tmpregs[3].clone()],
 			stack: self.stack.clone(),
 			
+			flag_n: self.flag_n.clone(),
+			flag_z: self.flag_z.clone(),
 			flag_c: self.flag_c.clone(),
 			flag_v: self.flag_v.clone(),
+			
+			compare: self.compare.clone(),
+			
+			mem: self.mem.clone(),
+			sym_mem: self.sym_mem.clone(),
+			banks: self.banks.clone(),
+			store_gen: self.store_gen,
 		}
 	}
 }
@@ -408,8 +1013,20 @@ impl ::std::fmt::Debug for StateData
 		}
 		try!( write!(f, "\n") );
 		try!( write!(f, "  Stack: {:?}\n", self.stack) );
-		try!( write!(f, "  Flags: C={:?} V={:?}\n", self.flag_c, self.flag_v) );
+		try!( write!(f, "  Flags: N={:?} Z={:?} C={:?} V={:?}\n", self.flag_n, self.flag_z, self.flag_c, self.flag_v) );
+		if let Some(ref c) = self.compare {
+			try!( write!(f, "  Compare: {:?} vs {:?}\n", c.lhs, c.rhs) );
+		}
 		try!( write!(f, "  Inputs: {:?} Writtens: {:?}\n", self.inputs, self.writtens) );
+		if ! self.mem.is_empty() {
+			try!( write!(f, "  Memory: {:?}\n", self.mem) );
+		}
+		if ! self.sym_mem.is_empty() {
+			try!( write!(f, "  Stored: {:?}\n", self.sym_mem) );
+		}
+		if ! self.banks.is_empty() {
+			try!( write!(f, "  Banks: {:?}\n", self.banks) );
+		}
 		try!( write!(f, "}}") );
 		Ok( () )
 	}
@@ -429,4 +1046,98 @@ impl ::std::fmt::Display for StateData
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use memory::MemoryState;
+	
+	fn banked_memory() -> MemoryState
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram(0x2000, 0x100);
+		mem.add_banked_data(0x4000, vec![vec![1, 2], vec![3, 4]], Some( (0x2000, 0x100) ));
+		mem
+	}
+	
+	#[test]
+	fn bank_switching()
+	{
+		let mem = banked_memory();
+		let cpu = ::disasm::cpus::pick("arm").unwrap();
+		let mut state = State::null(RunMode::Parse, cpu, &mem);
+		assert_eq!( state.read::<u8>(&Value::known(0x4000)).val_known(), Some(1) );
+		// - Writes to the select range switch banks (wrapping at the number of banks)
+		state.write::<u8>(&Value::known(0x2000), Value::known(3));
+		assert_eq!( state.read::<u8>(&Value::known(0x4000)).val_known(), Some(3) );
+		// - The selection is part of the state, not memory
+		assert_eq!( mem.read_u8(0x2000).map(|v| v.is_unknown()), Some(true) );
+		assert!( State::null(RunMode::Parse, cpu, &mem).read::<u8>(&Value::known(0x4000)).val_known() == Some(1) );
+		// - An unknown selection makes the contents unknown
+		state.write::<u8>(&Value::known(0x2000), Value::unknown());
+		assert!( state.read::<u8>(&Value::known(0x4000)).is_unknown() );
+		
+		// - Selects in RAM are also stored, selects in ROM only switch the bank
+		let mut mem = MemoryState::new();
+		mem.add_ram_data(0x2000, &[0; 0x10]);
+		mem.add_banked_data(0x4000, vec![vec![1, 2], vec![3, 4]], Some( (0x2000, 0x100) ));
+		mem.add_rom_data(0x2010, vec![0; 0xF0]);
+		let mut state = State::null(RunMode::Full, cpu, &mem);
+		state.write::<u8>(&Value::known(0x2000), Value::known(1));
+		assert_eq!( state.read::<u8>(&Value::known(0x2000)).val_known(), Some(1) );
+		state.write::<u8>(&Value::known(0x2010), Value::known(0));
+		assert_eq!( state.read::<u8>(&Value::known(0x4000)).val_known(), Some(1) );
+		assert!( state.diagnostics().is_empty() );
+	}
+	
+	#[test]
+	fn access_checks()
+	{
+		let mut mem = banked_memory();
+		mem.add_rom_data(0x8000, vec![0; 4]);
+		mem.set_permissions(0x2000, ::memory::Permissions { read: false, write: true, exec: false }).unwrap();
+		let cpu = ::disasm::cpus::pick("arm").unwrap();
+		let mut state = State::null(RunMode::Parse, cpu, &mem);
+		state.write::<u8>(&Value::known(0x8001), Value::known(1));
+		assert!( state.read::<u8>(&Value::known(0x2000)).is_unknown() );
+		assert!( state.read::<u32>(&Value::known(0x9000)).is_unknown() );
+		let diags: Vec<_> = state.diagnostics().iter().map(|e| e.1.clone()).collect();
+		assert_eq!( diags, vec![Diagnostic::WriteDenied(0x8001), Diagnostic::ReadDenied(0x2000), Diagnostic::ReadUnmapped(0x9000)] );
+		
+		// - Denied writes aren't stored, even in modes that keep written values
+		let mut state = State::null(RunMode::Full, cpu, &mem);
+		state.write::<u8>(&Value::known(0x8001), Value::known(1));
+		assert_eq!( state.read::<u8>(&Value::known(0x8001)).val_known(), Some(0) );
+		state.write::<u8>(&Value::known(0x2010), Value::known(1));
+		assert_eq!( state.diagnostics().len(), 1 );
+	}
+
+	#[test]
+	fn pointer_stores()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram(0x2000, 0x100);
+		let cpu = ::disasm::cpus::pick("arm").unwrap();
+		let mut state = State::null(RunMode::Full, cpu, &mem);
+		let p = Value::<u64>::Input(0) + Value::known(8);
+		let q = Value::<u64>::Input(1);
+		// - Loads with no store in between see the same value
+		let a = state.read::<u32>(&p);
+		let q_a = state.read::<u32>(&q);
+		assert!( a == state.read::<u32>(&p) );
+		// - Stores are read back through the same pointer, and other pointers are loaded again
+		state.write::<u32>(&p, Value::known(5));
+		assert_eq!( state.read::<u32>(&p).val_known(), Some(5) );
+		assert!( state.read::<u16>(&(p.clone() + Value::known(2))).val_known() == Some(0) );
+		let q_b = state.read::<u32>(&q);
+		assert!( q_a != q_b );
+		assert!( (q_b - q_a).is_unknown() );
+		// - A store that could alias the pointer drops the stored value
+		state.write::<u8>(&Value::known(0x2000), Value::known(1));
+		let b = state.read::<u32>(&p);
+		assert!( b.val_known().is_none() );
+		assert!( a != b );
+	}
+}
+
 // vim: ft=rust
@@ -0,0 +1,346 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// loader/elf.rs
+// - ELF executable loader
+use disasm::CodePtr;
+use memory::{MemoryState,Endian,Permissions};
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+
+/// Largest segment that will be loaded (memory contents are held in full)
+const MAX_SEGMENT_SIZE: u64 = 0x1000_0000;
+
+/// Bounds-checked reader for the ELF file, handling the file's byte order and word size
+struct Reader<'a>
+{
+	data: &'a [u8],
+	big_endian: bool,
+	is_64: bool,
+}
+
+impl<'a> Reader<'a>
+{
+	fn bytes(&self, ofs: u64, len: u64) -> Result<&'a [u8],String>
+	{
+		if ofs > self.data.len() as u64 || len > self.data.len() as u64 - ofs {
+			return Err( format!("ELF truncated (reading {:#x}+{:#x}, file is {:#x} bytes)", ofs, len, self.data.len()) );
+		}
+		Ok( &self.data[ofs as usize .. (ofs + len) as usize] )
+	}
+	fn uint(&self, ofs: u64, len: u64) -> Result<u64,String>
+	{
+		let b = try!(self.bytes(ofs, len));
+		Ok( if self.big_endian {
+			b.iter().fold(0, |v, &x| v << 8 | x as u64)
+		}
+		else {
+			b.iter().rev().fold(0, |v, &x| v << 8 | x as u64)
+		} )
+	}
+	fn u8(&self, ofs: u64) -> Result<u8,String> {
+		self.uint(ofs, 1).map(|v| v as u8)
+	}
+	fn u16(&self, ofs: u64) -> Result<u16,String> {
+		self.uint(ofs, 2).map(|v| v as u16)
+	}
+	fn u32(&self, ofs: u64) -> Result<u32,String> {
+		self.uint(ofs, 4).map(|v| v as u32)
+	}
+	/// Read a word-sized value (address or offset)
+	fn word(&self, ofs: u64) -> Result<u64,String> {
+		self.uint(ofs, if self.is_64 { 8 } else { 4 })
+	}
+	/// Get the offset of entry `idx` in a table, checking that it's within the file
+	fn entry(&self, base: u64, idx: u64, entsize: u64) -> Result<u64,String>
+	{
+		match idx.checked_mul(entsize).and_then(|o| o.checked_add(base))
+		{
+		Some(ofs) if ofs <= self.data.len() as u64 => Ok(ofs),
+		_ => Err( format!("ELF truncated (entry {} of table at {:#x}, file is {:#x} bytes)", idx, base, self.data.len()) ),
+		}
+	}
+	/// Read a NUL-terminated string
+	fn string(&self, ofs: u64) -> Result<String,String>
+	{
+		if ofs >= self.data.len() as u64 {
+			return Err( format!("String offset {:#x} past the end of the file", ofs) );
+		}
+		let rest = &self.data[ofs as usize ..];
+		match rest.iter().position(|&b| b == 0)
+		{
+		Some(len) => Ok( String::from_utf8_lossy(&rest[..len]).into_owned() ),
+		None => Err( format!("Unterminated string at {:#x}", ofs) ),
+		}
+	}
+}
+
+/// Load an ELF executable
+///
+/// Maps PT_LOAD segments (writable segments become RAM, with the zero-filled tail as known zeroes), and
+/// collects the entrypoint and function symbols.
+pub fn load(memory: &mut MemoryState, path: &str) -> Result<super::Program,String>
+{
+	let data = try!(super::read_file(path));
+	if data.len() < 16 || &data[0..4] != b"\x7FELF" {
+		return Err( format!("'{}' isn't an ELF file", path) );
+	}
+	let rdr = Reader {
+		data: &data,
+		is_64: match data[4]
+			{
+			1 => false,
+			2 => true,
+			v @ _ => return Err( format!("Unknown ELF class {}", v) ),
+			},
+		big_endian: match data[5]
+			{
+			1 => false,
+			2 => true,
+			v @ _ => return Err( format!("Unknown ELF data encoding {}", v) ),
+			},
+		};
+	memory.set_endian( if rdr.big_endian { Endian::Big } else { Endian::Little } );
+
+	// - File header
+	let machine = try!(rdr.u16(18));
+	let (entry, phoff, shoff, hdr_ofs) = if rdr.is_64 {
+			(try!(rdr.word(24)), try!(rdr.word(32)), try!(rdr.word(40)), 48)
+		}
+		else {
+			(try!(rdr.word(24)), try!(rdr.word(28)), try!(rdr.word(32)), 36)
+		};
+	let phentsize = try!(rdr.u16(hdr_ofs + 6)) as u64;
+	let phnum     = try!(rdr.u16(hdr_ofs + 8)) as u64;
+	let shentsize = try!(rdr.u16(hdr_ofs + 10)) as u64;
+	let shnum     = try!(rdr.u16(hdr_ofs + 12)) as u64;
+	debug!("ELF {}-bit {}, machine={}, entry={:#x}, {} segments, {} sections",
+		if rdr.is_64 { 64 } else { 32 }, if rdr.big_endian { "BE" } else { "LE" }, machine, entry, phnum, shnum);
+
+	let mut program = super::Program::default();
+	program.cpu = match machine
+		{
+		EM_ARM => ::disasm::cpus::pick("arm"),
+		EM_386 => ::disasm::cpus::pick("x86"),
+		_ => {
+			warn!("ELF machine {} not supported", machine);
+			None
+			},
+		};
+	// - ARM uses the bottom bit of code addresses to select Thumb mode
+	let code_ptr = |addr: u64| if machine == EM_ARM {
+			CodePtr::new((addr & 1) as ::disasm::CPUMode, addr & !1)
+		}
+		else {
+			CodePtr::new(0, addr)
+		};
+
+	// - Program headers
+	let mut loaded = Vec::new();
+	for i in 0 .. phnum
+	{
+		let ph = try!(rdr.entry(phoff, i, phentsize));
+		let p_type = try!(rdr.u32(ph));
+		if p_type != PT_LOAD {
+			continue ;
+		}
+		let (flags, offset, vaddr, filesz, memsz) = if rdr.is_64 {
+				(try!(rdr.u32(ph+4)), try!(rdr.word(ph+8)), try!(rdr.word(ph+16)), try!(rdr.word(ph+32)), try!(rdr.word(ph+40)))
+			}
+			else {
+				(try!(rdr.u32(ph+24)), try!(rdr.word(ph+4)), try!(rdr.word(ph+8)), try!(rdr.word(ph+16)), try!(rdr.word(ph+20)))
+			};
+		if memsz == 0 {
+			continue ;
+		}
+		if filesz > memsz {
+			return Err( format!("Segment {} at {:#x} has filesz ({:#x}) > memsz ({:#x})", i, vaddr, filesz, memsz) );
+		}
+		let addr_limit = if rdr.is_64 { !0 } else { 0xFFFF_FFFF };
+		if memsz > MAX_SEGMENT_SIZE || vaddr > addr_limit || memsz - 1 > addr_limit - vaddr {
+			return Err( format!("Segment {} at {:#x} has an invalid memsz ({:#x})", i, vaddr, memsz) );
+		}
+		if let Err(e) = super::check_free(&loaded, vaddr, memsz) {
+			return Err( format!("Segment {}: {}", i, e) );
+		}
+		loaded.push( (vaddr, vaddr.saturating_add(memsz)) );
+		// Bytes past the end of the file data (e.g. .bss) are zero
+		let mut contents = try!(rdr.bytes(offset, filesz)).to_vec();
+		contents.resize(memsz as usize, 0);
+		let perms = Permissions { read: flags & PF_R != 0, write: flags & PF_W != 0, exec: flags & PF_X != 0 };
+		debug!("- Segment {:#x}+{:#x} ({:#x} from file) {}", vaddr, memsz, filesz, perms);
+		if perms.write {
+			memory.add_ram_data(vaddr, &contents);
+		}
+		else {
+			memory.add_rom_data(vaddr, contents);
+		}
+		try!( memory.set_permissions(vaddr, perms) );
+	}
+
+	program.entrypoints.push( code_ptr(entry) );
+
+	// - Function symbols (from both the static and dynamic symbol tables)
+	let mut seen = ::std::collections::HashSet::new();
+	for i in 0 .. shnum
+	{
+		let sh = try!(rdr.entry(shoff, i, shentsize));
+		let sh_type = try!(rdr.u32(sh + 4));
+		if sh_type != SHT_SYMTAB && sh_type != SHT_DYNSYM {
+			continue ;
+		}
+		let (offset, size, link, entsize) = if rdr.is_64 {
+				(try!(rdr.word(sh+24)), try!(rdr.word(sh+32)), try!(rdr.u32(sh+40)), try!(rdr.word(sh+56)))
+			}
+			else {
+				(try!(rdr.word(sh+16)), try!(rdr.word(sh+20)), try!(rdr.u32(sh+24)), try!(rdr.word(sh+36)))
+			};
+		if entsize == 0 {
+			return Err( format!("Symbol table section {} has a zero entry size", i) );
+		}
+		// - Names are in the linked string table
+		let strtab_sh = try!(rdr.entry(shoff, link as u64, shentsize));
+		let strtab = try!(rdr.word(strtab_sh + if rdr.is_64 { 24 } else { 16 }));
+		for j in 0 .. size / entsize
+		{
+			let sym = try!(rdr.entry(offset, j, entsize));
+			let (name_ofs, value, info, shndx) = if rdr.is_64 {
+					(try!(rdr.u32(sym)), try!(rdr.word(sym+8)), try!(rdr.u8(sym+4)), try!(rdr.u16(sym+6)))
+				}
+				else {
+					(try!(rdr.u32(sym)), try!(rdr.word(sym+4)), try!(rdr.u8(sym+12)), try!(rdr.u16(sym+14)))
+				};
+			if info & 0xF != STT_FUNC || shndx == SHN_UNDEF || name_ofs == 0 {
+				continue ;
+			}
+			let name = match strtab.checked_add(name_ofs as u64)
+				{
+				Some(ofs) => try!(rdr.string(ofs)),
+				None => return Err( format!("Symbol {} name offset {:#x} overflows", j, name_ofs) ),
+				};
+			let ptr = code_ptr(value);
+			if seen.insert(ptr) {
+				trace!("- Symbol {} = {}", name, ptr);
+				program.methods.push( (ptr, name) );
+			}
+		}
+	}
+	debug!("ELF '{}': {} function symbols", path, program.methods.len());
+
+	Ok( program )
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use loader::test_util::{temp_file,put};
+	
+	/// A 32-bit little-endian ARM executable: code at 0x8000 (entry in Thumb mode), data+bss at 0x10000,
+	/// and a symbol table with a defined and an undefined function
+	fn arm_elf() -> Vec<u8>
+	{
+		let mut d = Vec::new();
+		d.extend_from_slice(b"\x7FELF\x01\x01\x01");
+		put(&mut d, 16, 2, 2);	// e_type
+		put(&mut d, 18, EM_ARM as u64, 2);
+		put(&mut d, 20, 1, 4);
+		put(&mut d, 24, 0x8001, 4);	// e_entry
+		put(&mut d, 28, 0x34, 4);	// e_phoff
+		put(&mut d, 32, 0x200, 4);	// e_shoff
+		put(&mut d, 40, 52, 2);
+		put(&mut d, 42, 32, 2);	// e_phentsize
+		put(&mut d, 44, 2, 2);
+		put(&mut d, 46, 40, 2);	// e_shentsize
+		put(&mut d, 48, 3, 2);
+		// - Program headers
+		for &(ph, ofs, vaddr, filesz, memsz, flags) in [(0x34, 0x100, 0x8000, 8, 8, PF_R|PF_X), (0x54, 0x108, 0x10000, 4, 8, PF_R|PF_W)].iter()
+		{
+			put(&mut d, ph, PT_LOAD as u64, 4);
+			put(&mut d, ph+4, ofs, 4);
+			put(&mut d, ph+8, vaddr, 4);
+			put(&mut d, ph+16, filesz, 4);
+			put(&mut d, ph+20, memsz, 4);
+			put(&mut d, ph+24, flags as u64, 4);
+		}
+		put(&mut d, 0x100, 0xE12FFF1E_E3A00F01, 8);
+		put(&mut d, 0x108, 0x04030201, 4);
+		// - Symbols (null, `main` defined in section 1, `ext` undefined)
+		put(&mut d, 0x130, 1, 4);
+		put(&mut d, 0x134, 0x8000, 4);
+		put(&mut d, 0x13C, 0x12, 1);
+		put(&mut d, 0x13E, 1, 2);
+		put(&mut d, 0x140, 6, 4);
+		put(&mut d, 0x14C, 0x12, 1);
+		d.resize(0x160, 0);
+		d.extend_from_slice(b"\0main\0ext\0");
+		// - Section headers (null, symtab, strtab)
+		put(&mut d, 0x228 + 4, SHT_SYMTAB as u64, 4);
+		put(&mut d, 0x228 + 16, 0x120, 4);
+		put(&mut d, 0x228 + 20, 48, 4);
+		put(&mut d, 0x228 + 24, 2, 4);
+		put(&mut d, 0x228 + 36, 16, 4);
+		put(&mut d, 0x250 + 4, 3, 4);
+		put(&mut d, 0x250 + 16, 0x160, 4);
+		put(&mut d, 0x250 + 20, 10, 4);
+		d
+	}
+	
+	#[test]
+	fn load_segments_and_symbols()
+	{
+		let mut mem = MemoryState::new();
+		let program = load(&mut mem, &temp_file("elf_good", &arm_elf())).unwrap();
+		assert!( program.cpu.is_some() );
+		assert_eq!( program.entrypoints, vec![CodePtr::new(1, 0x8000)] );
+		assert_eq!( program.methods, vec![(CodePtr::new(0, 0x8000), String::from("main"))] );
+		assert_eq!( mem.endian(), Some(Endian::Little) );
+		assert_eq!( mem.read_u32(0x8000).and_then(|v| v.val_known()), Some(0xE3A00F01) );
+		assert_eq!( mem.permissions(0x8000).map(|p| p.to_string()), Some(String::from("r-x")) );
+		// - Writable segments are RAM, with the tail past the file data zeroed
+		assert_eq!( mem.read_u32(0x10000).and_then(|v| v.val_known()), Some(0x04030201) );
+		assert_eq!( mem.read_u32(0x10004).and_then(|v| v.val_known()), Some(0) );
+		assert_eq!( mem.permissions(0x10004).map(|p| p.to_string()), Some(String::from("rw-")) );
+		assert!( mem.read_u8(0x10008).is_none() );
+	}
+	
+	#[test]
+	fn bad_headers()
+	{
+		let mut mem = MemoryState::new();
+		assert!( load(&mut mem, &temp_file("elf_magic", b"MZ\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")).is_err() );
+		let mut d = arm_elf();
+		d.truncate(0x120);
+		assert!( load(&mut MemoryState::new(), &temp_file("elf_truncated", &d)).is_err() );
+		// - Segments that can't fit in memory, or in the address space
+		let mut d = arm_elf();
+		put(&mut d, 0x54 + 20, 0xFFFF_FFFF, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("elf_memsz", &d)).is_err() );
+		let mut d = arm_elf();
+		put(&mut d, 0x54 + 8, 0xFFFF_FFFC, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("elf_vaddr", &d)).is_err() );
+		// - Overlapping segments
+		let mut d = arm_elf();
+		put(&mut d, 0x54 + 8, 0x8004, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("elf_overlap", &d)).is_err() );
+		// - Tables past the end of the file
+		let mut d = arm_elf();
+		put(&mut d, 32, 0xFFFF_FF00, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("elf_shoff", &d)).is_err() );
+	}
+}
+
+// vim: ft=rust
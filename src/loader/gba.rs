@@ -0,0 +1,191 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// loader/gba.rs
+// - GameBoy Advance cartridge loader
+use disasm::CodePtr;
+use memory::{MemoryState,Endian,Permissions};
+
+const ROM_BASE: u64 = 0x08000000;
+const HEADER_SIZE: usize = 0xC0;
+
+/// Nintendo logo, must be present at 0x04 in the header for the BIOS to boot the cartridge (also used by the DS)
+pub static LOGO: [u8; 156] = [
+	0x24,0xFF,0xAE,0x51,0x69,0x9A,0xA2,0x21,0x3D,0x84,0x82,0x0A,0x84,0xE4,0x09,0xAD,
+	0x11,0x24,0x8B,0x98,0xC0,0x81,0x7F,0x21,0xA3,0x52,0xBE,0x19,0x93,0x09,0xCE,0x20,
+	0x10,0x46,0x4A,0x4A,0xF8,0x27,0x31,0xEC,0x58,0xC7,0xE8,0x33,0x82,0xE3,0xCE,0xBF,
+	0x85,0xF4,0xDF,0x94,0xCE,0x4B,0x09,0xC1,0x94,0x56,0x8A,0xC0,0x13,0x72,0xA7,0xFC,
+	0x9F,0x84,0x4D,0x73,0xA3,0xCA,0x9A,0x61,0x58,0x97,0xA3,0x27,0xFC,0x03,0x98,0x76,
+	0x23,0x1D,0xC7,0x61,0x03,0x04,0xAE,0x56,0xBF,0x38,0x84,0x00,0x40,0xA7,0x0E,0xFD,
+	0xFF,0x52,0xFE,0x03,0x6F,0x95,0x30,0xF1,0x97,0xFB,0xC0,0x85,0x60,0xD6,0x80,0x25,
+	0xA9,0x63,0xBE,0x03,0x01,0x4E,0x38,0xE2,0xF9,0xA2,0x34,0xFF,0xBB,0x3E,0x03,0x44,
+	0x78,0x00,0x90,0xCB,0x88,0x11,0x3A,0x94,0x65,0xC0,0x7C,0x63,0x87,0xF0,0x3C,0xAF,
+	0xD6,0x25,0xE4,0x8B,0x38,0x0A,0xAC,0x72,0x21,0xD4,0xF8,0x07,
+	];
+
+/// Load a GBA cartridge ROM, and set up the rest of the standard memory map around it
+///
+/// The I/O registers are described by `gba_io.mmio` (next to the ROM) if present. ROMs with a
+/// bad header logo or checksum are rejected unless `ignore_header` is set.
+pub fn load(memory: &mut MemoryState, path: &str, ignore_header: bool) -> Result<super::Program,String>
+{
+	use std::io::Read;
+	let mut data = Vec::new();
+	if let Err(e) = ::std::fs::File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+		return Err( format!("Unable to read ROM: {}", e) );
+	}
+	if data.len() < HEADER_SIZE {
+		return Err( format!("ROM too small for a cartridge header ({:#x} bytes)", data.len()) );
+	}
+	if data.len() > 0x02000000 {
+		return Err( format!("ROM larger than the 32MiB cartridge space ({:#x} bytes)", data.len()) );
+	}
+
+	// - Cartridge header
+	let hdr = &data[..HEADER_SIZE];
+	if hdr[0xB2] != 0x96 {
+		return Err( format!("Not a GBA ROM (fixed value at 0xB2 is {:#x}, expected 0x96)", hdr[0xB2]) );
+	}
+	let mut bad_header = None;
+	if &hdr[0x04 .. 0xA0] != &LOGO[..] {
+		bad_header = Some( format!("GBA header logo doesn't match, the BIOS wouldn't boot this ROM") );
+	}
+	let complement = hdr[0xA0 .. 0xBD].iter().fold(0u8, |v, &b| v.wrapping_sub(b)).wrapping_sub(0x19);
+	if hdr[0xBD] != complement {
+		bad_header = Some( format!("GBA header checksum is {:#x}, expected {:#x}", hdr[0xBD], complement) );
+	}
+	match bad_header
+	{
+	Some(msg) => if ignore_header { warn!("{}", msg) } else { return Err(msg) },
+	None => {},
+	}
+	let title = String::from_utf8_lossy(&hdr[0xA0 .. 0xAC]).trim_matches('\0').to_string();
+	let code = String::from_utf8_lossy(&hdr[0xAC .. 0xB0]).into_owned();
+	let maker = String::from_utf8_lossy(&hdr[0xB0 .. 0xB2]).into_owned();
+	if !code.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+		warn!("GBA game code {:?} isn't alphanumeric", code);
+	}
+	let description = format!("GBA ROM: \"{}\" (AGB-{}, maker {}, version {}), {:#x} bytes", title, code, maker, hdr[0xBC], data.len());
+	info!("{}", description);
+
+	// - Standard memory map
+	// > The BIOS (0x0 - 0x4000) isn't available, so is left unmapped
+	memory.set_endian(Endian::Little);
+	memory.add_ram(0x02000000, 0x40000);	// On-board WRAM
+	memory.add_ram(0x03000000, 0x8000);	// On-chip WRAM
+	let io_desc = ::std::path::Path::new(path).with_file_name("gba_io.mmio");
+	let io_dev = if io_desc.exists() {
+			debug!("Using I/O register descriptions from {}", io_desc.display());
+			Some( try!(::parse::parse_mmio("gba_io", &io_desc)) )
+		}
+		else {
+			warn!("No I/O register descriptions ({} not found)", io_desc.display());
+			None
+		};
+	memory.add_mmio(0x04000000, 0x400, "gba_io", io_dev);	// I/O registers
+	memory.add_ram(0x05000000, 0x400);	// Palette RAM
+	memory.add_ram(0x06000000, 0x18000);	// VRAM
+	memory.add_ram(0x07000000, 0x400);	// OAM
+	let rom_size = data.len();
+	memory.add_rom_data(ROM_BASE, data);	// Game Pak ROM
+	memory.add_ram(0x0e000000, 0x10000);	// Game Pak SRAM/flash
+	// - 8-bit bus, code can't run from it
+	try!( memory.set_permissions(0x0e000000, Permissions { read: true, write: true, exec: false }) );
+	// - Mirrors
+	memory.add_mirror(0x02040000, 0xfc0000, 0x02000000);
+	memory.add_mirror(0x03008000, 0xff8000, 0x03000000);
+	memory.add_mirror(ROM_BASE + 0x02000000, rom_size, ROM_BASE);	// Wait state 1
+	memory.add_mirror(ROM_BASE + 0x04000000, rom_size, ROM_BASE);	// Wait state 2
+
+	// - Execution starts with the (ARM mode) branch at the start of the header
+	let mut program = super::Program::default();
+	program.description = Some(description);
+	program.cpu = ::disasm::cpus::pick("arm");
+	program.entrypoints.push( CodePtr::new(0, ROM_BASE) );
+	program.methods.push( (CodePtr::new(0, ROM_BASE), String::from("start")) );
+	Ok( program )
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use loader::test_util::temp_file;
+	
+	/// A minimal cartridge with a valid header
+	fn cartridge() -> Vec<u8>
+	{
+		let mut d = vec![0; 0x200];
+		d[0 .. 4].copy_from_slice(&[0x2E, 0x00, 0x00, 0xEA]);	// B 0xC0
+		d[0x04 .. 0xA0].copy_from_slice(&LOGO);
+		d[0xA0 .. 0xA8].copy_from_slice(b"TESTGAME");
+		d[0xAC .. 0xB2].copy_from_slice(b"ABCE01");
+		d[0xB2] = 0x96;
+		fix_checksum(&mut d);
+		d
+	}
+	fn fix_checksum(d: &mut [u8])
+	{
+		d[0xBD] = d[0xA0 .. 0xBD].iter().fold(0u8, |v, &b| v.wrapping_sub(b)).wrapping_sub(0x19);
+	}
+	fn load_data(name: &str, data: &[u8], ignore_header: bool) -> Result<(MemoryState, ::loader::Program),String>
+	{
+		let mut mem = MemoryState::new();
+		load(&mut mem, &temp_file(name, data), ignore_header).map(|p| (mem, p))
+	}
+	
+	#[test]
+	fn standard_map()
+	{
+		let (mem, program) = load_data("gba_good", &cartridge(), false).unwrap();
+		assert_eq!( program.entrypoints, vec![CodePtr::new(0, ROM_BASE)] );
+		assert!( program.description.unwrap().contains("\"TESTGAME\" (AGB-ABCE, maker 01") );
+		assert_eq!( mem.read_u32(ROM_BASE).and_then(|v| v.val_known()), Some(0xEA00002E) );
+		// - Wait state mirrors of the ROM, and of work RAM
+		assert_eq!( mem.read_u32(0x0A000000).and_then(|v| v.val_known()), Some(0xEA00002E) );
+		assert_eq!( mem.canonicalise(0x02040010), 0x02000010 );
+		assert_eq!( mem.permissions(0x0E000000).map(|p| p.exec), Some(false) );
+		assert!( mem.read_u8(0).is_none() );
+	}
+	
+	#[test]
+	fn bad_header()
+	{
+		// - Not a cartridge at all
+		let mut d = cartridge();
+		d[0xB2] = 0;
+		assert!( load_data("gba_fixed", &d, true).is_err() );
+		assert!( load_data("gba_small", &cartridge()[..0x80], true).is_err() );
+		// - Bad checksum or logo are errors, unless the header is ignored
+		let mut d = cartridge();
+		d[0xBD] ^= 1;
+		assert!( load_data("gba_checksum", &d, false).is_err() );
+		assert!( load_data("gba_checksum", &d, true).is_ok() );
+		let mut d = cartridge();
+		d[0x10] ^= 1;
+		assert!( load_data("gba_logo", &d, false).is_err() );
+		assert!( load_data("gba_logo", &d, true).is_ok() );
+	}
+	
+	#[test]
+	fn io_description()
+	{
+		use std::io::Write;
+		// - Registers are described by `gba_io.mmio` in the ROM's directory
+		let dir = ::std::env::temp_dir().join( format!("binaryview2-{}-gba_io", ::std::process::id()) );
+		::std::fs::create_dir_all(&dir).unwrap();
+		::std::fs::File::create(dir.join("gba_io.mmio")).unwrap().write_all(b"REG 0x0 16 DISPCNT 0x80\nEND\n").unwrap();
+		::std::fs::File::create(dir.join("rom.gba")).unwrap().write_all(&cartridge()).unwrap();
+		let mut mem = MemoryState::new();
+		let rv = load(&mut mem, dir.join("rom.gba").to_str().unwrap(), false);
+		::std::fs::remove_dir_all(&dir).unwrap();
+		rv.unwrap();
+		assert_eq!( mem.mmio_register(0x04000000).map(|(r,_)| &r.name[..]), Some("DISPCNT") );
+		// - Without one the I/O space is still mapped, just undescribed
+		let (mem, _) = load_data("gba_nodesc", &cartridge(), false).unwrap();
+		assert!( mem.mmio_register(0x04000000).is_none() );
+		assert!( mem.read_u8(0x04000000).unwrap().is_unknown() );
+	}
+}
+
+// vim: ft=rust
@@ -0,0 +1,263 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// loader/hexfile.rs
+// - Intel HEX and Motorola S-record loader
+use disasm::CodePtr;
+use memory::MemoryState;
+
+/// Contents of a hex file, before being mapped
+struct Records
+{
+	/// Data records (address, bytes)
+	data: Vec<(u64, Vec<u8>)>,
+	/// Start address record (if present)
+	start: Option<u64>,
+}
+
+/// Load an Intel HEX or Motorola S-record file (selected using the first character of the file)
+///
+/// Each contiguous run of data becomes a ROM region, and gaps between records are left unmapped.
+pub fn load(memory: &mut MemoryState, file: &mut ::std::fs::File) -> Result<super::Program,String>
+{
+	use std::io::{Read,Seek};
+	let mut text = String::new();
+	if let Err(e) = file.seek( ::std::io::SeekFrom::Start(0) ).and_then(|_| file.read_to_string(&mut text)) {
+		return Err( format!("Unable to read hex file: {}", e) );
+	}
+	let mut records = match text.trim().chars().next()
+		{
+		Some(':') => try!(parse_ihex(&text)),
+		Some('S') => try!(parse_srec(&text)),
+		_ => return Err( String::from("Unknown hex file format, expected Intel HEX or S-records") ),
+		};
+
+	// - Join adjacent records into regions
+	records.data.sort_by(|a, b| a.0.cmp(&b.0));
+	let mut chunks: Vec<(u64, Vec<u8>)> = Vec::new();
+	for (addr, bytes) in records.data.into_iter()
+	{
+		if bytes.len() == 0 {
+			continue ;
+		}
+		if let Some(&mut (base, ref mut cur)) = chunks.last_mut()
+		{
+			let end = base + cur.len() as u64;
+			if addr < end {
+				return Err( format!("Record at {:#x} overlaps previous data (ending at {:#x})", addr, end) );
+			}
+			if addr == end {
+				cur.extend(bytes);
+				continue ;
+			}
+		}
+		chunks.push( (addr, bytes) );
+	}
+
+	let mut program = super::Program::default();
+	if let Some(start) = records.start
+	{
+		if chunks.iter().any(|&(base, ref d)| base <= start && start < base + d.len() as u64) {
+			program.entrypoints.push( CodePtr::new(0, start) );
+		}
+		else {
+			warn!("Start address {:#x} isn't within the loaded data, ignoring", start);
+		}
+	}
+	debug!("Hex file: {} regions, start={:?}", chunks.len(), records.start);
+	for (base, data) in chunks.into_iter()
+	{
+		memory.add_rom_data(base, data);
+	}
+	Ok( program )
+}
+
+/// Decode a string of hex digit pairs
+fn decode_hex(s: &str, line: usize) -> Result<Vec<u8>,String>
+{
+	if s.len() % 2 != 0 {
+		return Err( format!("Line {}: Odd number of hex digits", line) );
+	}
+	(0 .. s.len() / 2).map(|i| match s.get(i*2 .. i*2+2).and_then(|d| u8::from_str_radix(d, 16).ok())
+		{
+		Some(v) => Ok(v),
+		None => Err( format!("Line {}: Invalid hex digits at column {}", line, i*2+2) ),
+		}).collect()
+}
+
+/// Parse Intel HEX records
+fn parse_ihex(text: &str) -> Result<Records,String>
+{
+	let mut rv = Records { data: Vec::new(), start: None };
+	let mut base = 0u64;
+	for (i, line) in text.lines().enumerate()
+	{
+		let line = line.trim();
+		let lineno = i + 1;
+		if line.len() == 0 {
+			continue ;
+		}
+		if !line.starts_with(':') {
+			return Err( format!("Line {}: Expected ':' at the start of an Intel HEX record", lineno) );
+		}
+		let rec = try!(decode_hex(&line[1..], lineno));
+		if rec.len() < 5 || rec.len() != 5 + rec[0] as usize {
+			return Err( format!("Line {}: Record length doesn't match byte count", lineno) );
+		}
+		if rec.iter().fold(0u8, |v, &b| v.wrapping_add(b)) != 0 {
+			return Err( format!("Line {}: Checksum mismatch", lineno) );
+		}
+		let ofs = (rec[1] as u64) << 8 | rec[2] as u64;
+		let payload = &rec[4 .. rec.len()-1];
+		let be_val = || payload.iter().fold(0u64, |v, &b| v << 8 | b as u64);
+		match rec[3]
+		{
+		// Data
+		0x00 => rv.data.push( (base + ofs, payload.to_vec()) ),
+		// End of file
+		0x01 => break,
+		// Extended segment address (bits 4-19)
+		0x02 if payload.len() == 2 => base = be_val() << 4,
+		// Start segment address (CS:IP)
+		0x03 if payload.len() == 4 => rv.start = Some( (be_val() >> 16 << 4) + (be_val() & 0xFFFF) ),
+		// Extended linear address (bits 16-31)
+		0x04 if payload.len() == 2 => base = be_val() << 16,
+		// Start linear address
+		0x05 if payload.len() == 4 => rv.start = Some( be_val() ),
+		v @ _ => return Err( format!("Line {}: Unknown or malformed record type {:#x}", lineno, v) ),
+		}
+	}
+	Ok( rv )
+}
+
+/// Parse Motorola S-records
+fn parse_srec(text: &str) -> Result<Records,String>
+{
+	let mut rv = Records { data: Vec::new(), start: None };
+	for (i, line) in text.lines().enumerate()
+	{
+		let line = line.trim();
+		let lineno = i + 1;
+		if line.len() == 0 {
+			continue ;
+		}
+		let (ty, digits) = match (line.as_bytes().get(1), line.get(2..))
+			{
+			(Some(&ty), Some(digits)) if line.starts_with('S') => (ty, digits),
+			_ => return Err( format!("Line {}: Expected 'S<type>' at the start of an S-record", lineno) ),
+			};
+		let rec = try!(decode_hex(digits, lineno));
+		if rec.len() < 1 || rec.len() != 1 + rec[0] as usize {
+			return Err( format!("Line {}: Record length doesn't match byte count", lineno) );
+		}
+		if rec.iter().fold(0u8, |v, &b| v.wrapping_add(b)) != 0xFF {
+			return Err( format!("Line {}: Checksum mismatch", lineno) );
+		}
+		let addr_len = match ty
+			{
+			b'0' | b'1' | b'5' | b'9' => 2,
+			b'2' | b'6' | b'8' => 3,
+			b'3' | b'7' => 4,
+			_ => return Err( format!("Line {}: Unknown record type S{}", lineno, ty as char) ),
+			};
+		if rec.len() < 2 + addr_len {
+			return Err( format!("Line {}: Record too short for its address", lineno) );
+		}
+		let addr = rec[1 .. 1+addr_len].iter().fold(0u64, |v, &b| v << 8 | b as u64);
+		let payload = &rec[1+addr_len .. rec.len()-1];
+		match ty
+		{
+		// Data
+		b'1' | b'2' | b'3' => rv.data.push( (addr, payload.to_vec()) ),
+		// Start address
+		b'7' | b'8' | b'9' => rv.start = Some(addr),
+		// Header and record counts
+		_ => {},
+		}
+	}
+	Ok( rv )
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use loader::test_util::temp_file;
+	
+	/// Build an Intel HEX record (with a correct checksum)
+	fn ihex(ofs: u16, ty: u8, payload: &[u8]) -> String
+	{
+		let mut rec = vec![payload.len() as u8, (ofs >> 8) as u8, ofs as u8, ty];
+		rec.extend_from_slice(payload);
+		let sum = rec.iter().fold(0u8, |v, &b| v.wrapping_add(b));
+		rec.push( 0u8.wrapping_sub(sum) );
+		format!(":{}\n", rec.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+	}
+	/// Build a Motorola S-record (with a correct checksum)
+	fn srec(ty: char, addr: &[u8], payload: &[u8]) -> String
+	{
+		let mut rec = vec![(addr.len() + payload.len() + 1) as u8];
+		rec.extend_from_slice(addr);
+		rec.extend_from_slice(payload);
+		let sum = rec.iter().fold(0u8, |v, &b| v.wrapping_add(b));
+		rec.push( !sum );
+		format!("S{}{}\n", ty, rec.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+	}
+	
+	#[test]
+	fn ihex_records()
+	{
+		let r = parse_ihex(":0B0010006164647265737320676170A7\n:00000001FF\n:0100000000FF\n").unwrap();
+		assert_eq!( r.data, vec![(0x10, b"address gap".to_vec())] );
+		assert_eq!( r.start, None );
+		assert!( parse_ihex(":0B0010006164647265737320676170A8\n").is_err() );
+		assert!( parse_ihex(":0C0010006164647265737320676170A7\n").is_err() );
+		assert!( parse_ihex("0B0010006164647265737320676170A7\n").is_err() );
+		assert!( parse_ihex(&ihex(0, 0x06, &[])).is_err() );
+	}
+	
+	#[test]
+	fn ihex_extended_address()
+	{
+		let text = ihex(0, 0x04, &[0x08, 0x00]) + &ihex(0x10, 0x00, &[1, 2]) + &ihex(0, 0x05, &[0x08, 0x00, 0x00, 0x10])
+			+ &ihex(0, 0x02, &[0x12, 0x34]) + &ihex(0x1, 0x00, &[3]) + &ihex(0, 0x03, &[0x12, 0x34, 0x00, 0x05]);
+		let r = parse_ihex(&text).unwrap();
+		assert_eq!( r.data, vec![(0x0800_0010, vec![1, 2]), (0x12341, vec![3])] );
+		assert_eq!( r.start, Some(0x12345) );
+	}
+	
+	#[test]
+	fn srec_records()
+	{
+		let text = srec('0', &[0, 0], b"hdr") + &srec('1', &[0x10, 0x00], &[1, 2])
+			+ &srec('2', &[0x01, 0x00, 0x00], &[3]) + &srec('3', &[0x08, 0x00, 0x00, 0x00], &[4])
+			+ &srec('5', &[0, 3], &[]) + &srec('7', &[0x08, 0x00, 0x00, 0x00], &[]);
+		let r = parse_srec(&text).unwrap();
+		assert_eq!( r.data, vec![(0x1000, vec![1, 2]), (0x10000, vec![3]), (0x0800_0000, vec![4])] );
+		assert_eq!( r.start, Some(0x0800_0000) );
+		assert!( parse_srec("S1051000010200\n").is_err() );
+		assert!( parse_srec(&srec('4', &[0, 0], &[])).is_err() );
+		assert!( parse_srec(&srec('3', &[0, 0], &[])).is_err() );
+	}
+	
+	#[test]
+	fn load_regions()
+	{
+		let text = ihex(0x100, 0x00, &[1, 2]) + &ihex(0x102, 0x00, &[3, 4]) + &ihex(0x200, 0x00, &[5]) + &ihex(0, 0x05, &[0, 0, 0x01, 0x02]);
+		let mut mem = MemoryState::new();
+		let program = load(&mut mem, &mut ::std::fs::File::open(temp_file("ihex_good", text.as_bytes())).unwrap()).unwrap();
+		assert_eq!( program.entrypoints, vec![CodePtr::new(0, 0x102)] );
+		// - Adjacent records are joined, gaps are unmapped
+		assert_eq!( mem.read_u32(0x100).and_then(|v| v.val_known()), Some(0x04030201) );
+		assert!( mem.read_u8(0x104).is_none() );
+		assert_eq!( mem.read_u8(0x200).and_then(|v| v.val_known()), Some(5) );
+		
+		let text = ihex(0x100, 0x00, &[1, 2]) + &ihex(0x101, 0x00, &[3]);
+		let mut file = ::std::fs::File::open(temp_file("ihex_overlap", text.as_bytes())).unwrap();
+		assert!( load(&mut MemoryState::new(), &mut file).is_err() );
+		let mut file = ::std::fs::File::open(temp_file("ihex_unknown", b"garbage")).unwrap();
+		assert!( load(&mut MemoryState::new(), &mut file).is_err() );
+	}
+}
+
+// vim: ft=rust
@@ -0,0 +1,110 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// loader/mod.rs
+// - Loaders for executable file formats
+use disasm::CodePtr;
+use memory::MemoryState;
+
+pub mod elf;
+pub mod gba;
+pub mod hexfile;
+pub mod nds;
+pub mod pe;
+
+/// Loader for an executable file (given its path)
+pub type LoadFn = fn(&mut MemoryState, &str) -> Result<Program,String>;
+
+/// Information about a program, obtained while loading it into memory
+#[derive(Default)]
+pub struct Program
+{
+	/// Addresses to start disassembly from
+	pub entrypoints: Vec<CodePtr>,
+	/// Named methods (e.g. from a symbol table)
+	pub methods: Vec<(CodePtr, String)>,
+	/// Functions outside the program (e.g. imports), with the name of their calling convention
+	pub externals: Vec<(CodePtr, String, String)>,
+	/// Entrypoints tagged with the CPU that runs them, for systems with more than one (e.g. "arm9")
+	pub cores: Vec<(&'static str, Vec<CodePtr>)>,
+	/// CPU that the program is for (None if not known)
+	pub cpu: Option<&'static ::disasm::CPU>,
+	/// Description of the program (e.g. the title from a cartridge header), printed before the disassembly
+	pub description: Option<String>,
+}
+
+/// Read an entire file
+fn read_file(path: &str) -> Result<Vec<u8>,String>
+{
+	use std::io::Read;
+	let mut fp = match ::std::fs::File::open(path)
+		{
+		Ok(f) => f,
+		Err(e) => return Err( format!("Unable to open '{}': {}", path, e) ),
+		};
+	let mut data = Vec::new();
+	match fp.read_to_end(&mut data)
+	{
+	Ok(_) => Ok(data),
+	Err(e) => Err( format!("Unable to read '{}': {}", path, e) ),
+	}
+}
+
+/// Check that a range doesn't overlap anything already loaded (list of (start, end))
+fn check_free(loaded: &[(u64,u64)], base: u64, size: u64) -> Result<(),String>
+{
+	match loaded.iter().find(|&&(s,e)| s < base.saturating_add(size) && base < e)
+	{
+	Some(&(s,e)) => Err( format!("{:#x}+{:#x} overlaps data already loaded at {:#x}-{:#x}", base, size, s, e) ),
+	None => Ok( () ),
+	}
+}
+
+/// Helpers for building test files
+#[cfg(test)]
+mod test_util
+{
+	/// Temporary file holding test data (derefs to its path), deleted when dropped
+	pub struct TempFile(String);
+	impl ::std::ops::Deref for TempFile
+	{
+		type Target = str;
+		fn deref(&self) -> &str {
+			&self.0
+		}
+	}
+	impl AsRef<::std::path::Path> for TempFile
+	{
+		fn as_ref(&self) -> &::std::path::Path {
+			self.0.as_ref()
+		}
+	}
+	impl Drop for TempFile
+	{
+		fn drop(&mut self) {
+			let _ = ::std::fs::remove_file(&self.0);
+		}
+	}
+	
+	/// Write test data to a temporary file
+	pub fn temp_file(name: &str, data: &[u8]) -> TempFile
+	{
+		use std::io::Write;
+		let path = ::std::env::temp_dir().join( format!("binaryview2-{}-{}", ::std::process::id(), name) );
+		::std::fs::File::create(&path).unwrap().write_all(data).unwrap();
+		TempFile( path.to_str().unwrap().to_owned() )
+	}
+	
+	/// Store a little-endian value of `len` bytes, growing the data if needed
+	pub fn put(data: &mut Vec<u8>, ofs: usize, val: u64, len: usize)
+	{
+		if data.len() < ofs + len {
+			data.resize(ofs + len, 0);
+		}
+		for i in 0 .. len {
+			data[ofs + i] = (val >> (i * 8)) as u8;
+		}
+	}
+}
+
+// vim: ft=rust
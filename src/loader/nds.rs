@@ -0,0 +1,362 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// loader/nds.rs
+// - Nintendo DS ROM loader
+use disasm::{CodePtr,CPUMode};
+use memory::{MemoryState,Endian};
+
+const HEADER_SIZE: usize = 0x200;
+/// Size of an entry in an overlay table
+const OVERLAY_ENTRY_SIZE: u64 = 32;
+/// Flag (in the last word of an overlay entry) set if the overlay is compressed
+const OVERLAY_COMPRESSED: u64 = 1 << 24;
+/// Largest overlay (including .bss) that will be loaded, the size of main memory
+const MAX_OVERLAY_SIZE: u64 = 0x400000;
+
+/// RAM areas (base, size), with any code loaded into them mapped separately
+static RAM_AREAS: [(u64, u64); 3] = [
+	(0x02000000, 0x400000),	// Main memory
+	(0x03000000, 0x8000),	// Shared WRAM
+	(0x03800000, 0x10000),	// ARM7 WRAM
+	];
+
+/// An overlay, before being mapped
+struct Overlay
+{
+	id: u64,
+	base: u64,
+	/// Contents (with the zeroed .bss)
+	data: Vec<u8>,
+	/// Table of static initialiser pointers (start, end)
+	init: (u64, u64),
+}
+
+fn bytes(data: &[u8], ofs: u64, len: u64) -> Result<&[u8],String>
+{
+	if ofs > data.len() as u64 || len > data.len() as u64 - ofs {
+		return Err( format!("ROM truncated (reading {:#x}+{:#x}, file is {:#x} bytes)", ofs, len, data.len()) );
+	}
+	Ok( &data[ofs as usize .. (ofs + len) as usize] )
+}
+/// Read a little-endian value
+fn le(data: &[u8], ofs: u64, len: u64) -> Result<u64,String>
+{
+	Ok( try!(bytes(data, ofs, len)).iter().rev().fold(0, |v, &x| v << 8 | x as u64) )
+}
+
+/// CRC-16 (as used by the DS BIOS)
+fn crc16(data: &[u8]) -> u16
+{
+	let mut crc = 0xFFFFu16;
+	for &b in data
+	{
+		crc ^= b as u16;
+		for _ in 0 .. 8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+		}
+	}
+	crc
+}
+
+/// Code pointer for an address, where the bottom bit selects Thumb mode
+fn code_ptr(bank: ::memory::BankId, addr: u64) -> CodePtr
+{
+	CodePtr::new_banked((addr & 1) as CPUMode, bank, addr & !1)
+}
+
+/// Load a Nintendo DS ROM
+///
+/// Maps the ARM9 and ARM7 binaries (with RAM around them), and each CPU's overlays. Overlays that
+/// share an address become banks of a banked region. Entrypoints are returned for each CPU.
+pub fn load(memory: &mut MemoryState, path: &str) -> Result<super::Program,String>
+{
+	let data = try!(super::read_file(path));
+	if data.len() < HEADER_SIZE {
+		return Err( format!("'{}' is too small for a DS header ({:#x} bytes)", path, data.len()) );
+	}
+
+	// - Header
+	let hdr = &data[..HEADER_SIZE];
+	let stored_crc = try!(le(hdr, 0x15E, 2)) as u16;
+	if crc16(&hdr[..0x15E]) != stored_crc {
+		warn!("DS header checksum is {:#x}, expected {:#x}", stored_crc, crc16(&hdr[..0x15E]));
+	}
+	if &hdr[0xC0 .. 0x15C] != &super::gba::LOGO[..] {
+		warn!("DS header logo doesn't match, the BIOS wouldn't boot this ROM");
+	}
+	let title = String::from_utf8_lossy(&hdr[0x00 .. 0x0C]).trim_matches('\0').to_string();
+	let code = String::from_utf8_lossy(&hdr[0x0C .. 0x10]).into_owned();
+	info!("DS ROM: \"{}\" (NTR-{}), {:#x} bytes", title, code, data.len());
+	let fat = (try!(le(hdr, 0x48, 4)), try!(le(hdr, 0x4C, 4)));
+
+	memory.set_endian(Endian::Little);
+	let mut program = super::Program::default();
+	program.cpu = ::disasm::cpus::pick("arm");
+	// - Ranges that have been mapped (start, end)
+	let mut loaded = Vec::new();
+	// (name, header offset of the binary's info, header offset of the overlay table)
+	for &(name, bin_hdr, ovl_hdr) in [("arm9", 0x20, 0x50), ("arm7", 0x30, 0x58)].iter()
+	{
+		let rom_ofs = try!(le(hdr, bin_hdr, 4));
+		let entry   = try!(le(hdr, bin_hdr + 4, 4));
+		let ram     = try!(le(hdr, bin_hdr + 8, 4));
+		let size    = try!(le(hdr, bin_hdr + 12, 4));
+		debug!("{}: {:#x}+{:#x} from {:#x}, entry {:#x}", name, ram, size, rom_ofs, entry);
+		if size == 0 {
+			warn!("DS ROM has no {} binary", name);
+			continue ;
+		}
+		try!(super::check_free(&loaded, ram, size));
+		memory.add_ram_data(ram, try!(bytes(&data, rom_ofs, size)));
+		loaded.push( (ram, ram + size) );
+
+		let start = code_ptr(0, entry);
+		program.methods.push( (start, format!("{}_start", name)) );
+		let mut entrypoints = vec![start];
+		let (ovl_ofs, ovl_size) = (try!(le(hdr, ovl_hdr, 4)), try!(le(hdr, ovl_hdr + 4, 4)));
+		let overlays = try!(read_overlays(&data, ovl_ofs, ovl_size, fat));
+		try!(map_overlays(memory, name, overlays, &mut loaded, &mut entrypoints, &mut program.methods));
+		program.cores.push( (name, entrypoints) );
+	}
+
+	// - The rest of the memory map
+	// > The BIOSes and TCMs aren't available, so are left unmapped
+	for &(base, size) in RAM_AREAS.iter()
+	{
+		map_ram_around(memory, base, size, &loaded);
+	}
+	memory.add_mmio(0x04000000, 0x2000, "nds_io", None);	// I/O registers
+	memory.add_ram(0x05000000, 0x800);	// Palette RAM
+	memory.add_ram(0x06800000, 0xA4000);	// VRAM (LCDC view)
+	memory.add_ram(0x07000000, 0x800);	// OAM
+
+	Ok( program )
+}
+
+/// Read an overlay table (y9/y7), getting the contents of each overlay from the FAT (offset, size)
+fn read_overlays(data: &[u8], ofs: u64, size: u64, fat: (u64,u64)) -> Result<Vec<Overlay>,String>
+{
+	let mut rv = Vec::new();
+	for i in 0 .. size / OVERLAY_ENTRY_SIZE
+	{
+		let ent = ofs + i * OVERLAY_ENTRY_SIZE;
+		let id       = try!(le(data, ent, 4));
+		let base     = try!(le(data, ent + 4, 4));
+		let ram_size = try!(le(data, ent + 8, 4));
+		let bss_size = try!(le(data, ent + 12, 4));
+		let init     = (try!(le(data, ent + 16, 4)), try!(le(data, ent + 20, 4)));
+		let file_id  = try!(le(data, ent + 24, 4));
+		let flags    = try!(le(data, ent + 28, 4));
+		if (file_id + 1) * 8 > fat.1 {
+			return Err( format!("Overlay {} has an invalid file ID {}", id, file_id) );
+		}
+		let start = try!(le(data, fat.0 + file_id * 8, 4));
+		let end   = try!(le(data, fat.0 + file_id * 8 + 4, 4));
+		if end < start {
+			return Err( format!("Overlay {} has an invalid FAT entry ({:#x}-{:#x})", id, start, end) );
+		}
+		if ram_size + bss_size > MAX_OVERLAY_SIZE {
+			return Err( format!("Overlay {} is too large ({:#x}+{:#x} bytes)", id, ram_size, bss_size) );
+		}
+		if flags & OVERLAY_COMPRESSED != 0 {
+			warn!("Overlay {} is compressed, not loading", id);
+			continue ;
+		}
+		let mut contents = try!(bytes(data, start, end - start)).to_vec();
+		contents.resize((ram_size + bss_size) as usize, 0);
+		rv.push(Overlay { id: id, base: base, data: contents, init: init });
+	}
+	Ok( rv )
+}
+
+/// Map a CPU's overlays, adding static initialisers as entrypoints
+///
+/// Overlays with overlapping addresses are grouped into a writable banked region (one bank per overlay, none loaded initially)
+fn map_overlays(
+	memory: &mut MemoryState,
+	cpu_name: &str,
+	mut overlays: Vec<Overlay>,
+	loaded: &mut Vec<(u64,u64)>,
+	entrypoints: &mut Vec<CodePtr>,
+	methods: &mut Vec<(CodePtr,String)>
+	) -> Result<(),String>
+{
+	overlays.retain(|o| o.data.len() > 0);
+	overlays.sort_by(|a, b| a.base.cmp(&b.base));
+	let mut groups: Vec<(u64, u64, Vec<Overlay>)> = Vec::new();
+	for o in overlays.into_iter()
+	{
+		let end = o.base + o.data.len() as u64;
+		if let Some(&mut (_, ref mut g_end, ref mut members)) = groups.last_mut()
+		{
+			if o.base < *g_end {
+				*g_end = ::std::cmp::max(*g_end, end);
+				members.push(o);
+				continue ;
+			}
+		}
+		groups.push( (o.base, end, vec![o]) );
+	}
+
+	for (base, end, members) in groups.into_iter()
+	{
+		try!(super::check_free(loaded, base, end - base));
+		let mut banks = Vec::new();
+		for (bank, o) in members.iter().enumerate()
+		{
+			debug!("- {} overlay {} at {:#x}+{:#x} (bank {})", cpu_name, o.id, o.base, o.data.len(), bank);
+			let mut contents = vec![0; (end - base) as usize];
+			let ofs = (o.base - base) as usize;
+			contents[ofs .. ofs + o.data.len()].copy_from_slice(&o.data);
+			// - Static initialisers are a table of function pointers (within the overlay)
+			let mut n = 0;
+			let mut addr = o.init.0;
+			while addr + 4 <= o.init.1
+			{
+				let ptr = match addr.checked_sub(o.base)
+					{
+					Some(ofs) => try!(le(&o.data, ofs, 4)),
+					None => return Err( format!("Overlay {} initialiser table isn't within the overlay", o.id) ),
+					};
+				if ptr != 0
+				{
+					let in_overlay = o.base <= ptr && ptr < o.base + o.data.len() as u64;
+					let ptr = code_ptr(if in_overlay { bank as ::memory::BankId } else { 0 }, ptr);
+					entrypoints.push(ptr);
+					methods.push( (ptr, format!("{}_overlay{}_init{}", cpu_name, o.id, n)) );
+					n += 1;
+				}
+				addr += 4;
+			}
+			banks.push(contents);
+		}
+		memory.add_overlay_data(base, banks);
+		loaded.push( (base, end) );
+	}
+	Ok( () )
+}
+
+/// Map RAM over an area, leaving holes for ranges that are already mapped
+fn map_ram_around(memory: &mut MemoryState, base: u64, size: u64, loaded: &[(u64,u64)])
+{
+	let end = base + size;
+	let mut used: Vec<_> = loaded.iter().filter(|&&(s,e)| s < end && base < e).cloned().collect();
+	used.sort();
+	let mut pos = base;
+	for (s, e) in used
+	{
+		if s > pos {
+			memory.add_ram(pos, (s - pos) as usize);
+		}
+		pos = ::std::cmp::max(pos, e);
+	}
+	if pos < end {
+		memory.add_ram(pos, (end - pos) as usize);
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use loader::test_util::{temp_file,put};
+	
+	/// A ROM with ARM9 and ARM7 binaries, and two ARM9 overlays sharing an address
+	fn rom() -> Vec<u8>
+	{
+		let mut d = vec![0; HEADER_SIZE];
+		d[0 .. 8].copy_from_slice(b"TESTGAME");
+		d[0xC0 .. 0x15C].copy_from_slice(&::loader::gba::LOGO);
+		for &(hdr, rom_ofs, entry, ram) in [(0x20, 0x200, 0x02000000, 0x02000000), (0x30, 0x210, 0x03800001, 0x03800000)].iter()
+		{
+			put(&mut d, hdr, rom_ofs, 4);
+			put(&mut d, hdr + 4, entry, 4);
+			put(&mut d, hdr + 8, ram, 4);
+			put(&mut d, hdr + 12, 0x10, 4);
+		}
+		put(&mut d, 0x48, 0x300, 4);	// FAT
+		put(&mut d, 0x4C, 16, 4);
+		put(&mut d, 0x50, 0x240, 4);	// ARM9 overlay table
+		put(&mut d, 0x54, 64, 4);
+		let crc = crc16(&d[.. 0x15E]);
+		put(&mut d, 0x15E, crc as u64, 2);
+		put(&mut d, 0x200, 0xE12FFF1E, 4);
+		// - Overlay 0 (with a .bss and a Thumb initialiser) and overlay 1, overlapping it
+		for &(ent, id, base, ram_size, bss_size, init) in [(0x240, 0, 0x02100000, 0x10, 0x10, 0x02100000), (0x260, 1, 0x02100008, 0x8, 0, 0)].iter()
+		{
+			put(&mut d, ent, id, 4);
+			put(&mut d, ent + 4, base, 4);
+			put(&mut d, ent + 8, ram_size, 4);
+			put(&mut d, ent + 12, bss_size, 4);
+			put(&mut d, ent + 16, init, 4);
+			put(&mut d, ent + 20, if init != 0 { init + 4 } else { 0 }, 4);
+			put(&mut d, ent + 24, id, 4);
+		}
+		put(&mut d, 0x300, 0x320, 4);
+		put(&mut d, 0x304, 0x330, 4);
+		put(&mut d, 0x308, 0x330, 4);
+		put(&mut d, 0x30C, 0x338, 4);
+		put(&mut d, 0x320, 0x02100005, 4);
+		put(&mut d, 0x330, 0xAAAAAAAA_AAAAAAAA, 8);
+		d
+	}
+	
+	#[test]
+	fn load_binaries()
+	{
+		let mut mem = MemoryState::new();
+		let program = load(&mut mem, &temp_file("nds_good", &rom())).unwrap();
+		assert_eq!( program.cores, vec![
+			("arm9", vec![CodePtr::new(0, 0x02000000), CodePtr::new_banked(1, 0, 0x02100004)]),
+			("arm7", vec![CodePtr::new(1, 0x03800000)]),
+			] );
+		assert!( program.methods.contains( &(CodePtr::new_banked(1, 0, 0x02100004), String::from("arm9_overlay0_init0")) ) );
+		assert_eq!( mem.read_u32(0x02000000).and_then(|v| v.val_known()), Some(0xE12FFF1E) );
+		// - RAM is mapped around the loaded code
+		assert!( mem.read_u8(0x02000010).unwrap().is_unknown() );
+		assert!( mem.read_u8(0x02100020).unwrap().is_unknown() );
+		assert!( mem.read_u8(0x03800010).unwrap().is_unknown() );
+		assert!( mem.read_u8(0x03008000).is_none() );
+	}
+	
+	#[test]
+	fn overlays()
+	{
+		let mut mem = MemoryState::new();
+		load(&mut mem, &temp_file("nds_overlays", &rom())).unwrap();
+		// - Overlapping overlays are banks of one writable region, with no bank loaded initially
+		assert_eq!( mem.banked_region(0x02100018), Some(0x02100000) );
+		assert_eq!( mem.bank_at(&Default::default(), 0x02100000), None );
+		assert_eq!( mem.permissions(0x02100000).map(|p| p.write), Some(true) );
+		assert_eq!( mem.read_in_bank::<u32>(0, 0x02100000).and_then(|v| v.val_known()), Some(0x02100005) );
+		assert_eq!( mem.read_in_bank::<u8>(0, 0x02100018).and_then(|v| v.val_known()), Some(0) );
+		assert_eq!( mem.read_in_bank::<u8>(1, 0x02100008).and_then(|v| v.val_known()), Some(0xAA) );
+		assert_eq!( mem.read_in_bank::<u8>(1, 0x02100000).and_then(|v| v.val_known()), Some(0) );
+	}
+	
+	#[test]
+	fn bad_roms()
+	{
+		assert!( load(&mut MemoryState::new(), &temp_file("nds_small", &[0; 0x100])).is_err() );
+		// - Both binaries loaded at the same address
+		let mut d = rom();
+		put(&mut d, 0x38, 0x02000008, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("nds_overlap", &d)).is_err() );
+		// - Overlay files outside the FAT, or past the end of the ROM
+		let mut d = rom();
+		put(&mut d, 0x260 + 24, 5, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("nds_file_id", &d)).is_err() );
+		let mut d = rom();
+		put(&mut d, 0x30C, 0x1000, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("nds_fat", &d)).is_err() );
+		// - Overlays larger than main memory
+		let mut d = rom();
+		put(&mut d, 0x240 + 12, 0xFFFFFFFF, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("nds_bss", &d)).is_err() );
+		
+		assert_eq!( crc16(b"123456789"), 0x4B37 );
+	}
+}
+
+// vim: ft=rust
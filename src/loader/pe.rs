@@ -0,0 +1,404 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// loader/pe.rs
+// - PE/COFF (Windows) executable loader
+use disasm::CodePtr;
+use memory::{MemoryState,Endian,Permissions};
+
+const IMAGE_FILE_MACHINE_I386: u16 = 0x014c;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+const DIR_EXPORT: u64 = 0;
+const DIR_IMPORT: u64 = 1;
+
+/// Spacing between the addresses given to imported functions
+const IMPORT_STUB_SIZE: u64 = 0x10;
+
+/// Read a little-endian value from the file
+fn le(data: &[u8], ofs: u64, len: u64) -> Result<u64,String>
+{
+	if ofs > data.len() as u64 || len > data.len() as u64 - ofs {
+		return Err( format!("PE truncated (reading {:#x}+{:#x}, file is {:#x} bytes)", ofs, len, data.len()) );
+	}
+	Ok( data[ofs as usize .. (ofs + len) as usize].iter().rev().fold(0, |v, &x| v << 8 | x as u64) )
+}
+
+/// A section of the loaded image (before being mapped)
+struct Section
+{
+	rva: u64,
+	data: Vec<u8>,
+	perms: Permissions,
+}
+
+/// The loaded image, addressed using RVAs (offsets from the image base)
+struct Image
+{
+	sections: Vec<Section>,
+}
+
+impl Image
+{
+	/// Locate `len` bytes at `rva`, returning the section index and offset
+	fn find(&self, rva: u64, len: u64) -> Result<(usize, usize),String>
+	{
+		for (i, s) in self.sections.iter().enumerate()
+		{
+			if s.rva <= rva && rva - s.rva < s.data.len() as u64
+			{
+				let ofs = rva - s.rva;
+				if len > s.data.len() as u64 - ofs {
+					return Err( format!("RVA {:#x}+{:#x} crosses the end of a section", rva, len) );
+				}
+				return Ok( (i, ofs as usize) );
+			}
+		}
+		Err( format!("RVA {:#x} isn't within a section", rva) )
+	}
+	fn uint(&self, rva: u64, len: u64) -> Result<u64,String>
+	{
+		let (i, ofs) = try!(self.find(rva, len));
+		Ok( le(&self.sections[i].data, ofs as u64, len).unwrap() )
+	}
+	fn write_uint(&mut self, rva: u64, len: u64, val: u64) -> Result<(),String>
+	{
+		let (i, ofs) = try!(self.find(rva, len));
+		for (j, b) in self.sections[i].data[ofs .. ofs + len as usize].iter_mut().enumerate() {
+			*b = (val >> (j * 8)) as u8;
+		}
+		Ok( () )
+	}
+	/// Read a NUL-terminated string
+	fn string(&self, rva: u64) -> Result<String,String>
+	{
+		let (i, ofs) = try!(self.find(rva, 1));
+		let rest = &self.sections[i].data[ofs ..];
+		match rest.iter().position(|&b| b == 0)
+		{
+		Some(len) => Ok( String::from_utf8_lossy(&rest[..len]).into_owned() ),
+		None => Err( format!("Unterminated string at RVA {:#x}", rva) ),
+		}
+	}
+	fn is_exec(&self, rva: u64) -> bool
+	{
+		self.find(rva, 1).map(|(i,_)| self.sections[i].perms.exec).unwrap_or(false)
+	}
+}
+
+/// Load a PE32 or PE32+ executable (x86 or x86-64)
+///
+/// Maps the headers and sections, and collects the entrypoint and exports. Each import is given an
+/// address past the end of the image (which is written to its IAT slot), and returned as an external.
+pub fn load(memory: &mut MemoryState, path: &str) -> Result<super::Program,String>
+{
+	let data = try!(super::read_file(path));
+	if data.len() < 0x40 || &data[0..2] != b"MZ" {
+		return Err( format!("'{}' isn't a PE file (no MZ header)", path) );
+	}
+	let pe = try!(le(&data, 0x3C, 4));
+	if try!(le(&data, pe, 4)) != 0x00004550 {
+		return Err( format!("'{}' isn't a PE file (no PE signature)", path) );
+	}
+
+	// - COFF header
+	let machine = try!(le(&data, pe + 4, 2)) as u16;
+	let nsections = try!(le(&data, pe + 6, 2));
+	let opt_size = try!(le(&data, pe + 20, 2));
+	let opt = pe + 24;
+	match machine
+	{
+	IMAGE_FILE_MACHINE_I386 | IMAGE_FILE_MACHINE_AMD64 => {},
+	_ => return Err( format!("PE machine {:#x} not supported", machine) ),
+	}
+
+	// - Optional header
+	let is_64 = match try!(le(&data, opt, 2))
+		{
+		0x10b => false,
+		0x20b => true,
+		v @ _ => return Err( format!("Unknown PE optional header magic {:#x}", v) ),
+		};
+	let word = if is_64 { 8 } else { 4 };
+	let entry_rva = try!(le(&data, opt + 16, 4));
+	let image_base = if is_64 { try!(le(&data, opt + 24, 8)) } else { try!(le(&data, opt + 28, 4)) };
+	let image_size = try!(le(&data, opt + 56, 4));
+	let headers_size = try!(le(&data, opt + 60, 4));
+	let (ndirs, dirs) = if is_64 { (try!(le(&data, opt + 108, 4)), opt + 112) } else { (try!(le(&data, opt + 92, 4)), opt + 96) };
+	let directory = |idx: u64| -> Result<Option<(u64,u64)>,String> {
+		if idx >= ndirs {
+			return Ok(None);
+		}
+		let rva = try!(le(&data, dirs + idx * 8, 4));
+		let size = try!(le(&data, dirs + idx * 8 + 4, 4));
+		Ok( if rva == 0 { None } else { Some( (rva, size) ) } )
+		};
+	debug!("PE{} machine={:#x}, base={:#x}, entry={:#x}, {} sections", if is_64 { "32+" } else { "32" }, machine, image_base, entry_rva, nsections);
+
+	// - Headers and sections
+	let mut image = Image { sections: Vec::new() };
+	image.sections.push(Section {
+		rva: 0,
+		data: try!(read_raw(&data, 0, headers_size, headers_size)),
+		perms: Permissions { read: true, write: false, exec: false },
+		});
+	let sh_base = opt + opt_size;
+	for i in 0 .. nsections
+	{
+		let sh = sh_base + i * 40;
+		let flags = try!(le(&data, sh + 36, 4)) as u32;
+		let name = String::from_utf8_lossy(&data[sh as usize .. sh as usize + 8]).trim_matches('\0').to_string();
+		let vsize = try!(le(&data, sh + 8, 4));
+		let rva = try!(le(&data, sh + 12, 4));
+		let raw_size = try!(le(&data, sh + 16, 4));
+		let raw_ofs = try!(le(&data, sh + 20, 4));
+		// - Some linkers leave the virtual size as zero
+		let size = if vsize != 0 { vsize } else { raw_size };
+		if size == 0 {
+			continue ;
+		}
+		let perms = Permissions {
+			read: flags & IMAGE_SCN_MEM_READ != 0,
+			write: flags & IMAGE_SCN_MEM_WRITE != 0,
+			exec: flags & IMAGE_SCN_MEM_EXECUTE != 0,
+			};
+		debug!("- Section {:?} {:#x}+{:#x} ({:#x} from file) {}", name, image_base + rva, size, raw_size, perms);
+		// Bytes past the end of the file data (e.g. uninitialised data) are zero
+		let contents = try!(read_raw(&data, raw_ofs, raw_size, size));
+		image.sections.push(Section { rva: rva, data: contents, perms: perms });
+	}
+
+	let mut program = super::Program::default();
+	program.cpu = ::disasm::cpus::pick("x86");
+	if entry_rva != 0 {
+		program.entrypoints.push( CodePtr::new(0, image_base + entry_rva) );
+	}
+
+	// - Exports (by name, or by ordinal if unnamed)
+	if let Some((exp_rva, exp_size)) = try!(directory(DIR_EXPORT))
+	{
+		let ord_base = try!(image.uint(exp_rva + 16, 4));
+		let nfuncs = try!(image.uint(exp_rva + 20, 4));
+		let nnames = try!(image.uint(exp_rva + 24, 4));
+		let funcs = try!(image.uint(exp_rva + 28, 4));
+		let names = try!(image.uint(exp_rva + 32, 4));
+		let ordinals = try!(image.uint(exp_rva + 36, 4));
+		let mut func_names = vec![None; nfuncs as usize];
+		for i in 0 .. nnames
+		{
+			let idx = try!(image.uint(ordinals + i * 2, 2));
+			let name = try!(image.string( try!(image.uint(names + i * 4, 4)) ));
+			match func_names.get_mut(idx as usize)
+			{
+			Some(slot) => if slot.is_none() { *slot = Some(name) },
+			None => warn!("Export {} has an invalid ordinal index {}", name, idx),
+			}
+		}
+		for (i, name) in func_names.into_iter().enumerate()
+		{
+			let rva = try!(image.uint(funcs + i as u64 * 4, 4));
+			// - Skip unused slots, forwarders (which point to a string in the export directory) and data
+			if rva == 0 || (exp_rva <= rva && rva < exp_rva + exp_size) || !image.is_exec(rva) {
+				continue ;
+			}
+			let name = name.unwrap_or_else(|| format!("ordinal_{}", ord_base + i as u64));
+			trace!("- Export {} = {:#x}", name, image_base + rva);
+			program.methods.push( (CodePtr::new(0, image_base + rva), name) );
+		}
+	}
+
+	// - Imports, each is given a stub address past the end of the image
+	let ord_flag = 1u64 << (word * 8 - 1);
+	let mut next_stub = image_base + image_size;
+	if let Some((imp_rva, _)) = try!(directory(DIR_IMPORT))
+	{
+		for i in 0 ..
+		{
+			let desc = imp_rva + i * 20;
+			let lookup = try!(image.uint(desc, 4));
+			let dll_name = try!(image.uint(desc + 12, 4));
+			let iat = try!(image.uint(desc + 16, 4));
+			if dll_name == 0 && iat == 0 {
+				break ;
+			}
+			let dll = try!(image.string(dll_name));
+			// - Names are read from the lookup table if present (the IAT may be pre-bound)
+			let lookup = if lookup != 0 { lookup } else { iat };
+			for j in 0 ..
+			{
+				let ent = try!(image.uint(lookup + j * word, word));
+				if ent == 0 {
+					break ;
+				}
+				let (name, conv) = if ent & ord_flag != 0 {
+						(format!("{}!#{}", dll, ent & 0xFFFF), import_conv(is_64, ""))
+					}
+					else {
+						let sym = try!(image.string((ent & 0x7FFFFFFF) + 2));
+						(format!("{}!{}", dll, sym), import_conv(is_64, &sym))
+					};
+				try!(image.write_uint(iat + j * word, word, next_stub));
+				trace!("- Import {} = {:#x} (IAT slot {:#x})", name, next_stub, image_base + iat + j * word);
+				program.externals.push( (CodePtr::new(0, next_stub), name, conv) );
+				next_stub += IMPORT_STUB_SIZE;
+			}
+		}
+	}
+	debug!("PE '{}': {} exports, {} imports", path, program.methods.len(), program.externals.len());
+
+	// - Map the image
+	memory.set_endian(Endian::Little);
+	for s in image.sections.into_iter()
+	{
+		let perms = s.perms;
+		if perms.write {
+			memory.add_ram_data(image_base + s.rva, &s.data);
+		}
+		else {
+			memory.add_rom_data(image_base + s.rva, s.data);
+		}
+		try!( memory.set_permissions(image_base + s.rva, perms) );
+	}
+
+	Ok( program )
+}
+
+/// Get the calling convention of an import
+///
+/// 32-bit imports are stdcall, with the size of the arguments given by decorated names (e.g. `_Sleep@4`)
+fn import_conv(is_64: bool, name: &str) -> String
+{
+	if is_64 {
+		return String::from("win64");
+	}
+	match name.rfind('@')
+	{
+	Some(pos) if pos > 0 && name[pos+1 ..].parse::<u64>().is_ok() => format!("stdcall{}", &name[pos ..]),
+	_ => String::from("stdcall"),
+	}
+}
+
+/// Read `len` bytes from the file (zero-padded to `size`)
+fn read_raw(data: &[u8], ofs: u64, len: u64, size: u64) -> Result<Vec<u8>,String>
+{
+	let len = ::std::cmp::min(len, size);
+	if ofs > data.len() as u64 || len > data.len() as u64 - ofs {
+		return Err( format!("PE truncated (reading {:#x}+{:#x}, file is {:#x} bytes)", ofs, len, data.len()) );
+	}
+	let mut rv = data[ofs as usize .. (ofs + len) as usize].to_vec();
+	rv.resize(size as usize, 0);
+	Ok( rv )
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use loader::test_util::{temp_file,put};
+	
+	fn put_str(data: &mut Vec<u8>, ofs: usize, s: &[u8])
+	{
+		for (i, &b) in s.iter().enumerate() {
+			put(data, ofs + i, b as u64, 1);
+		}
+	}
+	
+	/// A PE32 image based at 0x400000, with code at 0x1000 and an import table at 0x2000 (one named import, one by ordinal)
+	fn pe32() -> Vec<u8>
+	{
+		let mut d = Vec::new();
+		put_str(&mut d, 0, b"MZ");
+		put(&mut d, 0x3C, 0x40, 4);
+		put_str(&mut d, 0x40, b"PE\0\0");
+		put(&mut d, 0x44, IMAGE_FILE_MACHINE_I386 as u64, 2);
+		put(&mut d, 0x46, 2, 2);	// Sections
+		put(&mut d, 0x54, 0xE0, 2);	// Optional header size
+		put(&mut d, 0x58, 0x10b, 2);
+		put(&mut d, 0x58 + 16, 0x1000, 4);	// Entrypoint
+		put(&mut d, 0x58 + 28, 0x400000, 4);	// Image base
+		put(&mut d, 0x58 + 56, 0x3000, 4);	// Image size
+		put(&mut d, 0x58 + 60, 0x200, 4);	// Headers size
+		put(&mut d, 0x58 + 92, 16, 4);
+		put(&mut d, 0x58 + 96 + 8, 0x2000, 4);	// Import directory
+		put(&mut d, 0x58 + 96 + 12, 40, 4);
+		// - Section headers (.text and .idata)
+		for &(sh, name, rva, ofs, raw_size, flags) in [
+			(0x138, b".text\0\0\0", 0x1000, 0x200, 0x10, IMAGE_SCN_MEM_EXECUTE|IMAGE_SCN_MEM_READ),
+			(0x160, b".idata\0\0", 0x2000, 0x210, 0x100, IMAGE_SCN_MEM_READ|IMAGE_SCN_MEM_WRITE),
+			].iter()
+		{
+			put_str(&mut d, sh, name);
+			put(&mut d, sh + 8, 0x100, 4);
+			put(&mut d, sh + 12, rva, 4);
+			put(&mut d, sh + 16, raw_size, 4);
+			put(&mut d, sh + 20, ofs, 4);
+			put(&mut d, sh + 36, flags as u64, 4);
+		}
+		put(&mut d, 0x200, 0xC3, 1);	// RET
+		put(&mut d, 0x20F, 0xCC, 1);	// INT3, last byte of .text in the file
+		// - Import descriptor (lookup table, DLL name, IAT) and its tables
+		let idata = |rva: usize| rva - 0x2000 + 0x210;
+		put(&mut d, idata(0x2000), 0x2040, 4);
+		put(&mut d, idata(0x200C), 0x2080, 4);
+		put(&mut d, idata(0x2010), 0x2050, 4);
+		put(&mut d, idata(0x2040), 0x2060, 4);
+		put(&mut d, idata(0x2044), 0x8000_0007, 4);
+		put_str(&mut d, idata(0x2062), b"_Sleep@4\0");
+		put_str(&mut d, idata(0x2080), b"KERNEL32.dll\0");
+		d.resize(0x310, 0);
+		d
+	}
+	
+	#[test]
+	fn load_imports()
+	{
+		let mut mem = MemoryState::new();
+		let program = load(&mut mem, &temp_file("pe_good", &pe32())).unwrap();
+		assert_eq!( program.entrypoints, vec![CodePtr::new(0, 0x401000)] );
+		assert_eq!( program.externals, vec![
+			(CodePtr::new(0, 0x403000), String::from("KERNEL32.dll!_Sleep@4"), String::from("stdcall@4")),
+			(CodePtr::new(0, 0x403010), String::from("KERNEL32.dll!#7"), String::from("stdcall")),
+			] );
+		// - IAT slots hold the import addresses
+		assert_eq!( mem.read_u32(0x402050).and_then(|v| v.val_known()), Some(0x403000) );
+		assert_eq!( mem.read_u32(0x402054).and_then(|v| v.val_known()), Some(0x403010) );
+		assert_eq!( mem.read_u8(0x401000).and_then(|v| v.val_known()), Some(0xC3) );
+		assert_eq!( mem.permissions(0x401000).map(|p| p.to_string()), Some(String::from("r-x")) );
+		assert_eq!( mem.permissions(0x402000).map(|p| p.to_string()), Some(String::from("rw-")) );
+		// - Headers are readable, and the end of sections is zero-filled
+		assert_eq!( mem.read_u16(0x400000).and_then(|v| v.val_known()), Some(0x5A4D) );
+		assert_eq!( mem.read_u8(0x40100F).and_then(|v| v.val_known()), Some(0xCC) );
+		assert_eq!( mem.read_u8(0x401010).and_then(|v| v.val_known()), Some(0) );
+	}
+	
+	#[test]
+	fn bad_headers()
+	{
+		assert!( load(&mut MemoryState::new(), &temp_file("pe_magic", &[0; 0x40])).is_err() );
+		let mut d = pe32();
+		put(&mut d, 0x40, 0, 4);
+		assert!( load(&mut MemoryState::new(), &temp_file("pe_signature", &d)).is_err() );
+		let mut d = pe32();
+		put(&mut d, 0x44, 0x1c0, 2);
+		assert!( load(&mut MemoryState::new(), &temp_file("pe_machine", &d)).is_err() );
+		let mut d = pe32();
+		d.truncate(0x280);
+		assert!( load(&mut MemoryState::new(), &temp_file("pe_truncated", &d)).is_err() );
+	}
+	
+	#[test]
+	fn import_conventions()
+	{
+		assert_eq!( import_conv(false, "_Sleep@4"), "stdcall@4" );
+		assert_eq!( import_conv(false, "GetTickCount"), "stdcall" );
+		assert_eq!( import_conv(false, "@4"), "stdcall" );
+		assert_eq!( import_conv(false, "odd@name"), "stdcall" );
+		assert_eq!( import_conv(true, "_Sleep@4"), "win64" );
+	}
+}
+
+// vim: ft=rust
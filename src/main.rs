@@ -30,10 +30,13 @@ macro_rules! impl_from {
 
 mod value;	// Value type
 mod memory;	// Memory
+mod mmio;	// MMIO register descriptions
+mod compression;	// GBA BIOS compressed data
 mod types;	// Type manager
 mod disasm;	// Disassembler
 //mod analyse;	// Analysis of the disassembled code (to produce more addresses, and get functions)
 mod parse;	// Configuration parsing
+mod loader;	// Executable file loaders
 
 static MAX_LOOPS: usize = 50;	// Maximum number of passes during disassembly+processing
 
@@ -45,13 +48,26 @@ fn main()
 	let mut opts = getopts::Options::new();
 	opts.optopt("m", "memmap", "Set memory map filename", "FILE");
 	opts.optopt("t", "types", "Set type list filename", "FILE");
+	opts.optopt("e", "elf", "Load an ELF executable", "FILE");
+	opts.optopt("p", "pe", "Load a PE (Windows) executable", "FILE");
+	opts.optopt("n", "nds", "Load a Nintendo DS ROM", "FILE");
+	opts.optflag("z", "decompress", "Scan ROM for GBA BIOS compressed data");
+	opts.optflag("", "ignore-header", "Load a GBA ROM even if its header logo or checksum is wrong");
 	let args = match opts.parse(&str_args[1..])
 		{
 		Ok(v) => v,
 		Err(reason) => panic!("getopts() failed: {}", reason),
 		};
 	let typesfile = args.opt_str("types").unwrap_or( String::from("types.txt") );
-	let mapfile = args.opt_str("memmap").unwrap_or( String::from("memorymap.txt") );
+	// - Executable to load, and the loader for its format
+	let exefile = match (args.opt_str("elf"), args.opt_str("pe"), args.opt_str("nds"))
+		{
+		(Some(p), None, None) => Some( (p, ::loader::elf::load as ::loader::LoadFn) ),
+		(None, Some(p), None) => Some( (p, ::loader::pe::load as ::loader::LoadFn) ),
+		(None, None, Some(p)) => Some( (p, ::loader::nds::load as ::loader::LoadFn) ),
+		(None, None, None) => None,
+		_ => panic!("ERROR: Only one executable can be loaded"),
+		};
 	// - Open input files
 	let mut infiles: HashMap<_, _> = args.free.iter().map(|p| {
 		let mut s = p.split('=');
@@ -66,6 +82,21 @@ fn main()
 			};
 		(String::from(ident), file)
 		}).collect();
+	// - A GBA ROM given as `rom=<path>` sets up memory itself if there's no memory map (given or in the current directory)
+	let gba_rom = if exefile.is_none() && !args.opt_present("memmap") && !::std::path::Path::new("memorymap.txt").exists() {
+			args.free.iter().filter(|a| a.starts_with("rom=")).map(|a| &a[4..]).next()
+		}
+		else {
+			None
+		};
+	let is_gba = gba_rom.is_some();
+	// - A memory map is only required if no executable is given
+	let mapfile = match args.opt_str("memmap")
+		{
+		Some(p) => Some(p),
+		None if exefile.is_none() && !is_gba => Some( String::from("memorymap.txt") ),
+		None => None,
+		};
 	
 	// ------------------------------------------------------------
 	// Load program state
@@ -76,28 +107,77 @@ fn main()
 		::parse::parse_typemap(&mut tmp, &*typesfile).unwrap();
 		tmp
 		};
-	// - Load memory map (includes overrides)
 	let mut memory = memory::MemoryState::new();
-	let (entrypoints,) = ::parse::parse_memorymap(
-		&mut memory,
-		&typemap, &mut infiles,
-		&*mapfile
-		).unwrap();
+	// - Load executable
+	let program = if let Some((ref path, load)) = exefile {
+			match load(&mut memory, path)
+			{
+			Ok(p) => p,
+			Err(e) => panic!("ERROR: Unable to load '{}': {}", path, e),
+			}
+		}
+		else if let Some(path) = gba_rom {
+			match ::loader::gba::load(&mut memory, path, args.opt_present("ignore-header"))
+			{
+			Ok(p) => p,
+			Err(e) => panic!("ERROR: Unable to load GBA ROM '{}': {}", path, e),
+			}
+		}
+		else {
+			Default::default()
+		};
+	let mut entrypoints = program.entrypoints;
+	let description = program.description;
+	// - Load memory map (includes overrides)
+	if let Some(ref mapfile) = mapfile
+	{
+		let (map_entrypoints,) = ::parse::parse_memorymap(
+			&mut memory,
+			&typemap, &mut infiles,
+			&*mapfile
+			).unwrap();
+		entrypoints.extend(map_entrypoints);
+	}
 	// - Select CPU
 	// TODO: Obtain CPU type from memory map
-	let cpu = match disasm::cpus::pick("arm")
+	let cpu = match program.cpu.or_else(|| disasm::cpus::pick("arm"))
 		{
 		Some(x) => x,
 		None => panic!("Unknown CPU type"),
 		};
+	// - Use the CPU's byte order, unless the memory map gave one
+	if memory.endian().is_none() {
+		memory.set_endian( cpu.default_endian() );
+	}
+	// - Decompressed data is added as virtual regions
+	if args.opt_present("decompress") {
+		for f in ::compression::scan(&mut memory) {
+			debug!("- {:#x}+{:#x} decompressed from {:#x} ({:#x} bytes)", f.base, f.size, f.source, f.compressed_len);
+		}
+	}
 	// ------------------------------------------------------------
 	// Run disassembler
 	// ------------------------------------------------------------
 	// > Iterate entrypoints, running conversion (and obtaining further addresses to process)
 	let mut disasm = disasm::Disassembled::new(&memory, cpu);
+	for (addr, name) in program.methods.into_iter()
+	{
+		disasm.add_method(addr, &name);
+	}
+	for (addr, name, conv) in program.externals.into_iter()
+	{
+		disasm.add_external(addr, &name, &conv);
+	}
+	for (core, core_entrypoints) in program.cores.into_iter()
+	{
+		debug!("{}: {} entrypoints", core, core_entrypoints.len());
+		for addr in core_entrypoints {
+			disasm.add_core_entrypoint(addr, core);
+		}
+	}
 	for addr in entrypoints.into_iter()
 	{
-		disasm.convert_from(addr);
+		disasm.add_entrypoint(addr);
 	}
 	// > Loop until no change in state happens, or a maximum iteration count is hit
 	let mut pass_count = 0;
@@ -123,6 +203,9 @@ fn main()
 	debug!(" Pass Count = {}", pass_count);
 	debug!(" Instruction Count = {}", disasm.instr_count());
 	
+	if let Some(ref desc) = description {
+		println!("; {}", desc);
+	}
 	let _ = disasm.dump( &mut WriterWrapper(::std::io::stdout()) );
 }
 
@@ -1,36 +1,136 @@
 //
 //
 //
-use value::Value;
+use value::{Value,ValueType};
 use std::cmp::Ordering;
+use std::cell::{Cell,RefCell};
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use bit_set::BitSet;
 use std::io::{Read,Seek};
 
+/// Size of a page in a memory snapshot (the granularity of sharing)
+const PAGE_SIZE: u64 = 256;
+/// Size of a page of RAM (pages are only allocated once written)
+const RAM_PAGE_SIZE: usize = 4096;
+/// Largest range of addresses a store is checked against byte-by-byte (larger ranges forget every written byte)
+const CLOBBER_RANGE_MAX: u64 = 0x1000;
+/// Lowest address used for virtual regions (above the 32-bit address space)
+const VIRTUAL_BASE: u64 = 0x1_0000_0000;
+
+/// Index of a bank within a banked region
+pub type BankId = u16;
+
 /// Memory region type
 enum RegionType
 {
 	ROM(Vec<u8>),
-	RAM(Vec<Value<u8>>),
-	MMIO(String),
+	/// Writable memory
+	RAM(RamRegion),
+	MMIO(MmioRegion),
+	/// Bank-switched ROM (e.g. cartridge mappers)
+	Banked(BankedRegion),
+	/// Alias of another region (target address, length of the aliased window - wraps at this)
+	Mirror(u64, u64),
+	/// Read-only data derived from other memory (e.g. decompressed), with the address of its source
+	Virtual(u64, Vec<u8>),
+}
+
+/// Sparse RAM contents, bytes that have never been written are unknown
+struct RamRegion
+{
+	pages: RefCell<BTreeMap<usize, RamPage>>,
+}
+/// A page of RAM that has been written
+struct RamPage
+{
+	/// Bytes holding a single known value (stored in `values`)
+	known: BitSet,
+	values: Vec<u8>,
+	/// Written bytes that don't have a single known value
+	other: BTreeMap<usize, Value<u8>>,
+}
+
+/// Bank-switched ROM, the bank mapped is part of the analysis state (see `BankSelection`)
+struct BankedRegion
+{
+	banks: Vec<Vec<u8>>,
+	/// Writes to this range (base, size) select the mapped bank
+	select: Option<(u64,u64)>,
+	/// Bank mapped until one is selected (None if not known)
+	initial: Option<BankId>,
+	/// Values written to the region, shared by every bank (None if the region is read-only)
+	ram: Option<RamRegion>,
+}
+
+/// Memory-mapped hardware registers
+struct MmioRegion
+{
+	class: String,
+	device: Option<::mmio::Device>,
+	big_endian: bool,
+	/// Register contents (reset values, merged with everything written since)
+	data: RefCell<Vec<Value<u8>>>,
 }
 
 struct Region
 {
 	start: u64,
 	size: usize,
+	perms: Permissions,
 	data: RegionType
 }
 
+/// Accesses permitted to a region
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub struct Permissions
+{
+	pub read: bool,
+	pub write: bool,
+	pub exec: bool,
+}
+
+/// Byte order of multi-byte values in memory
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Endian
+{
+	Little,
+	Big,
+}
+
 pub struct MemoryState
 {
-	endian_big: bool,
+	/// Byte order (None until set by the memory map or the CPU, treated as little endian)
+	endian: Option<Endian>,
 	regions: Vec<Region>,
+	/// Incremented whenever the contents of RAM change
+	generation: Cell<usize>,
+}
+
+/// Persistent copy-on-write view of memory, holding changes without modifying the underlying `MemoryState`
+///
+/// Cloning is cheap, pages are only copied when written while shared with another snapshot.
+/// Addresses within mirrors are stored at the address they alias.
+#[derive(Clone,Default)]
+pub struct Snapshot
+{
+	/// Pages that have been written, bytes that haven't been written are None
+	pages: Rc<BTreeMap<u64, Rc<Vec<Option<Value<u8>>>>>>,
+}
+
+/// Banks selected in each banked region (the region's initial bank is mapped until another is selected)
+#[derive(Clone,Default,PartialEq,Debug)]
+pub struct BankSelection
+{
+	/// Region base -> selected bank (None if not known)
+	selected: BTreeMap<u64, Option<BankId>>,
 }
 
 pub trait MemoryStateAccess:
 	::value::ValueType
 {
 	fn read(&MemoryState, addr: u64) -> Option<Value<Self>>;
-	fn write(&mut MemoryState, addr: u64, val: Value<Self>);
+	fn write(&MemoryState, addr: u64, val: Value<Self>);
 }
 
 impl Region
@@ -38,9 +138,48 @@ impl Region
 	pub fn read_u8(&self, ofs: usize) -> Value<u8> {
 		match self.data
 		{
-		RegionType::ROM(ref data) => Value::known(data[ofs % self.size]),	// ROMs wrap
-		RegionType::RAM(ref data) => data[ofs].clone(),
-		RegionType::MMIO(_) => Value::unknown(),
+		RegionType::ROM(ref data) => Value::known(data[ofs % data.len()]),	// ROMs wrap
+		RegionType::RAM(ref r) => r.read_u8(ofs).unwrap_or(Value::unknown()),
+		RegionType::MMIO(ref m) => m.filter_read(ofs, m.data.borrow()[ofs].clone()),
+		RegionType::Banked(ref b) => match b.initial
+			{
+			Some(bank) => b.read_u8(bank, ofs),
+			None => Value::unknown(),
+			},
+		RegionType::Virtual(_, ref data) => Value::known(data[ofs]),
+		RegionType::Mirror(..) => panic!("Region::read_u8 on a mirror (should be resolved by MemoryState)"),
+		}
+	}
+	/// Merge a stored byte into the region, returns true if the contents changed
+	///
+	/// RAM holds every value ever stored to it (memory isn't tracked per execution path), so
+	/// bytes without known initial contents stay unknown.
+	pub fn write_u8(&self, ofs: usize, val: Value<u8>) -> bool {
+		match self.data
+		{
+		RegionType::Banked(BankedRegion { ram: Some(ref r), .. }) => r.record_u8(ofs, val),
+		RegionType::ROM(_) | RegionType::Banked(_) | RegionType::Virtual(..) => {
+			warn!("Write to ROM at {:#x} ignored ({:?})", self.start + ofs as u64, val);
+			false
+			},
+		RegionType::RAM(ref r) => r.write_u8(ofs, val),
+		RegionType::MMIO(ref m) => match m.byte_masks(ofs)
+			{
+			Some( (ro, _) ) => {
+				// Read-only bits keep their value
+				let mut data = m.data.borrow_mut();
+				let stored = (val & Value::known(!ro)) | (data[ofs].clone() & Value::known(ro));
+				let new = data[ofs].union(&stored);
+				let changed = !data[ofs].is_identical(&new);
+				data[ofs] = new;
+				changed
+				},
+			None => {
+				trace!("Write to MMIO ({}) at {:#x} ignored, no register", m.class, self.start + ofs as u64);
+				false
+				},
+			},
+		RegionType::Mirror(..) => panic!("Region::write_u8 on a mirror (should be resolved by MemoryState)"),
 		}
 	}
 	pub fn read_u16_le(&self, ofs: usize) -> Value<u16> {
@@ -71,15 +210,186 @@ impl Region
 	}
 }
 
+impl BankedRegion
+{
+	/// Read a byte from a bank, merged with anything written to the region
+	fn read_u8(&self, bank: BankId, ofs: usize) -> Value<u8>
+	{
+		let val = Value::known(self.banks[bank as usize][ofs]);
+		match self.ram.as_ref().and_then(|r| r.read_u8(ofs))
+		{
+		Some(written) => val.union(&written),
+		None => val,
+		}
+	}
+}
+
+impl RamRegion
+{
+	fn new() -> RamRegion {
+		RamRegion {
+			pages: RefCell::new(BTreeMap::new()),
+		}
+	}
+	/// Read a byte, None if it has never been written
+	fn read_u8(&self, ofs: usize) -> Option<Value<u8>>
+	{
+		match self.pages.borrow().get(&(ofs / RAM_PAGE_SIZE))
+		{
+		Some(p) => p.get(ofs % RAM_PAGE_SIZE),
+		None => None,
+		}
+	}
+	/// Merge a stored byte with the existing contents, returns true if the contents changed
+	///
+	/// Bytes that have never been written stay unknown (their contents before the store aren't known)
+	fn write_u8(&self, ofs: usize, val: Value<u8>) -> bool
+	{
+		if self.read_u8(ofs).is_none() {
+			return false;
+		}
+		self.record_u8(ofs, val)
+	}
+	/// Record a stored byte, merged with earlier stores (bytes that have never been written take the stored value)
+	///
+	/// Used for initial contents, and where reads merge the stores with contents held elsewhere (e.g. overlays)
+	fn record_u8(&self, ofs: usize, val: Value<u8>) -> bool
+	{
+		let mut pages = self.pages.borrow_mut();
+		let page = pages.entry(ofs / RAM_PAGE_SIZE).or_insert_with(RamPage::new);
+		let idx = ofs % RAM_PAGE_SIZE;
+		let (new, changed) = match page.get(idx)
+			{
+			Some(old) => {
+				let new = old.union(&val);
+				let changed = !old.is_identical(&new);
+				(new, changed)
+				},
+			None => (val, true),
+			};
+		if changed {
+			page.set(idx, new);
+		}
+		changed
+	}
+}
+
+impl RamPage
+{
+	fn new() -> RamPage {
+		RamPage {
+			known: BitSet::with_capacity(RAM_PAGE_SIZE),
+			values: vec![0; RAM_PAGE_SIZE],
+			other: BTreeMap::new(),
+		}
+	}
+	fn get(&self, idx: usize) -> Option<Value<u8>> {
+		if self.known.contains(idx) {
+			Some( Value::known(self.values[idx]) )
+		}
+		else {
+			self.other.get(&idx).cloned()
+		}
+	}
+	fn set(&mut self, idx: usize, val: Value<u8>) {
+		match val
+		{
+		Value::Known(v) => {
+			self.known.insert(idx);
+			self.values[idx] = v;
+			self.other.remove(&idx);
+			},
+		v @ _ => {
+			self.known.remove(idx);
+			self.other.insert(idx, v);
+			},
+		}
+	}
+}
+
+impl MmioRegion
+{
+	/// Get the (read-only, write-only) bit masks for a byte, None if it isn't part of a described register
+	fn byte_masks(&self, ofs: usize) -> Option<(u8,u8)>
+	{
+		let dev = match self.device
+			{
+			Some(ref d) => d,
+			None => return None,
+			};
+		dev.register_at(ofs as u64).map(|(r, idx)| {
+			let shift = if self.big_endian { (r.size as usize - 1 - idx) * 8 } else { idx * 8 };
+			((r.read_only >> shift) as u8, (r.write_only >> shift) as u8)
+			})
+	}
+	/// Set registers to their reset values, laid out in the given byte order
+	fn reset(&mut self, size: usize, big_endian: bool)
+	{
+		let mut data: Vec<_> = ::std::iter::repeat(Value::unknown()).take(size).collect();
+		if let Some(ref dev) = self.device
+		{
+			for r in dev.registers()
+			{
+				if let Some(reset) = r.reset
+				{
+					for i in 0 .. r.size as usize
+					{
+						let ofs = r.offset as usize + i;
+						let shift = if big_endian { (r.size as usize - 1 - i) * 8 } else { i * 8 };
+						if ofs < size {
+							data[ofs] = Value::known( (reset >> shift) as u8 );
+						}
+					}
+				}
+			}
+		}
+		self.big_endian = big_endian;
+		self.data = RefCell::new(data);
+	}
+	/// Apply hardware read semantics to a byte (read-only bits are set by hardware, write-only bits read as zero)
+	fn filter_read(&self, ofs: usize, val: Value<u8>) -> Value<u8>
+	{
+		match self.byte_masks(ofs)
+		{
+		Some( (ro, wo) ) => (val & Value::known(!ro & !wo)) | Value::masked(0, !ro),
+		None => Value::unknown(),
+		}
+	}
+}
+
 impl MemoryState
 {
 	pub fn new() -> MemoryState {
 		MemoryState {
-			endian_big: false,
+			endian: None,
 			regions: Vec::new(),
+			generation: Cell::new(0),
 		}
 	}
 	
+	/// Get the byte order (None if it hasn't been set)
+	pub fn endian(&self) -> Option<Endian> {
+		self.endian
+	}
+	/// Set the byte order
+	///
+	/// NOTE: Hardware registers are returned to their reset values, so this should be set before analysis
+	pub fn set_endian(&mut self, endian: Endian)
+	{
+		debug!("Byte order {:?}", endian);
+		self.endian = Some(endian);
+		let big = self.is_big_endian();
+		for r in self.regions.iter_mut()
+		{
+			if let RegionType::MMIO(ref mut m) = r.data {
+				m.reset(r.size, big);
+			}
+		}
+	}
+	fn is_big_endian(&self) -> bool {
+		self.endian == Some(Endian::Big)
+	}
+	
 	fn add_region(&mut self, base: u64, size: usize, data: RegionType)
 	{
 		let pos = match self.regions.binary_search_by(|r| r.start.cmp(&base))
@@ -95,9 +405,20 @@ impl MemoryState
 				idx
 				}
 			};
+		let perms = match data
+			{
+			RegionType::Banked(BankedRegion { ram: Some(_), .. }) => Permissions { read: true, write: true, exec: true },
+			RegionType::ROM(_) | RegionType::Banked(_) => Permissions { read: true, write: false, exec: true },
+			RegionType::RAM(_) => Permissions { read: true, write: true, exec: true },
+			RegionType::MMIO(_) => Permissions { read: true, write: true, exec: false },
+			RegionType::Virtual(..) => Permissions { read: true, write: false, exec: false },
+			// - Mirrors use the permissions of the region they alias
+			RegionType::Mirror(..) => Permissions { read: true, write: true, exec: true },
+			};
 		self.regions.insert(pos, Region {
 			start: base,
 			size: size,
+			perms: perms,
 			data: data
 			});
 	}
@@ -121,20 +442,149 @@ impl MemoryState
 		self.add_region(base, size, RegionType::ROM(data));
 		debug!("Add ROM {:#x}+{:#x}", base, size);
 	}
+	/// Add fixed memory with the provided contents (e.g. a segment of an executable)
+	pub fn add_rom_data(&mut self, base: u64, data: Vec<u8>)
+	{
+		let size = data.len();
+		self.add_region(base, size, RegionType::ROM(data));
+		debug!("Add ROM {:#x}+{:#x} (from data)", base, size);
+	}
+	/// Load bank-switched ROM from a file (split into banks of `size` bytes)
+	///
+	/// Writes to the `select` range (base, size) switch the mapped bank
+	pub fn add_banked(&mut self, base: u64, size: usize, file: &mut ::std::fs::File, select: Option<(u64,u64)>)
+	{
+		let filesize = file.metadata().unwrap().len();
+		if filesize == 0 || filesize % size as u64 != 0 {
+			panic!("Unable to map banked ROM at {:#x}, provided file isn't a multiple of the bank size", base);
+		}
+		file.seek( ::std::io::SeekFrom::Start(0) ).unwrap();
+		let mut data = Vec::new();
+		file.read_to_end(&mut data).unwrap();
+		let banks: Vec<_> = data.chunks(size).map(|c| c.to_vec()).collect();
+		self.add_banked_data(base, banks, select);
+	}
+	/// Add bank-switched ROM with the provided contents for each bank (bank 0 is mapped until another is selected)
+	pub fn add_banked_data(&mut self, base: u64, banks: Vec<Vec<u8>>, select: Option<(u64,u64)>)
+	{
+		debug!("Add banked ROM {:#x} ({} banks, select={:?})", base, banks.len(), select);
+		self.add_banked_region(base, BankedRegion {
+			banks: banks,
+			select: select,
+			initial: Some(0),
+			ram: None,
+			});
+	}
+	/// Add overlays sharing an address (one bank per overlay)
+	///
+	/// Overlays are writable, and which is loaded isn't known until a bank is selected (or code in one is run)
+	pub fn add_overlay_data(&mut self, base: u64, banks: Vec<Vec<u8>>)
+	{
+		debug!("Add overlays {:#x} ({} banks)", base, banks.len());
+		self.add_banked_region(base, BankedRegion {
+			banks: banks,
+			select: None,
+			initial: None,
+			ram: Some(RamRegion::new()),
+			});
+	}
+	fn add_banked_region(&mut self, base: u64, region: BankedRegion)
+	{
+		let size = region.banks[0].len();
+		if region.banks.iter().any(|b| b.len() != size) {
+			panic!("Unable to map banked memory at {:#x}, banks differ in size", base);
+		}
+		self.add_region(base, size, RegionType::Banked(region));
+	}
 	pub fn add_ram(&mut self, base: u64, size: usize)
 	{
-		self.add_region(base, size, RegionType::RAM(::std::iter::repeat(Value::unknown()).take(size).collect()));
+		self.add_region(base, size, RegionType::RAM(RamRegion::new()));
 		debug!("Add RAM {:#x}+{:#x}", base, size);
 	}
-	pub fn add_mmio(&mut self, base: u64, size: usize, class: &str)
+	/// Add RAM with known initial contents (e.g. initialised data, or zeroed .bss)
+	pub fn add_ram_data(&mut self, base: u64, data: &[u8])
+	{
+		let ram = RamRegion::new();
+		for (i,&b) in data.iter().enumerate() {
+			ram.record_u8(i, Value::known(b));
+		}
+		self.add_region(base, data.len(), RegionType::RAM(ram));
+		debug!("Add RAM {:#x}+{:#x} (initialised)", base, data.len());
+	}
+	pub fn add_mmio(&mut self, base: u64, size: usize, class: &str, device: Option<::mmio::Device>)
 	{
-		self.add_region(base, size, RegionType::MMIO(String::from(class)));
+		if let Some(ref dev) = device {
+			debug!("- {} registers from '{}'", dev.registers().len(), dev.name);
+		}
+		let mut region = MmioRegion {
+			class: String::from(class),
+			device: device,
+			big_endian: false,
+			data: RefCell::new(Vec::new()),
+			};
+		region.reset(size, self.is_big_endian());
+		self.add_region(base, size, RegionType::MMIO(region));
 		debug!("Add MMIO {:#x}+{:#x} \"{}\"", base, size, class);
 	}
+	/// Map a window that aliases an existing region (wrapping if larger than the region)
+	pub fn add_mirror(&mut self, base: u64, size: usize, target: u64)
+	{
+		let span = match self.get_region_raw(target)
+			{
+			Some( (&Region { data: RegionType::Mirror(..), .. }, _) ) => panic!("Unable to map mirror at {:#x}, target {:#x} is itself a mirror", base, target),
+			Some( (r, ofs) ) => (r.size - ofs) as u64,
+			None => panic!("Unable to map mirror at {:#x}, target {:#x} isn't mapped", base, target),
+			};
+		self.add_region(base, size, RegionType::Mirror(target, span));
+		debug!("Add mirror {:#x}+{:#x} of {:#x}", base, size, target);
+	}
+	/// Add a read-only virtual region holding data derived from `source` (e.g. decompressed from it)
+	///
+	/// Virtual regions are placed above all other regions, returns the address used
+	pub fn add_virtual(&mut self, source: u64, data: Vec<u8>) -> u64
+	{
+		let end = self.regions.last().map(|r| r.start + r.size as u64).unwrap_or(0);
+		let base = (::std::cmp::max(end, VIRTUAL_BASE) + 0xFFF) & !0xFFF;
+		let size = data.len();
+		self.add_region(base, size, RegionType::Virtual(source, data));
+		debug!("Add virtual {:#x}+{:#x} from {:#x}", base, size, source);
+		base
+	}
 	
+	/// Get the contents of every ROM region (start address, data)
+	///
+	/// NOTE: Data is shorter than the region if the ROM repeats
+	pub fn rom_regions(&self) -> Vec<(u64, &[u8])> {
+		self.regions.iter().filter_map(|r| match r.data
+			{
+			RegionType::ROM(ref data) => Some( (r.start, &data[..]) ),
+			_ => None,
+			}).collect()
+	}
+	/// Get the source of the virtual region containing an address
+	pub fn virtual_source(&self, addr: u64) -> Option<u64> {
+		match self.get_region(addr)
+		{
+		Some( (&Region { data: RegionType::Virtual(source, _), .. }, _) ) => Some(source),
+		_ => None,
+		}
+	}
 	
-	/// Get the region corresponding to a given address
+	
+	/// Get the address that an address aliases (the address itself if it isn't within a mirror)
+	pub fn canonicalise(&self, addr: u64) -> u64 {
+		match self.get_region_raw(addr)
+		{
+		Some( (&Region { data: RegionType::Mirror(target, span), .. }, ofs) ) => target + ofs as u64 % span,
+		_ => addr,
+		}
+	}
+	/// Get the region corresponding to a given address (resolving mirrors)
 	fn get_region(&self, addr: u64) -> Option<(&Region,usize)> {
+		self.get_region_raw( self.canonicalise(addr) )
+	}
+	/// (internal) Get the region containing an address, without resolving mirrors
+	fn get_region_raw(&self, addr: u64) -> Option<(&Region,usize)> {
 		match self.regions.binary_search_by(|r| r.cmp_inner(addr))
 		{
 		Ok(idx) => {
@@ -154,37 +604,442 @@ impl MemoryState
 	pub fn read_u8(&self, addr: u64) -> Option<Value<u8>> {
 		self.get_region(addr).map( |(a,ofs)| a.read_u8(ofs) )
 	}
-	/// Read two bytes (from the same region)
 	pub fn read_u16(&self, addr: u64) -> Option<Value<u16>> {
-		self.get_region(addr).map( |(a,ofs)| if self.endian_big { a.read_u16_be(ofs) } else { a.read_u16_le(ofs) } )
+		match self.get_region_span(addr, 2)
+		{
+		Some( (a,ofs) ) => Some( if self.is_big_endian() { a.read_u16_be(ofs) } else { a.read_u16_le(ofs) } ),
+		None => self.read_stitched(addr),
+		}
 	}
-	/// Read four bytes (from the same region)
 	pub fn read_u32(&self, addr: u64) -> Option<Value<u32>> {
-		self.get_region(addr).map( |(a,ofs)| if self.endian_big { a.read_u32_be(ofs) } else { a.read_u32_le(ofs) } )
+		match self.get_region_span(addr, 4)
+		{
+		Some( (a,ofs) ) => Some( if self.is_big_endian() { a.read_u32_be(ofs) } else { a.read_u32_le(ofs) } ),
+		None => self.read_stitched(addr),
+		}
 	}
-	/// Read eight bytes (from the same region)
 	pub fn read_u64(&self, addr: u64) -> Option<Value<u64>> {
-		self.get_region(addr).map(
-			|(a,ofs)|
-				if self.endian_big {
-					Value::concat( a.read_u32_be(ofs+4), a.read_u32_be(ofs+0) )
-				} else {
-					Value::concat( a.read_u32_le(ofs+0), a.read_u32_le(ofs+4) )
-				}
-			)
+		match self.get_region_span(addr, 8)
+		{
+		Some( (a,ofs) ) => Some(
+			if self.is_big_endian() {
+				Value::concat( a.read_u32_be(ofs+4), a.read_u32_be(ofs+0) )
+			} else {
+				Value::concat( a.read_u32_le(ofs+0), a.read_u32_le(ofs+4) )
+			}
+			),
+		None => self.read_stitched(addr),
+		}
+	}
+	/// (internal) Get the region containing all `size` bytes starting at `addr`
+	fn get_region_span(&self, addr: u64, size: usize) -> Option<(&Region,usize)> {
+		match self.get_region(addr)
+		{
+		Some( (r, ofs) ) if ofs + size <= r.size => Some( (r, ofs) ),
+		_ => None,
+		}
+	}
+	/// (internal) Read a value byte-by-byte, for reads that cross a region boundary
+	///
+	/// Unmapped bytes are unknown, returns None if none of the bytes are mapped.
+	fn read_stitched<T: ValueType>(&self, addr: u64) -> Option<Value<T>>
+	{
+		let bytes: Vec<_> = (0 .. ::std::mem::size_of::<T>() as u64).map(|i| self.read_u8(addr.wrapping_add(i))).collect();
+		if bytes.iter().all(|b| b.is_none()) {
+			return None;
+		}
+		if bytes.iter().any(|b| b.is_none()) {
+			warn!("Read of {} bytes from {:#x} is partially unmapped", bytes.len(), addr);
+		}
+		let bytes: Vec<_> = bytes.into_iter().map(|b| b.unwrap_or(Value::unknown())).collect();
+		Some( self.join_bytes(&bytes) )
 	}
 	
+	/// Get the accesses permitted to an address (None if unmapped)
+	pub fn permissions(&self, addr: u64) -> Option<Permissions> {
+		self.get_region(addr).map( |(r,_)| r.perms )
+	}
+	/// Set the accesses permitted to the region containing an address
+	pub fn set_permissions(&mut self, addr: u64, perms: Permissions) -> Result<(),String>
+	{
+		let addr = self.canonicalise(addr);
+		match self.regions.binary_search_by(|r| r.cmp_inner(addr))
+		{
+		Ok(idx) => {
+			debug!("Region {:#x}+{:#x} permissions {}", self.regions[idx].start, self.regions[idx].size, perms);
+			self.regions[idx].perms = perms;
+			Ok( () )
+			},
+		Err(_) => Err( format!("No region at {:#x} to set permissions on", addr) ),
+		}
+	}
+	/// Check if an address is within a banked region
+	pub fn is_banked(&self, addr: u64) -> bool {
+		match self.get_region(addr)
+		{
+		Some( (&Region { data: RegionType::Banked(_), .. }, _) ) => true,
+		_ => false,
+		}
+	}
+	/// Get the bank that code at an address is in (0 outside banked regions, None if the selected bank isn't known)
+	pub fn bank_at(&self, sel: &BankSelection, addr: u64) -> Option<BankId> {
+		match self.get_region(addr)
+		{
+		Some( (&Region { data: RegionType::Banked(ref b), start, .. }, _) ) => sel.get(start, b.initial),
+		_ => Some(0),
+		}
+	}
+	/// Get the base of the banked region containing an address
+	pub fn banked_region(&self, addr: u64) -> Option<u64> {
+		match self.get_region(addr)
+		{
+		Some( (&Region { data: RegionType::Banked(_), start, .. }, _) ) => Some(start),
+		_ => None,
+		}
+	}
+	/// Check if a write to an address selects a bank, returns the region's base and number of banks
+	pub fn bank_select(&self, addr: u64) -> Option<(u64, usize)> {
+		self.regions.iter()
+			.filter_map(|r| match r.data
+				{
+				RegionType::Banked(ref b) => match b.select
+					{
+					Some( (base, size) ) if base <= addr && addr - base < size => Some( (r.start, b.banks.len()) ),
+					_ => None,
+					},
+				_ => None,
+				})
+			.next()
+	}
+	/// Read a value with `bank` mapped in the banked region containing `addr` (other regions are read normally)
+	pub fn read_in_bank<T: ValueType+MemoryStateAccess>(&self, bank: BankId, addr: u64) -> Option<Value<T>>
+	{
+		let size = ::std::mem::size_of::<T>();
+		match self.get_region_span(addr, size)
+		{
+		Some( (&Region { data: RegionType::Banked(ref b), .. }, ofs) ) => match b.banks.get(bank as usize)
+			{
+			Some(_) => {
+				let bytes: Vec<_> = (ofs .. ofs + size).map(|i| b.read_u8(bank, i)).collect();
+				Some( self.join_bytes(&bytes) )
+				},
+			None => {
+				warn!("Bank {} doesn't exist (reading {:#x})", bank, addr);
+				Some( Value::unknown() )
+				},
+			},
+		_ => MemoryStateAccess::read(self, addr),
+		}
+	}
+	
+	/// Apply hardware read semantics to a byte that was written on the current path
+	pub fn filter_read(&self, addr: u64, val: Value<u8>) -> Value<u8>
+	{
+		match self.get_region(addr)
+		{
+		Some( (&Region { data: RegionType::MMIO(ref m), .. }, ofs) ) => m.filter_read(ofs, val),
+		_ => val,
+		}
+	}
+	/// Get the hardware register at an address (and the byte index within it)
+	pub fn mmio_register(&self, addr: u64) -> Option<(&::mmio::Register, usize)>
+	{
+		match self.get_region(addr)
+		{
+		Some( (&Region { data: RegionType::MMIO(ref m), .. }, ofs) ) => match m.device
+			{
+			Some(ref d) => d.register_at(ofs as u64),
+			None => None,
+			},
+		_ => None,
+		}
+	}
+	
+	/// Counter incremented whenever the contents of RAM (or hardware registers) change (allows passes to notice new stores)
+	pub fn generation(&self) -> usize {
+		self.generation.get()
+	}
+	
+	/// Split a value into bytes, in memory order
+	pub fn split_bytes<T: ValueType>(&self, val: &Value<T>) -> Vec<Value<u8>>
+	{
+		let n = ::std::mem::size_of::<T>();
+		(0 .. n).map(|i| {
+			let byte = if self.is_big_endian() { n-1-i } else { i };
+			let (_, v) = val.clone() >> (byte * 8);
+			v.truncate()
+			}).collect()
+	}
+	/// Join bytes (in memory order) into a value
+	pub fn join_bytes<T: ValueType>(&self, bytes: &[Value<u8>]) -> Value<T>
+	{
+		let n = bytes.len();
+		let mut rv = Value::<T>::zero();
+		for (i,b) in bytes.iter().enumerate()
+		{
+			let byte = if self.is_big_endian() { n-1-i } else { i };
+			let (_, v) = b.zero_extend::<T>() << (byte * 8);
+			rv = rv | v;
+		}
+		rv
+	}
+	
+	/// Merge stored bytes into memory
+	///
+	/// NOTE: Takes &self, as stores are folded back while the disassembler holds shared references
+	pub fn write_bytes(&self, addr: u64, bytes: &[Value<u8>])
+	{
+		let mut changed = false;
+		for (i,b) in bytes.iter().enumerate()
+		{
+			let addr = addr + i as u64;
+			match self.get_region(addr)
+			{
+			Some( (r,ofs) ) => { changed |= r.write_u8(ofs, b.clone()); },
+			None => warn!("Writing unmapped memory {:#x}", addr),
+			}
+		}
+		if changed {
+			self.generation.set( self.generation.get() + 1 );
+		}
+	}
+	/// Fold the bytes written to a snapshot back into memory
+	pub fn apply_snapshot(&self, snap: &Snapshot)
+	{
+		for (addr, val) in snap.writes()
+		{
+			self.write_bytes(addr, &[val.clone()]);
+		}
+	}
 	pub fn write_u8(&self, addr: u64, val: Value<u8>) {
-		panic!("TODO: MemoryState.write_u8(addr={:#x},val={:?})", addr, val);
+		self.write_bytes(addr, &[val]);
 	}
 	pub fn write_u16(&self, addr: u64, val: Value<u16>) {
-		panic!("TODO: MemoryState.write_u16(addr={:#x},val={:?})", addr, val);
+		self.write_bytes(addr, &self.split_bytes(&val));
 	}
 	pub fn write_u32(&self, addr: u64, val: Value<u32>) {
-		panic!("TODO: MemoryState.write_u32(addr={:#x},val={:?})", addr, val);
+		self.write_bytes(addr, &self.split_bytes(&val));
 	}
 	pub fn write_u64(&self, addr: u64, val: Value<u64>) {
-		panic!("TODO: MemoryState.write_u64(addr={:#x},val={:?})", addr, val);
+		self.write_bytes(addr, &self.split_bytes(&val));
+	}
+}
+
+impl Snapshot
+{
+	pub fn new() -> Snapshot {
+		Default::default()
+	}
+	
+	/// Check if nothing has been written
+	pub fn is_empty(&self) -> bool {
+		self.pages.is_empty()
+	}
+	/// Get a byte written to this snapshot
+	pub fn get(&self, addr: u64) -> Option<&Value<u8>> {
+		match self.pages.get(&(addr / PAGE_SIZE))
+		{
+		Some(p) => p[(addr % PAGE_SIZE) as usize].as_ref(),
+		None => None,
+		}
+	}
+	/// Check if any of the `size` bytes starting at `addr` have been written
+	pub fn overlaps(&self, mem: &MemoryState, addr: u64, size: u64) -> bool {
+		(addr .. addr + size).any(|a| self.get(mem.canonicalise(a)).is_some())
+	}
+	/// Read a byte, falling back to memory if it hasn't been written
+	pub fn read_u8(&self, mem: &MemoryState, addr: u64) -> Value<u8> {
+		let addr = mem.canonicalise(addr);
+		match self.get(addr)
+		{
+		Some(v) => mem.filter_read(addr, v.clone()),
+		None => mem.read_u8(addr).unwrap_or(Value::unknown()),
+		}
+	}
+	/// Read a value (None if unmapped and not written)
+	pub fn read<T: ValueType+MemoryStateAccess>(&self, mem: &MemoryState, addr: u64) -> Option<Value<T>>
+	{
+		let size = ::std::mem::size_of::<T>() as u64;
+		if self.overlaps(mem, addr, size) {
+			// Combine with memory byte-by-byte
+			let bytes: Vec<_> = (addr .. addr + size).map(|a| self.read_u8(mem, a)).collect();
+			Some( mem.join_bytes(&bytes) )
+		}
+		else {
+			MemoryStateAccess::read(mem, addr)
+		}
+	}
+	
+	/// Store bytes, either replacing the existing contents or merging with them (e.g. if the store might not happen)
+	pub fn write_bytes(&mut self, mem: &MemoryState, addr: u64, bytes: &[Value<u8>], replace: bool)
+	{
+		for (i,b) in bytes.iter().enumerate()
+		{
+			let addr = mem.canonicalise(addr + i as u64);
+			let v = if replace { b.clone() } else { self.read_u8(mem, addr).union(b) };
+			let page = Rc::make_mut(&mut self.pages).entry(addr / PAGE_SIZE).or_insert_with(|| Rc::new(vec![None; PAGE_SIZE as usize]));
+			Rc::make_mut(page)[(addr % PAGE_SIZE) as usize] = Some(v);
+		}
+	}
+	
+	/// Forget the values of written bytes in `start .. end` (or every written byte), for stores to an address that isn't known
+	pub fn clobber(&mut self, mem: &MemoryState, range: Option<(u64,u64)>)
+	{
+		match range
+		{
+		Some( (start, end) ) if end - start <= CLOBBER_RANGE_MAX => {
+			for addr in start .. end
+			{
+				if self.get(mem.canonicalise(addr)).is_some() {
+					self.write_bytes(mem, addr, &[Value::unknown()], true);
+				}
+			}
+			},
+		_ => {
+			for page in Rc::make_mut(&mut self.pages).values_mut()
+			{
+				for b in Rc::make_mut(page).iter_mut().filter(|b| b.is_some())
+				{
+					*b = Some(Value::unknown());
+				}
+			}
+			},
+		}
+	}
+	
+	/// Merge two snapshots (e.g. where two paths join), bytes written on either path are merged with memory
+	///
+	/// `other` is the newer state when widening, each byte is merged using `op`
+	pub fn merge(&self, other: &Snapshot, mem: &MemoryState, op: ::value::MergeOp) -> Snapshot
+	{
+		if Rc::ptr_eq(&self.pages, &other.pages) {
+			return self.clone();
+		}
+		let mut pages = (*self.pages).clone();
+		for (&pgidx, o_page) in other.pages.iter()
+		{
+			let new_page = match self.pages.get(&pgidx)
+				{
+				// - Shared pages don't need merging
+				Some(s_page) if Rc::ptr_eq(s_page, o_page) => continue,
+				Some(s_page) => {
+					let base = pgidx * PAGE_SIZE;
+					let mem_byte = |i: usize| mem.read_u8(base + i as u64).unwrap_or(Value::unknown());
+					s_page.iter().zip(o_page.iter()).enumerate()
+						.map(|(i, (a, b))| match (a, b)
+							{
+							(&None, &None) => None,
+							(&Some(ref a), &Some(ref b)) => Some( op.apply(a, b) ),
+							(&Some(ref a), &None) => Some( op.apply(a, &mem_byte(i)) ),
+							(&None, &Some(ref b)) => Some( op.apply(&mem_byte(i), b) ),
+							})
+						.collect()
+					},
+				None => Self::merge_with_memory(o_page, pgidx, mem, |m, v| op.apply(m, v)),
+				};
+			pages.insert(pgidx, Rc::new(new_page));
+		}
+		// - Pages only written on this path
+		for (&pgidx, s_page) in self.pages.iter()
+		{
+			if ! other.pages.contains_key(&pgidx) {
+				pages.insert(pgidx, Rc::new( Self::merge_with_memory(s_page, pgidx, mem, |m, v| op.apply(v, m)) ));
+			}
+		}
+		Snapshot {
+			pages: Rc::new(pages),
+		}
+	}
+	/// (internal) Merge a page written on only one path with the contents of memory
+	///
+	/// `op` is called with the memory contents and the written value
+	fn merge_with_memory<F>(page: &[Option<Value<u8>>], pgidx: u64, mem: &MemoryState, op: F) -> Vec<Option<Value<u8>>>
+	where
+		F: Fn(&Value<u8>, &Value<u8>) -> Value<u8>
+	{
+		let base = pgidx * PAGE_SIZE;
+		page.iter().enumerate()
+			.map(|(i, v)| v.as_ref().map(|v| op(&mem.read_u8(base + i as u64).unwrap_or(Value::unknown()), v)))
+			.collect()
+	}
+	/// Check if two snapshots hold the same contents
+	pub fn is_identical(&self, other: &Snapshot) -> bool
+	{
+		if Rc::ptr_eq(&self.pages, &other.pages) {
+			return true;
+		}
+		self.pages.len() == other.pages.len()
+			&& self.pages.iter().zip(other.pages.iter()).all(|((ai, a), (bi, b))|
+				ai == bi && (Rc::ptr_eq(a, b) || a.iter().zip(b.iter()).all(|(a,b)| match (a, b)
+					{
+					(&None, &None) => true,
+					(&Some(ref a), &Some(ref b)) => a.is_identical(b),
+					_ => false,
+					}))
+				)
+	}
+	/// Iterate over all written bytes
+	pub fn writes<'s>(&'s self) -> Box<Iterator<Item=(u64, &'s Value<u8>)> + 's>
+	{
+		Box::new(self.pages.iter().flat_map(|(&pgidx, page)|
+			page.iter().enumerate().filter_map(move |(i, v)| v.as_ref().map(|v| (pgidx * PAGE_SIZE + i as u64, v)))
+			))
+	}
+}
+impl ::std::fmt::Debug for Snapshot
+{
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+	{
+		write!(f, "Snapshot({} pages)", self.pages.len())
+	}
+}
+
+impl ::std::fmt::Display for Permissions
+{
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+	{
+		write!(f, "{}{}{}",
+			if self.read { "r" } else { "-" },
+			if self.write { "w" } else { "-" },
+			if self.exec { "x" } else { "-" }
+			)
+	}
+}
+
+impl BankSelection
+{
+	pub fn is_empty(&self) -> bool {
+		self.selected.is_empty()
+	}
+	/// Get the bank selected for the region at `base` (`initial` if none has been selected, None if not known)
+	pub fn get(&self, base: u64, initial: Option<BankId>) -> Option<BankId> {
+		match self.selected.get(&base)
+		{
+		Some(&b) => b,
+		None => initial,
+		}
+	}
+	pub fn select(&mut self, base: u64, bank: Option<BankId>) {
+		self.selected.insert(base, bank);
+	}
+	/// Forget which bank is selected in every region that has had a bank selected
+	pub fn generalise(&self) -> BankSelection
+	{
+		BankSelection {
+			selected: self.selected.keys().map(|&base| (base, None)).collect(),
+		}
+	}
+	/// Merge the selections from two paths, regions where they differ have an unknown bank
+	pub fn merge(&self, other: &BankSelection, mem: &MemoryState) -> BankSelection
+	{
+		let mut rv = BankSelection::default();
+		for &base in self.selected.keys().chain(other.selected.keys())
+		{
+			// - A region only selected on one path has its initial bank on the other
+			let (a, b) = (mem.bank_at(self, base), mem.bank_at(other, base));
+			rv.selected.insert(base, if a == b { a } else { None });
+		}
+		rv
 	}
 }
 
@@ -194,7 +1049,7 @@ impl MemoryStateAccess for u8
 	{
 		mem.read_u8(addr)
 	}
-	fn write(mem: &mut MemoryState, addr: u64, val: Value<u8>)
+	fn write(mem: &MemoryState, addr: u64, val: Value<u8>)
 	{
 		mem.write_u8(addr, val);
 	}
@@ -206,7 +1061,7 @@ impl MemoryStateAccess for u16
 	{
 		mem.read_u16(addr)
 	}
-	fn write(mem: &mut MemoryState, addr: u64, val: Value<u16>)
+	fn write(mem: &MemoryState, addr: u64, val: Value<u16>)
 	{
 		mem.write_u16(addr, val);
 	}
@@ -218,7 +1073,7 @@ impl MemoryStateAccess for u32
 	{
 		mem.read_u32(addr)
 	}
-	fn write(mem: &mut MemoryState, addr: u64, val: Value<u32>)
+	fn write(mem: &MemoryState, addr: u64, val: Value<u32>)
 	{
 		mem.write_u32(addr, val);
 	}
@@ -230,10 +1085,271 @@ impl MemoryStateAccess for u64
 	{
 		mem.read_u64(addr)
 	}
-	fn write(mem: &mut MemoryState, addr: u64, val: Value<u64>)
+	fn write(mem: &MemoryState, addr: u64, val: Value<u64>)
 	{
 		mem.write_u64(addr, val);
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use value::{MergeOp,ValueBool};
+	
+	#[test]
+	fn ram_writes_merge()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram(0x1000, 0x100);
+		mem.add_ram_data(0x3000, &[0; 8]);
+		mem.add_rom_data(0x2000, vec![1, 2, 3, 4]);
+		// - Never-written RAM is unknown, and stays unknown (it could still hold its old contents)
+		assert!( mem.read_u8(0x1000).unwrap().is_unknown() );
+		let gen = mem.generation();
+		mem.write_u32(0x1004, Value::known(0x12345678));
+		assert!( mem.read_u32(0x1004).unwrap().is_unknown() );
+		assert_eq!( mem.generation(), gen );
+		// - Initialised RAM is merged with the stored value
+		mem.write_u32(0x3004, Value::known(0x12345678));
+		assert_eq!( mem.read_u8(0x3004).unwrap().possibilities().collect::<Vec<_>>(), vec![0, 0x78] );
+		assert!( mem.generation() > gen );
+		// - Later stores are merged with earlier ones (memory isn't tracked per path)
+		mem.write_u8(0x3004, Value::known(0x79));
+		assert_eq!( mem.read_u8(0x3004).unwrap().possibilities().collect::<Vec<_>>(), vec![0, 0x78, 0x79] );
+		// - Storing an existing value doesn't count as a change
+		let gen = mem.generation();
+		mem.write_u8(0x3005, Value::known(0x56));
+		assert_eq!( mem.generation(), gen );
+		// - ROM is left alone
+		mem.write_u8(0x2000, Value::known(0xFF));
+		assert_eq!( mem.read_u8(0x2000).and_then(|v| v.val_known()), Some(1) );
+	}
+	
+	#[test]
+	fn snapshot_overlay()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram_data(0x1000, &[0; 16]);
+		let mut snap = Snapshot::new();
+		snap.write_bytes(&mem, 0x1000, &mem.split_bytes(&Value::<u16>::known(0xBEEF)), true);
+		// - Reads check the snapshot first, without changing memory
+		assert_eq!( snap.read::<u32>(&mem, 0x1000).and_then(|v| v.val_known()), Some(0xBEEF) );
+		assert_eq!( mem.read_u16(0x1000).and_then(|v| v.val_known()), Some(0) );
+		// - Until the snapshot is folded back (merged byte-by-byte)
+		mem.apply_snapshot(&snap);
+		assert_eq!( mem.read_u8(0x1000).unwrap().possibilities().collect::<Vec<_>>(), vec![0, 0xEF] );
+		assert_eq!( mem.read_u8(0x1001).unwrap().possibilities().collect::<Vec<_>>(), vec![0, 0xBE] );
+	}
+	
+	#[test]
+	fn snapshot_forks()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram_data(0x1000, &[0; 0x400]);
+		let mut a = Snapshot::new();
+		a.write_bytes(&mem, 0x1000, &[Value::known(1)], true);
+		// - Forks share pages until written
+		let mut b = a.clone();
+		assert!( a.is_identical(&b) );
+		b.write_bytes(&mem, 0x1000, &[Value::known(2)], true);
+		assert_eq!( a.read_u8(&mem, 0x1000).val_known(), Some(1) );
+		assert_eq!( b.read_u8(&mem, 0x1000).val_known(), Some(2) );
+		assert!( !a.is_identical(&b) );
+		// - A store that might not happen keeps the old value as an option
+		let mut c = a.clone();
+		c.write_bytes(&mem, 0x1000, &[Value::known(3)], false);
+		assert_eq!( c.read_u8(&mem, 0x1000).possibilities().collect::<Vec<_>>(), vec![1, 3] );
+	}
+	
+	#[test]
+	fn snapshot_clobber()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram_data(0x1000, &[0; 0x400]);
+		let mut snap = Snapshot::new();
+		snap.write_bytes(&mem, 0x1000, &[Value::known(1)], true);
+		snap.write_bytes(&mem, 0x1300, &[Value::known(2)], true);
+		// - Only written bytes in the range are forgotten
+		snap.clobber(&mem, Some( (0x1200, 0x1400) ));
+		assert_eq!( snap.read_u8(&mem, 0x1000).val_known(), Some(1) );
+		assert!( snap.read_u8(&mem, 0x1300).is_unknown() );
+		assert_eq!( snap.read_u8(&mem, 0x1301).val_known(), Some(0) );
+		// - Without a range, every written byte is
+		snap.clobber(&mem, None);
+		assert!( snap.read_u8(&mem, 0x1000).is_unknown() );
+	}
+	
+	#[test]
+	fn snapshot_merge()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram_data(0x1000, &[0; 0x400]);
+		let mut a = Snapshot::new();
+		a.write_bytes(&mem, 0x1000, &[Value::known(1)], true);
+		let mut b = Snapshot::new();
+		b.write_bytes(&mem, 0x1000, &[Value::known(2)], true);
+		b.write_bytes(&mem, 0x1300, &[Value::known(5)], true);
+		let m = a.merge(&b, &mem, MergeOp::Join);
+		assert_eq!( m.read_u8(&mem, 0x1000).possibilities().collect::<Vec<_>>(), vec![1, 2] );
+		// - Bytes only written on one path are merged with memory
+		assert_eq!( m.read_u8(&mem, 0x1300).possibilities().collect::<Vec<_>>(), vec![0, 5] );
+		assert!( m.get(0x1200).is_none() );
+		// - Merging is idempotent
+		assert!( m.merge(&m, &mem, MergeOp::Join).is_identical(&m) );
+		assert!( m.merge(&m.clone(), &mem, MergeOp::Widen).is_identical(&m) );
+	}
+	
+	#[test]
+	fn mmio_semantics()
+	{
+		let mut dev = ::mmio::Device::new("test");
+		dev.add_register(::mmio::Register {
+			name: String::from("STAT"), offset: 0, size: 2, reset: Some(0),
+			read_only: 0x0001, write_only: 0x8000, fields: Vec::new(),
+			}).unwrap();
+		let mut mem = MemoryState::new();
+		mem.add_mmio(0x4000, 0x10, "test", Some(dev));
+		assert_eq!( mem.read_u16(0x4000).and_then(|v| v.val_known()), None );
+		mem.write_u16(0x4000, Value::known(0xFFFF));
+		let v = mem.read_u16(0x4000).unwrap();
+		// - Read-only bits are set by hardware, write-only bits read as zero, others may be reset or written
+		assert_eq!( v.bit(0), ValueBool::Unknown );
+		assert_eq!( v.bit(15), ValueBool::False );
+		assert_eq!( v.bit(4), ValueBool::Unknown );
+		// - Bytes outside a register are unknown, and writes to them are dropped
+		mem.write_u8(0x4008, Value::known(1));
+		assert!( mem.read_u8(0x4008).unwrap().is_unknown() );
+	}
+	
+	#[test]
+	fn cross_region_reads()
+	{
+		let mut mem = MemoryState::new();
+		mem.set_endian(Endian::Little);
+		mem.add_rom_data(0x1000, vec![0x11, 0x22, 0x33, 0x44]);
+		mem.add_ram_data(0x1004, &[0x55, 0x66]);
+		// - Reads spanning two regions are stitched together
+		assert_eq!( mem.read_u32(0x1002).and_then(|v| v.val_known()), Some(0x66554433) );
+		assert_eq!( mem.read_u64(0x1000).map(|v| v.known_bits()), Some( (0x6655_44332211, 0xFFFF_FFFFFFFF) ) );
+		// - Unmapped bytes are unknown, and entirely unmapped reads fail
+		assert_eq!( mem.read_u16(0x1005).map(|v| v.known_bits()), Some( (0x66, 0xFF) ) );
+		assert!( mem.read_u32(0x2000).is_none() );
+		
+		mem.set_endian(Endian::Big);
+		assert_eq!( mem.read_u32(0x1002).and_then(|v| v.val_known()), Some(0x33445566) );
+	}
+	
+	#[test]
+	fn mirrors()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram_data(0x1000, &[0; 0x10]);
+		mem.add_mirror(0x2000, 0x40, 0x1000);
+		assert_eq!( mem.canonicalise(0x2004), 0x1004 );
+		// - Windows larger than the target wrap
+		assert_eq!( mem.canonicalise(0x2014), 0x1004 );
+		assert_eq!( mem.canonicalise(0x3000), 0x3000 );
+		// - Reads and writes through either address see the same byte
+		mem.write_u8(0x2014, Value::known(7));
+		assert_eq!( mem.read_u8(0x1004).unwrap().possibilities().collect::<Vec<_>>(), vec![0, 7] );
+		assert_eq!( mem.read_u8(0x2004).unwrap().possibilities().collect::<Vec<_>>(), vec![0, 7] );
+		let mut snap = Snapshot::new();
+		snap.write_bytes(&mem, 0x2005, &[Value::known(9)], true);
+		assert_eq!( snap.read_u8(&mem, 0x1005).val_known(), Some(9) );
+		assert_eq!( mem.permissions(0x2000), mem.permissions(0x1000) );
+	}
+	
+	#[test]
+	fn ram_pages()
+	{
+		let ram = RamRegion::new();
+		// - Pages are only allocated once written
+		assert!( ram.read_u8(0x10).is_none() );
+		assert!( !ram.write_u8(0x10, Value::known(5)) );
+		assert!( ram.pages.borrow().is_empty() );
+		assert!( ram.record_u8(RAM_PAGE_SIZE + 1, Value::known(5)) );
+		assert_eq!( ram.pages.borrow().keys().cloned().collect::<Vec<_>>(), vec![1] );
+		assert!( ram.read_u8(1).is_none() );
+		assert_eq!( ram.read_u8(RAM_PAGE_SIZE + 1).and_then(|v| v.val_known()), Some(5) );
+		// - Rewriting the same value isn't a change
+		assert!( !ram.write_u8(RAM_PAGE_SIZE + 1, Value::known(5)) );
+	}
+	
+	#[test]
+	fn ram_page_bitmap()
+	{
+		let mut page = RamPage::new();
+		page.set(3, Value::known(0xAA));
+		assert!( page.known.contains(3) );
+		assert_eq!( page.get(3).and_then(|v| v.val_known()), Some(0xAA) );
+		// - Values that aren't a single known byte move out of the bitmap, and back
+		page.set(3, Value::set(vec![1, 2]));
+		assert!( !page.known.contains(3) );
+		assert_eq!( page.get(3).map(|v| v.possibilities().collect::<Vec<_>>()), Some(vec![1, 2]) );
+		page.set(3, Value::known(4));
+		assert!( page.other.is_empty() );
+		assert_eq!( page.get(3).and_then(|v| v.val_known()), Some(4) );
+		assert!( page.get(4).is_none() );
+	}
+	
+	#[test]
+	fn banked_regions()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_ram(0x2000, 0x100);
+		mem.add_banked_data(0x4000, vec![vec![1, 2], vec![3, 4]], Some( (0x2000, 0x100) ));
+		assert!( mem.is_banked(0x4001) );
+		assert_eq!( mem.banked_region(0x4001), Some(0x4000) );
+		assert_eq!( mem.bank_select(0x2010), Some( (0x4000, 2) ) );
+		assert!( mem.bank_select(0x2100).is_none() );
+		// - Bank 0 is mapped until another is selected
+		let mut sel = BankSelection::default();
+		assert_eq!( mem.bank_at(&sel, 0x4000), Some(0) );
+		assert_eq!( mem.read_u8(0x4001).and_then(|v| v.val_known()), Some(2) );
+		assert_eq!( mem.read_in_bank::<u16>(1, 0x4000).and_then(|v| v.val_known()), Some(0x0403) );
+		sel.select(0x4000, Some(1));
+		assert_eq!( mem.bank_at(&sel, 0x4000), Some(1) );
+		// - Paths that selected different banks merge to an unknown bank
+		let other = BankSelection::default();
+		assert_eq!( mem.bank_at(&sel.merge(&other, &mem), 0x4000), None );
+		assert_eq!( mem.bank_at(&sel.merge(&sel.clone(), &mem), 0x4000), Some(1) );
+		assert_eq!( mem.bank_at(&sel.generalise(), 0x4000), None );
+	}
+	
+	#[test]
+	fn overlays()
+	{
+		let mut mem = MemoryState::new();
+		mem.add_overlay_data(0x4000, vec![vec![1, 2], vec![3, 4]]);
+		// - Which overlay is loaded isn't known, and overlays can be written
+		assert_eq!( mem.bank_at(&BankSelection::default(), 0x4000), None );
+		assert!( mem.read_u8(0x4000).unwrap().is_unknown() );
+		assert_eq!( mem.permissions(0x4000).map(|p| p.write), Some(true) );
+		mem.write_u8(0x4000, Value::known(9));
+		assert_eq!( mem.read_in_bank::<u8>(0, 0x4000).unwrap().possibilities().collect::<Vec<_>>(), vec![1, 9] );
+		assert_eq!( mem.read_in_bank::<u8>(1, 0x4001).and_then(|v| v.val_known()), Some(4) );
+	}
+	
+	#[test]
+	fn byte_order()
+	{
+		let mut dev = ::mmio::Device::new("test");
+		dev.add_register(::mmio::Register {
+			name: String::from("ID"), offset: 0, size: 4, reset: Some(0x11223344),
+			read_only: 0, write_only: 0, fields: Vec::new(),
+			}).unwrap();
+		let mut mem = MemoryState::new();
+		assert_eq!( mem.endian(), None );
+		mem.add_mmio(0x4000, 0x10, "test", Some(dev));
+		assert_eq!( mem.read_u8(0x4000).and_then(|v| v.val_known()), Some(0x44) );
+		// - Registers are laid out again when the byte order changes
+		mem.set_endian(Endian::Big);
+		assert_eq!( mem.read_u8(0x4000).and_then(|v| v.val_known()), Some(0x11) );
+		assert_eq!( mem.read_u32(0x4000).and_then(|v| v.val_known()), Some(0x11223344) );
+		assert_eq!( mem.split_bytes(&Value::<u16>::known(0xABCD)).iter().map(|b| b.val_known()).collect::<Vec<_>>(), vec![Some(0xAB), Some(0xCD)] );
+		assert_eq!( mem.join_bytes::<u16>(&[Value::known(0xAB), Value::known(0xCD)]).val_known(), Some(0xABCD) );
+	}
+}
+
 // vim: ft=rust
@@ -0,0 +1,176 @@
+// BinaryView2
+// - By John Hodge (thePowersGang)
+//
+// mmio.rs
+// - Descriptions of memory-mapped hardware registers
+//
+// Loaded from `<class>.mmio` files (see parse::parse_mmio), and used to give MMIO reads/writes
+// meaningful values and to annotate the disassembly with register names.
+
+/// A memory-mapped device (a block of hardware registers)
+#[derive(Debug)]
+pub struct Device
+{
+	pub name: String,
+	/// Registers, sorted by offset
+	registers: Vec<Register>,
+}
+
+/// A single hardware register
+#[derive(Debug)]
+pub struct Register
+{
+	pub name: String,
+	/// Offset from the start of the device
+	pub offset: u64,
+	/// Size in bytes
+	pub size: u8,
+	/// Value after reset (None if not known)
+	pub reset: Option<u64>,
+	/// Bits that can't be written (e.g. status set by hardware)
+	pub read_only: u64,
+	/// Bits that read back as zero
+	pub write_only: u64,
+	pub fields: Vec<Field>,
+}
+
+/// A named bitfield within a register
+#[derive(Debug)]
+pub struct Field
+{
+	pub name: String,
+	/// Index of the lowest bit
+	pub shift: u8,
+	/// Number of bits
+	pub bits: u8,
+}
+
+impl Device
+{
+	pub fn new(name: &str) -> Device
+	{
+		Device {
+			name: String::from(name),
+			registers: Vec::new(),
+		}
+	}
+
+	/// Add a register, fails if it overlaps an existing register
+	pub fn add_register(&mut self, reg: Register) -> Result<(),String>
+	{
+		let pos = match self.registers.binary_search_by(|r| r.offset.cmp(&reg.offset))
+			{
+			Ok(_) => return Err( format!("Register {} overlaps an existing register", reg.name) ),
+			Err(i) => i,
+			};
+		if pos > 0 && self.registers[pos-1].end() > reg.offset {
+			return Err( format!("Register {} overlaps {}", reg.name, self.registers[pos-1].name) );
+		}
+		if pos < self.registers.len() && reg.end() > self.registers[pos].offset {
+			return Err( format!("Register {} overlaps {}", reg.name, self.registers[pos].name) );
+		}
+		self.registers.insert(pos, reg);
+		Ok( () )
+	}
+
+	pub fn registers(&self) -> &[Register] {
+		&self.registers
+	}
+
+	/// Get the register covering an offset, and the byte index within it
+	pub fn register_at(&self, ofs: u64) -> Option<(&Register, usize)>
+	{
+		let pos = match self.registers.binary_search_by(|r| r.offset.cmp(&ofs))
+			{
+			Ok(i) => i,
+			Err(0) => return None,
+			Err(i) => i - 1,
+			};
+		let r = &self.registers[pos];
+		if ofs < r.end() {
+			Some( (r, (ofs - r.offset) as usize) )
+		}
+		else {
+			None
+		}
+	}
+}
+
+impl Register
+{
+	/// Offset of the first byte after the register
+	pub fn end(&self) -> u64 {
+		self.offset + self.size as u64
+	}
+
+	/// Describe a value written to the register using its fields (e.g. `BG_MODE=3|OBJ_ENABLE`)
+	pub fn describe_value(&self, val: u64) -> String
+	{
+		let mut rv = String::new();
+		let mut covered = 0;
+		for f in self.fields.iter()
+		{
+			let mask = if f.bits >= 64 { !0 } else { (1u64 << f.bits) - 1 };
+			covered |= mask << f.shift;
+			let fv = (val >> f.shift) & mask;
+			if fv == 0 {
+				continue ;
+			}
+			if rv.len() > 0 {
+				rv.push('|');
+			}
+			if f.bits == 1 {
+				rv.push_str(&f.name);
+			}
+			else {
+				rv.push_str(&format!("{}={}", f.name, fv));
+			}
+		}
+		// - Bits not covered by a field are shown as a raw value
+		if val & !covered != 0 || rv.len() == 0 {
+			if rv.len() > 0 {
+				rv.push('|');
+			}
+			rv.push_str(&format!("{:#x}", val & !covered));
+		}
+		rv
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	
+	fn reg(name: &str, offset: u64, size: u8) -> Register
+	{
+		Register { name: String::from(name), offset: offset, size: size, reset: None, read_only: 0, write_only: 0, fields: Vec::new() }
+	}
+	
+	#[test]
+	fn register_lookup()
+	{
+		let mut dev = Device::new("test");
+		dev.add_register(reg("CTRL", 0, 2)).unwrap();
+		dev.add_register(reg("DATA", 4, 4)).unwrap();
+		assert!( dev.add_register(reg("BAD", 3, 2)).is_err() );
+		assert!( dev.add_register(reg("BAD", 4, 1)).is_err() );
+		assert_eq!( dev.register_at(1).map(|(r,i)| (&r.name[..], i)), Some( ("CTRL", 1) ) );
+		assert_eq!( dev.register_at(7).map(|(r,i)| (&r.name[..], i)), Some( ("DATA", 3) ) );
+		assert!( dev.register_at(2).is_none() );
+		assert!( dev.register_at(8).is_none() );
+	}
+	
+	#[test]
+	fn describe_fields()
+	{
+		let mut r = reg("DISPCNT", 0, 2);
+		r.fields.push( Field { name: String::from("BG_MODE"), shift: 0, bits: 3 } );
+		r.fields.push( Field { name: String::from("OBJ_ENABLE"), shift: 12, bits: 1 } );
+		assert_eq!( r.describe_value(0x1003), "BG_MODE=3|OBJ_ENABLE" );
+		assert_eq!( r.describe_value(0x0100), "0x100" );
+		assert_eq!( r.describe_value(0), "0x0" );
+	}
+}
+
+// vim: ft=rust
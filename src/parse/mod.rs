@@ -43,6 +43,17 @@ pub fn parse_memorymap(
 		{
 		lexer::TokIdent(ident) => match &*ident
 			{
+			"ENDIAN" => {
+				let endian = match &*assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) )
+					{
+					"big" => ::memory::Endian::Big,
+					"little" => ::memory::Endian::Little,
+					v @ _ => return Err( format!("Unknown byte order '{}', expected big or little", v) ),
+					};
+				assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+				debug!("Set byte order {:?}", endian);
+				memory.set_endian(endian);
+				},
 			"RAM" => {
 				let addr = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
 				let size = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
@@ -53,24 +64,95 @@ pub fn parse_memorymap(
 			"MMIO" => {
 				let addr = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
 				let size = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+				// - Optional device class, described by `<class>.mmio` next to the memory map
+				let (class, device) = match try!(get_tok(&mut lex))
+					{
+					lexer::TokIdent(class) => {
+						assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+						let devpath = ::std::path::Path::new(path).with_file_name( format!("{}.mmio", class) );
+						let device = try!( parse_mmio(&class, &devpath) );
+						(class, Some(device))
+						},
+					lexer::TokNewline => (String::new(), None),
+					tok @ _ => return Err( format!("Unexpected {:?}, expected TokIdent or TokNewline", tok) ),
+					};
+				debug!("Add MMIO {:#x}+{:#x} class '{}'", addr, size, class);
+				memory.add_mmio(addr, size as usize, &class, device);
+				},
+			"MIRROR" => {
+				let addr = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+				let size = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+				let target = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
 				assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
-				debug!("Add MMIO {:#x}+{:#x}", addr, size);
-				memory.add_mmio(addr, size as usize, "");
+				debug!("Add mirror {:#x}+{:#x} of {:#x}", addr, size, target);
+				memory.add_mirror(addr, size as usize, target);
 				},
-			"ROM" => {
+			"ROM" => match try!(get_tok(&mut lex))
+				{
+				// - `ROM <file_ident>` : Intel HEX/S-record file, addresses are from the file
+				lexer::TokIdent(file_id) => {
+					assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+					debug!("Add ROM from hex file ident {}", file_id);
+					let file_struct = match infiles.get_mut(&file_id)
+						{
+						None => return Err( format!("No filename set for ident '{}'", file_id) ),
+						Some(f) => f,
+						};
+					let program = match ::loader::hexfile::load(memory, file_struct)
+						{
+						Ok(p) => p,
+						Err(e) => return Err( format!("Unable to load hex file '{}': {}", file_id, e) ),
+						};
+					entrypoints.extend( program.entrypoints );
+					},
+				// - `ROM <base> <size> <file_ident>` : Raw binary file
+				lexer::TokInteger(addr) => {
+					let size = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+					let file_id = assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) );
+					assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+					debug!("Add ROM {:#x}+{:#x} ident {}", addr, size, file_id);
+					match infiles.get_mut(&file_id)
+					{
+					None => return Err( format!("No filename set for ident '{}'", file_id) ),
+					Some(file_struct) => {
+						memory.add_rom(addr, size as usize, file_struct);
+						}
+					}
+					},
+				tok @ _ => return Err( format!("Unexpected {:?}, expected TokInteger or TokIdent", tok) ),
+				},
+			"BANKED" => {
 				let addr = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
 				let size = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
 				let file_id = assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) );
-				assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
-				debug!("Add ROM {:#x}+{:#x} ident {}", addr, size, file_id);
+				// - Optional range that selects the bank when written
+				let select = match try!(get_tok(&mut lex))
+					{
+					lexer::TokInteger(sel_base) => {
+						let sel_size = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+						assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+						Some( (sel_base, sel_size) )
+						},
+					lexer::TokNewline => None,
+					tok @ _ => return Err( format!("Unexpected {:?}, expected TokInteger or TokNewline", tok) ),
+					};
+				debug!("Add banked ROM {:#x}+{:#x} ident {} select {:?}", addr, size, file_id, select);
 				match infiles.get_mut(&file_id)
 				{
 				None => return Err( format!("No filename set for ident '{}'", file_id) ),
 				Some(file_struct) => {
-					memory.add_rom(addr, size as usize, file_struct);
+					memory.add_banked(addr, size as usize, file_struct, select);
 					}
 				}
 				},
+			"PERMS" => {
+				let addr = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+				let flags = assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) );
+				assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+				let perms = try!( parse_permissions(&flags) );
+				debug!("Set permissions of {:#x} to {}", addr, perms);
+				try!( memory.set_permissions(addr, perms) );
+				},
 			"ENTRY" => {
 				let addr = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
 				let mode = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
@@ -195,6 +277,116 @@ pub fn parse_typemap(typemap: &mut ::types::TypeMap, path: &str) -> Result<(),St
 	Ok( () )
 }
 
+/// Parse an MMIO register description file
+///
+/// Registers are listed as:
+///
+///     REG <offset> <width_bits> <name> [<reset>]
+///       FIELD <name> <first_bit> <bit_count>
+///       RO <read_only_mask>
+///       WO <write_only_mask>
+///     END
+pub fn parse_mmio(class: &str, path: &::std::path::Path) -> Result<::mmio::Device,String>
+{
+	let mut reader = match ::std::fs::File::open(path)
+		{
+		Ok(f) => f,
+		Err(e) => return Err( format!("Unable to open MMIO description '{}': {}", path.display(), e) ),
+		};
+	let mut lex = lexer::Lexer::new( &mut reader );
+	let mut device = ::mmio::Device::new(class);
+	
+	loop
+	{
+		match try!(get_tok(&mut lex))
+		{
+		lexer::TokIdent(ident) => match &*ident
+			{
+			"REG" => {
+				let offset = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+				let width = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+				let name = assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) );
+				let reset = match try!(get_tok(&mut lex))
+					{
+					lexer::TokInteger(v) => {
+						assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+						Some(v)
+						},
+					lexer::TokNewline => None,
+					tok @ _ => return Err( format!("Unexpected {:?}, expected TokInteger or TokNewline", tok) ),
+					};
+				if width != 8 && width != 16 && width != 32 && width != 64 {
+					return Err( format!("Register {} has an invalid width ({} bits)", name, width) );
+				}
+				let mut reg = ::mmio::Register {
+					name: name,
+					offset: offset,
+					size: (width / 8) as u8,
+					reset: reset,
+					read_only: 0,
+					write_only: 0,
+					fields: Vec::new(),
+					};
+				// Attributes
+				// - terminated by: END
+				loop
+				{
+					let attr = assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) );
+					match &*attr
+					{
+					"END" => break,
+					"FIELD" => {
+						let fldname = assert_token!( lexer::TokIdent(s) = try!(get_tok(&mut lex)) );
+						let shift = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+						let bits = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) );
+						if bits == 0 || shift + bits > width {
+							return Err( format!("Field {}.{} doesn't fit in the register", reg.name, fldname) );
+						}
+						reg.fields.push( ::mmio::Field { name: fldname, shift: shift as u8, bits: bits as u8 } );
+						},
+					"RO" => { reg.read_only = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) ); },
+					"WO" => { reg.write_only = assert_token!( lexer::TokInteger(i) = try!(get_tok(&mut lex)) ); },
+					_ => return Err( format!("Unknown register attribute '{}'", attr) ),
+					}
+					assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+				}
+				assert_token!( lexer::TokNewline = try!(get_tok(&mut lex)) );
+				debug!("MMIO {}: {} at +{:#x}", class, reg.name, reg.offset);
+				try!( device.add_register(reg) );
+				},
+			_ => return Err( format!("Unknown keyword in MMIO description '{}'", ident) ),
+			},
+		lexer::TokEof => break,
+		lexer::TokNewline => continue,
+		tok @ _ => {
+			return Err( format!("Unexpected {:?}, expected TokIdent or TokEOF", tok) );
+			}
+		}
+	}
+	
+	Ok( device )
+}
+
+/// Parse region permissions, a combination of `r`, `w` and `x` (or `none`)
+fn parse_permissions(flags: &str) -> Result<::memory::Permissions,String>
+{
+	let mut rv = ::memory::Permissions { read: false, write: false, exec: false };
+	if flags == "none" {
+		return Ok(rv);
+	}
+	for c in flags.chars()
+	{
+		match c
+		{
+		'r' => rv.read = true,
+		'w' => rv.write = true,
+		'x' => rv.exec = true,
+		_ => return Err( format!("Unknown permission '{}' in '{}', expected r, w or x", c, flags) ),
+		}
+	}
+	Ok(rv)
+}
+
 fn parse_type(typemap: &::types::TypeMap, lex: &mut lexer::Lexer) -> Result<::types::Type,String>
 {
 	let mut ptrdepth = 0;
@@ -230,4 +422,139 @@ fn parse_type(typemap: &::types::TypeMap, lex: &mut lexer::Lexer) -> Result<::ty
 	
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::io::Write;
+	use std::path::PathBuf;
+	
+	/// Scratch directory for a test (derefs to its path), deleted when dropped
+	struct TestDir(PathBuf);
+	impl ::std::ops::Deref for TestDir
+	{
+		type Target = PathBuf;
+		fn deref(&self) -> &PathBuf {
+			&self.0
+		}
+	}
+	impl Drop for TestDir
+	{
+		fn drop(&mut self) {
+			let _ = ::std::fs::remove_dir_all(&self.0);
+		}
+	}
+	/// Create an empty scratch directory for a test
+	fn test_dir(name: &str) -> TestDir
+	{
+		let dir = ::std::env::temp_dir().join( format!("binaryview2-{}-{}", ::std::process::id(), name) );
+		let _ = ::std::fs::remove_dir_all(&dir);
+		::std::fs::create_dir_all(&dir).unwrap();
+		TestDir(dir)
+	}
+	fn write_file(dir: &PathBuf, name: &str, data: &[u8]) -> PathBuf
+	{
+		let path = dir.join(name);
+		::std::fs::File::create(&path).unwrap().write_all(data).unwrap();
+		path
+	}
+	/// Parse a memory map, with input files opened from `files` (ident, filename in `dir`)
+	fn load_map(dir: &PathBuf, map: &str, files: &[(&str, &str)]) -> Result<::memory::MemoryState,String>
+	{
+		let mut memory = ::memory::MemoryState::new();
+		let typemap = ::types::TypeMap::new();
+		let mut infiles = ::std::collections::HashMap::new();
+		for &(ident, name) in files {
+			infiles.insert( String::from(ident), ::std::fs::File::open(dir.join(name)).unwrap() );
+		}
+		let path = write_file(dir, "test.map", map.as_bytes());
+		try!( parse_memorymap(&mut memory, &typemap, &mut infiles, path.to_str().unwrap()) );
+		Ok(memory)
+	}
+	
+	const DISPLAY_MMIO: &'static str = "REG 0x0 16 DISPCNT 0x80\n\tFIELD BG_MODE 0 3\n\tFIELD FORCE_BLANK 7 1\nEND\nREG 0x4 16 DISPSTAT\n\tRO 0x7\n\tWO 0x8000\nEND\n";
+	
+	#[test]
+	fn mmio_description()
+	{
+		let dir = test_dir("mmio_description");
+		let dev = parse_mmio("display", &write_file(&dir, "display.mmio", DISPLAY_MMIO.as_bytes())).unwrap();
+		assert_eq!( dev.registers().len(), 2 );
+		let (r, idx) = dev.register_at(5).unwrap();
+		assert_eq!( (&r.name[..], idx, r.size), ("DISPSTAT", 1, 2) );
+		assert_eq!( (r.read_only, r.write_only, r.reset), (0x7, 0x8000, None) );
+		assert_eq!( dev.register_at(0).unwrap().0.fields.len(), 2 );
+		
+		let bad = write_file(&dir, "bad.mmio", b"REG 0x0 12 ODD\nEND\n");
+		assert!( parse_mmio("bad", &bad).is_err() );
+		let bad = write_file(&dir, "bad.mmio", b"REG 0x0 8 SMALL\n\tFIELD BIG 4 8\nEND\n");
+		assert!( parse_mmio("bad", &bad).is_err() );
+		assert!( parse_mmio("missing", &dir.join("missing.mmio")).is_err() );
+	}
+	
+	#[test]
+	fn mmio_directive()
+	{
+		let dir = test_dir("mmio_directive");
+		write_file(&dir, "display.mmio", DISPLAY_MMIO.as_bytes());
+		let mem = load_map(&dir, "MMIO 0x4000000 0x100 display\nMMIO 0x5000000 0x100\n", &[]).unwrap();
+		// - Registers start at their reset values, undescribed space is unknown
+		assert_eq!( mem.read_u16(0x4000000).and_then(|v| v.val_known()), Some(0x80) );
+		assert_eq!( mem.mmio_register(0x4000004).map(|(r,_)| &r.name[..]), Some("DISPSTAT") );
+		assert!( mem.read_u8(0x4000010).unwrap().is_unknown() );
+		assert!( mem.mmio_register(0x5000000).is_none() );
+		// - Missing descriptions are reported
+		assert!( load_map(&dir, "MMIO 0x4000000 0x100 sound\n", &[]).is_err() );
+	}
+	
+	#[test]
+	fn mirror_directive()
+	{
+		let dir = test_dir("mirror_directive");
+		write_file(&dir, "rom.bin", &[1, 2, 3, 4]);
+		let mem = load_map(&dir, "ROM 0x8000000 0x4 rom\nMIRROR 0xA000000 0x10 0x8000000\n", &[("rom", "rom.bin")]).unwrap();
+		assert_eq!( mem.read_u8(0xA000001).and_then(|v| v.val_known()), Some(2) );
+		assert_eq!( mem.read_u8(0xA00000E).and_then(|v| v.val_known()), Some(3) );
+		assert!( load_map(&dir, "MIRROR 0xA000000 0x10\n", &[]).is_err() );
+	}
+	
+	#[test]
+	fn banked_directive()
+	{
+		let dir = test_dir("banked_directive");
+		write_file(&dir, "cart.bin", &[1, 2, 3, 4, 5, 6]);
+		let mem = load_map(&dir, "BANKED 0x4000 0x2 cart 0x2000 0x100\nRAM 0x2000 0x100\n", &[("cart", "cart.bin")]).unwrap();
+		assert_eq!( mem.bank_select(0x2000), Some( (0x4000, 3) ) );
+		assert_eq!( mem.read_in_bank::<u8>(2, 0x4001).and_then(|v| v.val_known()), Some(6) );
+		let mem = load_map(&dir, "BANKED 0x4000 0x2 cart\n", &[("cart", "cart.bin")]).unwrap();
+		assert!( mem.is_banked(0x4000) && mem.bank_select(0x2000).is_none() );
+	}
+	
+	#[test]
+	fn perms_directive()
+	{
+		assert_eq!( parse_permissions("rx"), Ok(::memory::Permissions { read: true, write: false, exec: true }) );
+		assert_eq!( parse_permissions("none"), Ok(::memory::Permissions { read: false, write: false, exec: false }) );
+		assert!( parse_permissions("rq").is_err() );
+		
+		let dir = test_dir("perms_directive");
+		let mem = load_map(&dir, "RAM 0x1000 0x100\nPERMS 0x1000 rw\n", &[]).unwrap();
+		assert_eq!( mem.permissions(0x1010).map(|p| p.to_string()), Some(String::from("rw-")) );
+		assert!( load_map(&dir, "PERMS 0x1000 rw\n", &[]).is_err() );
+	}
+	
+	#[test]
+	fn endian_directive()
+	{
+		let dir = test_dir("endian_directive");
+		write_file(&dir, "rom.bin", &[0x12, 0x34]);
+		let mem = load_map(&dir, "ENDIAN big\nROM 0x0 0x2 rom\n", &[("rom", "rom.bin")]).unwrap();
+		assert_eq!( mem.endian(), Some(::memory::Endian::Big) );
+		assert_eq!( mem.read_u16(0).and_then(|v| v.val_known()), Some(0x1234) );
+		let mem = load_map(&dir, "ENDIAN little\nROM 0x0 0x2 rom\n", &[("rom", "rom.bin")]).unwrap();
+		assert_eq!( mem.read_u16(0).and_then(|v| v.val_known()), Some(0x3412) );
+		assert!( load_map(&dir, "ENDIAN middle\n", &[]).is_err() );
+	}
+}
+
 // vim: ft=rust
@@ -7,11 +7,22 @@
 // A core type to BinaryView, this represents a register value during execution and a possible value
 // for RAM.
 use num::{Zero,One,NumCast};
+use num::traits::{WrappingAdd,WrappingSub,WrappingMul};
 use std::fmt::LowerHex;
 use std::cmp::Ordering;
+use std::rc::Rc;
+
+/// Maximum number of entries in a value set before it decays to Unknown
+const MAX_SET_SIZE: usize = 32;
+/// Maximum number of entries enumerated from a range (e.g. when reading a jump table)
+const MAX_ENUM_SIZE: usize = 256;
+/// Symbolic values are assumed to fit in this many bits (the register size of the supported CPUs)
+///
+/// A narrower symbolic value (e.g. a register truncated to a byte) is only the bottom bits of the input.
+const SYMBOLIC_BITS: usize = 32;
 
 /// Trait for valid values in a value (only implemented for unsigned sized integers)
-pub trait ValueType : ::num::PrimInt + LowerHex {
+pub trait ValueType : ::num::PrimInt + LowerHex + WrappingAdd + WrappingSub + WrappingMul {
 }
 impl ValueType for u8 {
 }
@@ -22,20 +33,39 @@ impl ValueType for u32 {
 impl ValueType for u64 {
 }
 
+impl MergeOp
+{
+	/// Merge a value with a newer value for the same location
+	pub fn apply<T: ValueType>(&self, prev: &Value<T>, next: &Value<T>) -> Value<T>
+	{
+		match *self
+		{
+		MergeOp::Join => prev.union(next),
+		MergeOp::Widen => prev.widen(next),
+		}
+	}
+}
+
 /// A dynamic value (range determined during execution)
 #[derive(Clone)]
 pub enum Value<T: ValueType>
 {
 	/// Value is unknown, but has meaning
 	Input(u8),
+	/// Linear combination of inputs (never a plain input or a constant)
+	Expr(Rc<LinearExpr<T>>),
 	/// Value is completely unknown (or at least non-trivial)
 	Unknown,
 	/// Fully known value
 	Known(T),
-	// TODO: Support value sets
-	//Set(Rc<Vec<T>>),
-	// TODO: Support range+mask (or similar)
-	//Masked(T,T),	// (Value,KnownFlag)
+	/// One of a small set of known values (sorted, always more than one entry)
+	Set(Rc<Vec<T>>),
+	/// Partially known value (Value,KnownMask), bits set in the mask are known
+	Masked(T,T),
+	/// Strided range of values (First,Last,Stride), too large to store as a set
+	Range(T,T,T),
+	/// Unknown value loaded through a pointer with an unknown base, plus a constant offset
+	Loaded(Rc<Provenance>,T),
 	// TODO: Support multi-state, e.g. Unknown or a set of possible values
 	// - That would be messy to work with, and probably not needed?
 	//Nested(Rc<Vec<Value<T>>>),
@@ -49,7 +79,67 @@ pub enum ValueBool
 	Unknown,
 }
 
-struct ValuePossibilities<'a,T:ValueType+'a>
+/// Relation between two values (used to constrain values after a comparison)
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum Relation
+{
+	Equal,
+	NotEqual,
+	/// Unsigned less than
+	Below,
+	/// Unsigned less than or equal
+	BelowEqual,
+	/// Unsigned greater than
+	Above,
+	/// Unsigned greater than or equal
+	AboveEqual,
+	/// Signed less than
+	Less,
+	/// Signed less than or equal
+	LessEqual,
+	/// Signed greater than
+	Greater,
+	/// Signed greater than or equal
+	GreaterEqual,
+}
+
+/// How values from two paths are combined (see `Value::union` and `Value::widen`)
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum MergeOp
+{
+	/// Union of the possible values
+	Join,
+	/// Union, with bounds that keep moving pushed to their limits (so loops converge)
+	Widen,
+}
+
+/// Origin of a value loaded through a pointer with an unknown base
+#[derive(Clone,PartialEq)]
+pub struct Provenance
+{
+	/// Base of the pointer (input-derived, or itself loaded from memory), without any constant offset
+	pub base: Value<u64>,
+	/// Offset from the base
+	pub offset: u64,
+	/// Size of the access in bytes
+	pub size: u8,
+	/// Stores made before the load (see `StateData`), the same pointer can give a different value after a store
+	pub generation: usize,
+}
+
+/// Linear combination of input registers, plus a constant offset
+///
+/// Arithmetic is modulo the value size, so expressions survive truncation (see `SYMBOLIC_BITS` for extension)
+#[derive(Clone,PartialEq)]
+pub struct LinearExpr<T: ValueType>
+{
+	/// (Input register, Coefficient) pairs, sorted by register
+	terms: Vec<(u8,T)>,
+	/// Constant offset
+	offset: T,
+}
+
+pub struct ValuePossibilities<'a,T:ValueType+'a>
 where
 	<T as ::num::traits::Num>::FromStrRadixErr: 'a
 {
@@ -78,14 +168,93 @@ impl<T: ValueType> Value<T>
 	pub fn ones() -> Value<T> {
 		Value::Known( Value::<T>::ones_raw() )
 	}
-	//// A set of possible values
-	//pub fn set(vals: Vec<T>) -> Value<T> {
-	//	ValueSet(Rc::new(vals))
-	//}
+	/// A set of possible values
+	///
+	/// Collapses to a known value if only one value is present, and to unknown if
+	/// there are too many to track.
+	pub fn set(mut vals: Vec<T>) -> Value<T> {
+		vals.sort();
+		vals.dedup();
+		match vals.len()
+		{
+		0 => panic!("Value::set - Empty value set"),
+		1 => Value::Known(vals[0]),
+		n if n > MAX_SET_SIZE => Value::Unknown,
+		_ => Value::Set(Rc::new(vals)),
+		}
+	}
+	
+	/// Partially known value (bits set in `mask` are known, and given by `val`)
+	///
+	/// Collapses to a set if only a few bits are unknown
+	pub fn masked(val: T, mask: T) -> Value<T> {
+		let unknown_bits = mask.count_zeros() as usize;
+		if unknown_bits == 0 {
+			Value::Known(val)
+		}
+		else if unknown_bits == Value::<T>::_bitsize() {
+			Value::Unknown
+		}
+		else if unknown_bits < 8 && 1 << unknown_bits <= MAX_SET_SIZE {
+			// Enumerate all possible values of the unknown bits
+			let unknown_pos: Vec<_> = (0 .. Value::<T>::_bitsize()).filter(|&i| (mask >> i) & T::one() == T::zero()).collect();
+			let base = val & mask;
+			Value::set( (0 .. 1usize << unknown_bits).map(|n| {
+				unknown_pos.iter().enumerate()
+					.filter(|&(j,_)| (n >> j) & 1 != 0)
+					.fold(base, |v, (_,&pos)| v | T::one() << pos)
+				}).collect() )
+		}
+		else {
+			Value::Masked(val & mask, mask)
+		}
+	}
+	
+	/// Strided range of values (`lo`, `lo+stride`, ... up to `hi`)
+	///
+	/// Collapses to a set if the range is small, and to unknown if it covers everything
+	pub fn range(lo: T, hi: T, stride: T) -> Value<T> {
+		assert!(lo <= hi, "Value::range - Inverted range {:#x} > {:#x}", lo, hi);
+		let stride = if stride == T::zero() { T::one() } else { stride };
+		// Align the upper bound to the stride
+		let hi = hi - (hi - lo) % stride;
+		let steps: usize = NumCast::from( (hi - lo) / stride ).unwrap_or(!0);
+		if steps < MAX_SET_SIZE {
+			Value::set( (0 .. steps+1).map(|i| lo + stride * NumCast::from(i).unwrap()).collect() )
+		}
+		else if lo == T::zero() && hi == Value::<T>::ones_raw() && stride == T::one() {
+			Value::Unknown
+		}
+		else {
+			Value::Range(lo, hi, stride)
+		}
+	}
+	
+	/// (internal) Create a value from a linear expression, simplifying where possible
+	fn from_linear(expr: LinearExpr<T>) -> Value<T> {
+		if expr.terms.is_empty() {
+			Value::Known(expr.offset)
+		}
+		else if expr.terms.len() == 1 && expr.terms[0].1 == T::one() && expr.offset == T::zero() {
+			Value::Input(expr.terms[0].0)
+		}
+		else {
+			Value::Expr(Rc::new(expr))
+		}
+	}
 	
 	fn ones_raw() -> T {
 		T::max_value()
 	}
+	/// Mask covering the bottom `count` bits
+	fn low_mask(count: usize) -> T {
+		if count >= Value::<T>::_bitsize() {
+			Value::<T>::ones_raw()
+		}
+		else {
+			(T::one() << count) - T::one()
+		}
+	}
 	
 	fn _bitsize() -> usize {
 		::std::mem::size_of::<T>() * 8
@@ -120,12 +289,24 @@ impl<T: ValueType> Value<T>
 		assert_eq!( ::std::mem::size_of::<U>() * 2, ::std::mem::size_of::<T>() );
 		match (left,right)
 		{
-		(Value::Known(a),Value::Known(b)) => {
-			let a_u: T = NumCast::from(a).unwrap();
-			let b_u: T = NumCast::from(b).unwrap();
-			Value::Known(a_u | b_u << 8*::std::mem::size_of::<U>())
+		(ref a, ref b) if a.is_fixed_set() && b.is_fixed_set() => {
+			let mut vals = Vec::new();
+			for a in a.possibilities() {
+				for b in b.possibilities() {
+					let a_u: T = NumCast::from(a).unwrap();
+					let b_u: T = NumCast::from(b).unwrap();
+					vals.push( a_u | b_u << 8*::std::mem::size_of::<U>() );
+				}
+			}
+			Value::set(vals)
+			}
+		(a, b) => {
+			let (a_v,a_m) = a.known_bits();
+			let (b_v,b_m) = b.known_bits();
+			let shift = 8*::std::mem::size_of::<U>();
+			let cast = |v: U| -> T { NumCast::from(v).unwrap() };
+			Value::masked( cast(a_v) | cast(b_v) << shift, cast(a_m) | cast(b_m) << shift )
 			}
-		_ => Value::Unknown,	// TODO: Handle mask+value (or similar)
 		}
 	}
 	
@@ -134,20 +315,103 @@ impl<T: ValueType> Value<T>
 		::std::mem::size_of::<T>() * 8
 	}
 	
-	/// Truncate (or zero-extend) a value into another size
+	/// Truncate a value into a smaller size (or zero-extend into a larger one)
 	pub fn truncate<U: ValueType>(&self) -> Value<U>
 	{
+		if Value::<U>::_bitsize() > self.bitsize() {
+			return self.zero_extend();
+		}
 		match self
 		{
 		&Value::Known(a) => {
 			let a_u: U = Value::<U>::cast(a);
 			Value::Known(a_u)
 			}
-		&Value::Input(_) => Value::Unknown,
-		&Value::Unknown => Value::Unknown,
+		&Value::Set(ref vals) => Value::set( vals.iter().map(|&v| Value::<U>::cast(v)).collect() ),
+		// - Symbolic values are modulo the value size, so survive narrowing
+		&Value::Input(i) => Value::Input(i),
+		&Value::Expr(ref e) => Value::from_linear( e.cast() ),
+		&Value::Loaded(ref p, ofs) => Value::Loaded( p.clone(), LinearExpr::<T>::cast_signed(ofs) ),
+		// - Ranges survive as long as they fit in the new type
+		&Value::Range(lo,hi,stride) if NumCast::from(hi).map(|v: U| v).is_some() =>
+			Value::range( Value::<U>::cast(lo), Value::<U>::cast(hi), Value::<U>::cast(stride) ),
+		_ => self.truncate_known_bits(),
+		}
+	}
+	/// Zero-extend into a larger type
+	///
+	/// The new high bits are known zero, so symbolic values only survive if they fit in the source type.
+	pub fn zero_extend<U: ValueType>(&self) -> Value<U>
+	{
+		assert!(Value::<U>::_bitsize() >= self.bitsize(), "Value::zero_extend - Extending u{} to u{}", self.bitsize(), Value::<U>::_bitsize());
+		match self
+		{
+		&Value::Known(a) => Value::Known( Value::<U>::cast(a) ),
+		&Value::Set(ref vals) => Value::set( vals.iter().map(|&v| Value::<U>::cast(v)).collect() ),
+		&Value::Input(i) if self.symbolic_fits() => Value::Input(i),
+		&Value::Expr(ref e) if self.symbolic_fits() => Value::from_linear( e.cast() ),
+		&Value::Loaded(ref p, ofs) if self.symbolic_fits() => Value::Loaded( p.clone(), LinearExpr::<T>::cast_signed(ofs) ),
+		&Value::Range(lo,hi,stride) => Value::range( Value::<U>::cast(lo), Value::<U>::cast(hi), Value::<U>::cast(stride) ),
+		_ => self.truncate_known_bits(),
+		}
+	}
+	/// (internal) Truncate/extend using only the known bits (bits above the source are known zero)
+	fn truncate_known_bits<U: ValueType>(&self) -> Value<U>
+	{
+		let (v,m) = self.known_bits();
+		let ext_mask = !Value::<U>::cast(Value::<T>::ones_raw());
+		Value::masked( Value::<U>::cast(v), Value::<U>::cast(m) | ext_mask )
+	}
+	/// (internal) Check if a symbolic value is known to fit in its type (so can be extended unchanged)
+	fn symbolic_fits(&self) -> bool
+	{
+		match self
+		{
+		// - A loaded value with no offset fits if the load was no wider than the value
+		&Value::Loaded(ref p, ofs) if ofs == T::zero() && p.size as usize * 8 <= self.bitsize() => true,
+		_ => self.bitsize() >= SYMBOLIC_BITS,
+		}
+	}
+	/// Sign-extend into a larger type (or truncate into a smaller one)
+	pub fn sign_extend<U: ValueType>(&self) -> Value<U>
+	{
+		match self
+		{
+		&Value::Known(a) => Value::Known( LinearExpr::<T>::cast_signed(a) ),
+		&Value::Set(ref vals) => Value::set( vals.iter().map(|&v| LinearExpr::<T>::cast_signed(v)).collect() ),
+		// - Symbolic values are modulo the value size, so survive narrowing (and extension if register-sized)
+		&Value::Input(_) | &Value::Expr(_) | &Value::Loaded(..) if Value::<U>::_bitsize() <= self.bitsize() || self.bitsize() >= SYMBOLIC_BITS => self.truncate(),
+		_ => self.truncate::<U>().sign_extend_from(self.bitsize()),
+		}
+	}
+	/// Sign-extend the bottom `bits` bits to the full width of the type
+	pub fn sign_extend_from(&self, bits: usize) -> Value<T>
+	{
+		if bits == 0 || bits >= self.bitsize() {
+			return self.clone();
+		}
+		if self.is_fixed_set() {
+			return self.set_map(|v| sign_extend_raw(bits, v));
+		}
+		// - Ranges on one side of the sign boundary stay ranges
+		if let &Value::Range(lo,hi,st) = self {
+			if lo >> (bits-1) == hi >> (bits-1) {
+				return Value::range(sign_extend_raw(bits, lo), sign_extend_raw(bits, hi), st);
+			}
+		}
+		let high = !Value::<T>::low_mask(bits);
+		let low = self.clone() & Value::known(!high);
+		match self.bit(bits-1)
+		{
+		ValueBool::False => low,
+		ValueBool::True => low | Value::known(high),
+		ValueBool::Unknown => {
+			// Upper bits all match the (unknown) sign bit
+			let (v,m) = low.known_bits();
+			Value::masked(v, m & !high)
+			},
 		}
 	}
-	pub fn zero_extend<U: ValueType>(&self) -> Value<U> { self.truncate() }
 	
 	/// Returns Some(val) if the value is fixed
 	pub fn val_known(&self) -> Option<T>
@@ -159,6 +423,43 @@ impl<T: ValueType> Value<T>
 		}
 	}
 	
+	/// (internal) Get the smallest and largest possible values of a fixed set
+	fn set_bounds(&self) -> (T,T)
+	{
+		match self
+		{
+		&Value::Known(v) => (v, v),
+		&Value::Set(ref vals) => (vals[0], vals[vals.len()-1]),
+		&Value::Range(lo,hi,_) => (lo, hi),
+		_ => panic!("Value::set_bounds - Called on non-fixed value {:?}", self),
+		}
+	}
+	/// Get the strided range (lo, hi, stride) covering this value, if it's bounded
+	///
+	/// A stride of zero indicates a single value
+	pub fn as_range(&self) -> Option<(T,T,T)>
+	{
+		match self
+		{
+		&Value::Input(_) => None,
+		&Value::Expr(_) => None,
+		&Value::Loaded(..) => None,
+		&Value::Unknown => None,
+		&Value::Known(v) => Some( (v, v, T::zero()) ),
+		&Value::Set(ref vals) => {
+			let first = vals[0];
+			let stride = vals.iter().fold(T::zero(), |acc, &v| gcd(acc, v - first));
+			Some( (first, vals[vals.len()-1], stride) )
+			},
+		&Value::Masked(v,m) => {
+			// Smallest has all unknown bits clear, largest has them all set
+			let stride = T::one() << (!m).trailing_zeros() as usize;
+			Some( (v, v | !m, stride) )
+			},
+		&Value::Range(lo,hi,stride) => Some( (lo, hi, stride) ),
+		}
+	}
+
 	pub fn is_unknown(&self) -> bool
 	{
 		match self
@@ -173,8 +474,108 @@ impl<T: ValueType> Value<T>
 		match self
 		{
 		&Value::Input(_) => false,
+		&Value::Expr(_) => false,
+		&Value::Loaded(..) => false,
 		&Value::Unknown => false,
 		&Value::Known(_) => true,
+		&Value::Set(_) => true,
+		&Value::Masked(_,_) => false,
+		&Value::Range(..) => false,
+		}
+	}
+	
+	/// Returns true if the value is derived from inputs
+	pub fn is_symbolic(&self) -> bool
+	{
+		match self
+		{
+		&Value::Input(_) => true,
+		&Value::Expr(_) => true,
+		_ => false,
+		}
+	}
+	/// (internal) Get this value as a linear expression (if it is symbolic or known)
+	fn as_linear(&self) -> Option<LinearExpr<T>>
+	{
+		match self
+		{
+		&Value::Input(i) => Some( LinearExpr { terms: vec![ (i, T::one()) ], offset: T::zero() } ),
+		&Value::Expr(ref e) => Some( (**e).clone() ),
+		&Value::Known(v) => Some( LinearExpr { terms: vec![], offset: v } ),
+		_ => None,
+		}
+	}
+	/// If this value is a constant offset from an input, return (input, offset)
+	pub fn input_offset(&self) -> Option<(u8,T)>
+	{
+		match self
+		{
+		&Value::Input(i) => Some( (i, T::zero()) ),
+		&Value::Expr(ref e) if e.terms.len() == 1 && e.terms[0].1 == T::one() => Some( (e.terms[0].0, e.offset) ),
+		_ => None,
+		}
+	}
+	
+	/// Split a pointer with an unknown base into (base, offset)
+	///
+	/// Returns None if the value isn't input-derived or loaded from memory
+	pub fn pointer_base(&self) -> Option<(Value<T>,T)>
+	{
+		match self
+		{
+		&Value::Input(i) => Some( (Value::Input(i), T::zero()) ),
+		&Value::Expr(ref e) => {
+			let base = LinearExpr { terms: e.terms.clone(), offset: T::zero() };
+			Some( (Value::from_linear(base), e.offset) )
+			},
+		&Value::Loaded(ref p, ofs) => Some( (Value::Loaded(p.clone(), T::zero()), ofs) ),
+		_ => None,
+		}
+	}
+	
+	/// Returns true if the possible values can reasonably be enumerated (small sets and ranges)
+	pub fn is_enumerable(&self) -> bool
+	{
+		match self
+		{
+		&Value::Range(lo,hi,stride) => {
+			let steps: Option<usize> = NumCast::from( (hi - lo) / stride );
+			steps.map(|s| s < MAX_ENUM_SIZE).unwrap_or(false)
+			},
+		v => v.is_fixed_set(),
+		}
+	}
+	
+	/// Get the known bits of this value as (value, mask)
+	///
+	/// Bits set in the mask are known, with their value given by the first entry
+	pub fn known_bits(&self) -> (T,T)
+	{
+		match self
+		{
+		&Value::Input(_) => (T::zero(), T::zero()),
+		&Value::Loaded(..) => (T::zero(), T::zero()),
+		&Value::Expr(ref e) => {
+			// Low bits are known if every coefficient has them clear
+			let zeros = e.terms.iter().map(|&(_,c)| c.trailing_zeros() as usize).min().unwrap_or(0);
+			let mask = Value::<T>::low_mask(zeros);
+			(e.offset & mask, mask)
+			},
+		&Value::Unknown => (T::zero(), T::zero()),
+		&Value::Known(v) => (v, Value::<T>::ones_raw()),
+		&Value::Set(ref vals) => {
+			// Bits that differ from the first entry are unknown
+			let first = vals[0];
+			let differ = vals.iter().fold(T::zero(), |acc, &v| acc | (v ^ first));
+			(first & !differ, !differ)
+			},
+		&Value::Masked(v,m) => (v,m),
+		&Value::Range(lo,hi,stride) => {
+			// Bits above the highest differing bit are known, as are the bits below the stride
+			let high = Value::<T>::_bitsize() - (lo ^ hi).leading_zeros() as usize;
+			let mask = !Value::<T>::low_mask(high) | Value::<T>::low_mask(stride.trailing_zeros() as usize);
+			(lo & mask, mask)
+			},
 		}
 	}
 	
@@ -187,6 +588,178 @@ impl<T: ValueType> Value<T>
 		}
 	}
 	
+	/// Merge two values into a value that covers both (e.g. when control flow joins)
+	pub fn union(&self, other: &Value<T>) -> Value<T>
+	{
+		match (self, other)
+		{
+		(&Value::Input(a), &Value::Input(b)) if a == b => Value::Input(a),
+		(&Value::Expr(ref a), &Value::Expr(ref b)) if a == b => Value::Expr(a.clone()),
+		(&Value::Loaded(ref a, a_o), &Value::Loaded(ref b, b_o)) if a == b && a_o == b_o => Value::Loaded(a.clone(), a_o),
+		(a, b) if a.is_fixed_set() && b.is_fixed_set() && a.possibilities().chain(b.possibilities()).count() <= MAX_SET_SIZE
+			=> Value::set( a.possibilities().chain(b.possibilities()).collect() ),
+		(a, b) => match (a.as_range(), b.as_range())
+			{
+			// - Both bounded, covering range
+			(Some((a_lo,a_hi,a_st)), Some((b_lo,b_hi,b_st))) => {
+				let lo = ::std::cmp::min(a_lo, b_lo);
+				let hi = ::std::cmp::max(a_hi, b_hi);
+				Value::range(lo, hi, gcd(gcd(a_st, b_st), ::std::cmp::max(a_lo,b_lo) - lo))
+				},
+			// - Otherwise, keep the bits that are known (and equal) in both
+			_ => {
+				let (a_v,a_m) = a.known_bits();
+				let (b_v,b_m) = b.known_bits();
+				Value::masked(a_v, a_m & b_m & !(a_v ^ b_v))
+				},
+			},
+		}
+	}
+	/// Get the sign (most significant) bit
+	pub fn sign_bit(&self) -> ValueBool
+	{
+		self.bit(self.bitsize() - 1)
+	}
+	/// Arithmetic (sign-preserving) shift right, returns (ShiftedBits, Result) like `>>`
+	pub fn asr(self, count: usize) -> (Value<T>,Value<T>)
+	{
+		let bits = self.bitsize();
+		let count = if count > bits {
+				error!("ASR {:?} by {} outside of max shift ({}), clamping", self, count, bits);
+				bits
+			}
+			else {
+				count
+			};
+		let high = !Value::<T>::low_mask(bits - count);
+		let sign = self.sign_bit();
+		let (extra, res) = self.clone() >> count;
+		let res = if self.is_fixed_set() {
+				self.set_map(|v| {
+					let shifted = if count >= bits { T::zero() } else { v >> count };
+					if v.leading_zeros() == 0 { shifted | high } else { shifted }
+					})
+			}
+			else {
+				match sign
+				{
+				ValueBool::False => res,
+				ValueBool::True => res | Value::known(high),
+				// - Vacated bits are copies of the sign, so aren't known
+				ValueBool::Unknown => {
+					let (v,m) = res.known_bits();
+					Value::masked(v, m & !high)
+					},
+				}
+			};
+		(extra, res)
+	}
+	/// Bounds of the value when interpreted as signed, if it's bounded
+	pub fn signed_bounds(&self) -> Option<(i64,i64)>
+	{
+		let sign = !(T::max_value() >> 1);
+		self.flip_bit(sign).as_range().map(|(lo,hi,_)| (to_signed(lo ^ sign), to_signed(hi ^ sign)))
+	}
+	/// Compare two values as signed (None if the order isn't fixed)
+	pub fn signed_cmp(&self, other: &Value<T>) -> Option<Ordering>
+	{
+		match (self.signed_bounds(), other.signed_bounds())
+		{
+		(Some((a_lo,a_hi)), Some((b_lo,b_hi))) =>
+			if a_hi < b_lo {
+				Some(Ordering::Less)
+			}
+			else if a_lo > b_hi {
+				Some(Ordering::Greater)
+			}
+			else if a_lo == a_hi && b_lo == b_hi {
+				Some(Ordering::Equal)
+			}
+			else {
+				None
+			},
+		_ => None,
+		}
+	}
+	/// Check if a signed addition of the two values overflows
+	pub fn signed_add_overflows(&self, other: &Value<T>) -> ValueBool
+	{
+		self.signed_overflow(other, |(a_lo,a_hi),(b_lo,b_hi)| (a_lo as i128 + b_lo as i128, a_hi as i128 + b_hi as i128))
+	}
+	/// Check if a signed subtraction (`self - other`) overflows
+	pub fn signed_sub_overflows(&self, other: &Value<T>) -> ValueBool
+	{
+		self.signed_overflow(other, |(a_lo,a_hi),(b_lo,b_hi)| (a_lo as i128 - b_hi as i128, a_hi as i128 - b_lo as i128))
+	}
+	/// (internal) Check the signed bounds of an operation against the limits of the type
+	fn signed_overflow<F>(&self, other: &Value<T>, op: F) -> ValueBool
+	where
+		F: Fn((i64,i64),(i64,i64)) -> (i128,i128)
+	{
+		match (self.signed_bounds(), other.signed_bounds())
+		{
+		(Some(a), Some(b)) => {
+			let (lo, hi) = op(a, b);
+			let max = to_signed(T::max_value() >> 1) as i128;
+			let min = -max - 1;
+			if lo >= min && hi <= max {
+				ValueBool::False
+			}
+			else if hi < min || lo > max {
+				ValueBool::True
+			}
+			else {
+				ValueBool::Unknown
+			}
+			},
+		_ => ValueBool::Unknown,
+		}
+	}
+	/// Check if the value is zero (e.g. for a zero flag)
+	pub fn is_zero(&self) -> ValueBool
+	{
+		match self
+		{
+		&Value::Known(v) => ValueBool::from_bool(v == Zero::zero()),
+		&Value::Set(ref vals) => if vals.contains(&Zero::zero()) { ValueBool::Unknown } else { ValueBool::False },
+		&Value::Range(lo,_,_) => if lo == Zero::zero() { ValueBool::Unknown } else { ValueBool::False },
+		&Value::Masked(v,m) => if v & m != Zero::zero() { ValueBool::False } else { ValueBool::Unknown },
+		_ => ValueBool::Unknown,
+		}
+	}
+	/// Widen a value that changes between loop iterations, so that repeated merging converges
+	///
+	/// Bounds that moved are pushed out to the limits of the type
+	pub fn widen(&self, next: &Value<T>) -> Value<T>
+	{
+		let joined = self.union(next);
+		if joined.is_identical(self) {
+			return joined;
+		}
+		match (self.as_range(), joined.as_range())
+		{
+		(Some( (lo,hi,_) ), Some( (j_lo,j_hi,st) )) => {
+			let st = if st == T::zero() { T::one() } else { st };
+			let new_lo = if j_lo < lo { j_lo % st } else { j_lo };
+			let new_hi = if j_hi > hi { Value::<T>::ones_raw() } else { j_hi };
+			Value::range(new_lo, new_hi, st)
+			},
+		_ => Value::Unknown,
+		}
+	}
+	/// Structural equality (unlike `==`, unknown values, sets and ranges are identical to themselves)
+	pub fn is_identical(&self, other: &Value<T>) -> bool
+	{
+		match (self, other)
+		{
+		(&Value::Unknown, &Value::Unknown) => true,
+		(&Value::Masked(a,a_m), &Value::Masked(b,b_m)) => a == b && a_m == b_m,
+		(&Value::Set(ref a), &Value::Set(ref b)) => a == b,
+		(&Value::Range(a1,a2,a3), &Value::Range(b1,b2,b3)) => (a1,a2,a3) == (b1,b2,b3),
+		(a, b) => a == b,
+		}
+	}
+	
 	/// Fetch the value of the specified bit
 	pub fn bit(&self, pos: usize) -> ValueBool
 	{
@@ -195,7 +768,12 @@ impl<T: ValueType> Value<T>
 		match self
 		{
 		&Value::Input(_) => ValueBool::Unknown,
+		&Value::Loaded(..) => ValueBool::Unknown,
 		&Value::Unknown => ValueBool::Unknown,
+		&Value::Expr(_) | &Value::Range(..) => {
+			let (v,m) = self.known_bits();
+			Value::Masked(v,m).bit(pos)
+			},
 		&Value::Known(v) =>
 			if v & mask != Zero::zero() {
 				ValueBool::True
@@ -203,6 +781,326 @@ impl<T: ValueType> Value<T>
 			else {
 				ValueBool::False
 			},
+		&Value::Set(ref vals) => {
+			let count = vals.iter().filter(|&&v| v & mask != Zero::zero()).count();
+			if count == vals.len() {
+				ValueBool::True
+			}
+			else if count == 0 {
+				ValueBool::False
+			}
+			else {
+				ValueBool::Unknown
+			}
+			},
+		&Value::Masked(v,m) =>
+			if m & mask == Zero::zero() {
+				ValueBool::Unknown
+			}
+			else if v & mask != Zero::zero() {
+				ValueBool::True
+			}
+			else {
+				ValueBool::False
+			},
+		}
+	}
+	
+	/// Constrain this value such that `self <rel> other` holds, with values no larger than `max`
+	///
+	/// Returns None if the relation can never hold. Symbolic values are left unchanged.
+	pub fn restrict(&self, rel: Relation, other: &Value<T>, max: T) -> Option<Value<T>>
+	{
+		if self.is_symbolic() {
+			return Some(self.clone());
+		}
+		if let Some(urel) = rel.unsigned() {
+			// Flipping the sign bit maps signed order onto unsigned order
+			let sign = (max >> 1) + T::one();
+			return self.flip_bit(sign).restrict(urel, &other.flip_bit(sign), max).map(|v| v.flip_bit(sign));
+		}
+		let (o_lo, o_hi) = match other.as_range()
+			{
+			Some( (lo,hi,_) ) => (lo, hi),
+			None => (T::zero(), max),
+			};
+		let (lo, hi) = match rel
+			{
+			Relation::Equal => (o_lo, o_hi),
+			Relation::NotEqual => return match other.val_known()
+				{
+				// - Only a single known value can be excluded
+				Some(v) => self.exclude(v),
+				None => Some(self.clone()),
+				},
+			Relation::Below => if o_hi == T::zero() { return None } else { (T::zero(), o_hi - T::one()) },
+			Relation::BelowEqual => (T::zero(), o_hi),
+			Relation::Above => if o_lo >= max { return None } else { (o_lo + T::one(), max) },
+			Relation::AboveEqual => (o_lo, max),
+			Relation::Less | Relation::LessEqual | Relation::Greater | Relation::GreaterEqual =>
+				unreachable!(),
+			};
+		self.intersect(lo, hi)
+	}
+	/// Limit to values within `lo` to `hi` (inclusive), None if there are no such values
+	fn intersect(&self, lo: T, hi: T) -> Option<Value<T>>
+	{
+		if lo > hi {
+			return None;
+		}
+		if self.is_fixed_set() {
+			let vals: Vec<_> = self.possibilities().filter(|&v| lo <= v && v <= hi).collect();
+			return if vals.is_empty() { None } else { Some(Value::set(vals)) };
+		}
+		match self.as_range()
+		{
+		Some( (s_lo,s_hi,st) ) => {
+			if s_lo >= lo && s_hi <= hi {
+				return Some(self.clone());
+			}
+			let st = if st == T::zero() { T::one() } else { st };
+			// Round the lower bound up to the next value in the range
+			let new_lo = if lo > s_lo {
+					let steps = (lo - s_lo) / st + if (lo - s_lo) % st != T::zero() { T::one() } else { T::zero() };
+					match steps.checked_mul(&st).and_then(|o| s_lo.checked_add(&o))
+					{
+					Some(v) => v,
+					None => return None,
+					}
+				}
+				else {
+					s_lo
+				};
+			let new_hi = ::std::cmp::min(s_hi, hi);
+			if new_lo > new_hi {
+				None
+			}
+			else {
+				Some( Value::range(new_lo, new_hi, st) )
+			}
+			},
+		None => Some( Value::range(lo, hi, T::one()) ),
+		}
+	}
+	/// Remove a single value from the possibilities, None if nothing is left
+	fn exclude(&self, v: T) -> Option<Value<T>>
+	{
+		match self
+		{
+		&Value::Known(x) => if x == v { None } else { Some(self.clone()) },
+		&Value::Set(ref vals) => Some( Value::set(vals.iter().map(|&x| x).filter(|&x| x != v).collect()) ),
+		&Value::Range(lo,hi,st) =>
+			if v == lo {
+				Some( Value::range(lo + st, hi, st) )
+			}
+			else if v == hi {
+				Some( Value::range(lo, hi - st, st) )
+			}
+			else {
+				Some( self.clone() )
+			},
+		_ => Some( self.clone() ),
+		}
+	}
+	
+	/// (internal) Flip a single bit (used to map signed order onto unsigned order)
+	fn flip_bit(&self, bit: T) -> Value<T>
+	{
+		match self
+		{
+		// - Ranges that don't cross the bit's boundary just move
+		&Value::Range(lo,hi,st) if lo / bit == hi / bit => Value::range(lo ^ bit, hi ^ bit, st),
+		v => v.clone() ^ Value::known(bit),
+		}
+	}
+	/// (internal) Apply an operation to every possible value
+	fn set_map<F: Fn(T)->T>(&self, op: F) -> Value<T>
+	{
+		assert!( self.is_fixed_set() );
+		Value::set( self.possibilities().map(op).collect() )
+	}
+	/// (internal) Apply an operation to every combination of possible values
+	fn set_binop<F: Fn(T,T)->T>(a: &Value<T>, b: &Value<T>, op: F) -> Value<T>
+	{
+		assert!( a.is_fixed_set() && b.is_fixed_set() );
+		let mut vals = Vec::new();
+		for x in a.possibilities() {
+			for y in b.possibilities() {
+				vals.push( op(x, y) );
+			}
+		}
+		Value::set(vals)
+	}
+	/// (internal) Apply an operation to the bounds of two ranges
+	///
+	/// The operation is given ((a_lo,a_hi,a_stride),(b_lo,b_hi,b_stride)) and returns None on overflow
+	fn range_binop<F>(a: &Value<T>, b: &Value<T>, op: F) -> Option<Value<T>>
+	where
+		F: Fn((T,T,T),(T,T,T)) -> Option<(T,T,T)>
+	{
+		match (a.as_range(), b.as_range())
+		{
+		(Some(ra), Some(rb)) => op(ra, rb).map(|(lo,hi,stride)| Value::range(lo, hi, stride)),
+		_ => None,
+		}
+	}
+}
+
+/// (internal) Add two ranges, returning None on overflow
+fn range_add<T: ValueType>((a_lo,a_hi,a_st): (T,T,T), (b_lo,b_hi,b_st): (T,T,T)) -> Option<(T,T,T)>
+{
+	match (a_lo.checked_add(&b_lo), a_hi.checked_add(&b_hi))
+	{
+	(Some(lo), Some(hi)) => Some( (lo, hi, gcd(a_st, b_st)) ),
+	_ => None,
+	}
+}
+/// (internal) Subtract two ranges, returning None on underflow
+fn range_sub<T: ValueType>((a_lo,a_hi,a_st): (T,T,T), (b_lo,b_hi,b_st): (T,T,T)) -> Option<(T,T,T)>
+{
+	match (a_lo.checked_sub(&b_hi), a_hi.checked_sub(&b_lo))
+	{
+	(Some(lo), Some(hi)) => Some( (lo, hi, gcd(a_st, b_st)) ),
+	_ => None,
+	}
+}
+
+/// Sign-extend the bottom `bits` bits of a raw value (e.g. an instruction immediate)
+pub fn sign_extend_raw<T: ValueType>(bits: usize, v: T) -> T
+{
+	if bits == 0 || bits >= Value::<T>::_bitsize() {
+		v
+	}
+	else if (v >> (bits-1)) & T::one() != T::zero() {
+		v | !Value::<T>::low_mask(bits)
+	}
+	else {
+		v & Value::<T>::low_mask(bits)
+	}
+}
+/// (internal) Interpret a raw value as signed
+fn to_signed<T: ValueType>(v: T) -> i64
+{
+	LinearExpr::<T>::cast_signed::<u64>(v) as i64
+}
+
+/// Greatest common divisor (used for range strides)
+fn gcd<T: ValueType>(a: T, b: T) -> T
+{
+	if b == T::zero() { a } else { gcd(b, a % b) }
+}
+
+impl Relation
+{
+	/// Relation that holds when this one doesn't
+	pub fn negate(self) -> Relation
+	{
+		match self
+		{
+		Relation::Equal => Relation::NotEqual,
+		Relation::NotEqual => Relation::Equal,
+		Relation::Below => Relation::AboveEqual,
+		Relation::BelowEqual => Relation::Above,
+		Relation::Above => Relation::BelowEqual,
+		Relation::AboveEqual => Relation::Below,
+		Relation::Less => Relation::GreaterEqual,
+		Relation::LessEqual => Relation::Greater,
+		Relation::Greater => Relation::LessEqual,
+		Relation::GreaterEqual => Relation::Less,
+		}
+	}
+	/// Unsigned relation with the same sense, for signed relations
+	pub fn unsigned(self) -> Option<Relation>
+	{
+		match self
+		{
+		Relation::Less => Some(Relation::Below),
+		Relation::LessEqual => Some(Relation::BelowEqual),
+		Relation::Greater => Some(Relation::Above),
+		Relation::GreaterEqual => Some(Relation::AboveEqual),
+		_ => None,
+		}
+	}
+	/// Equivalent relation with the operands swapped (a < b becomes b > a)
+	pub fn swap(self) -> Relation
+	{
+		match self
+		{
+		Relation::Equal => Relation::Equal,
+		Relation::NotEqual => Relation::NotEqual,
+		Relation::Below => Relation::Above,
+		Relation::BelowEqual => Relation::AboveEqual,
+		Relation::Above => Relation::Below,
+		Relation::AboveEqual => Relation::BelowEqual,
+		Relation::Less => Relation::Greater,
+		Relation::LessEqual => Relation::GreaterEqual,
+		Relation::Greater => Relation::Less,
+		Relation::GreaterEqual => Relation::LessEqual,
+		}
+	}
+}
+
+impl ValueBool
+{
+	pub fn from_bool(v: bool) -> ValueBool
+	{
+		if v { ValueBool::True } else { ValueBool::False }
+	}
+	/// Merge two flag values (unknown unless both agree)
+	pub fn union(self, other: ValueBool) -> ValueBool
+	{
+		if self == other { self } else { ValueBool::Unknown }
+	}
+}
+
+impl ::std::ops::Not for ValueBool
+{
+	type Output = ValueBool;
+	fn not(self) -> ValueBool
+	{
+		match self
+		{
+		ValueBool::True => ValueBool::False,
+		ValueBool::False => ValueBool::True,
+		ValueBool::Unknown => ValueBool::Unknown,
+		}
+	}
+}
+impl ::std::ops::BitAnd for ValueBool
+{
+	type Output = ValueBool;
+	fn bitand(self, other: ValueBool) -> ValueBool
+	{
+		match (self, other)
+		{
+		(ValueBool::False, _) | (_, ValueBool::False) => ValueBool::False,
+		(ValueBool::True, ValueBool::True) => ValueBool::True,
+		_ => ValueBool::Unknown,
+		}
+	}
+}
+impl ::std::ops::BitOr for ValueBool
+{
+	type Output = ValueBool;
+	fn bitor(self, other: ValueBool) -> ValueBool
+	{
+		match (self, other)
+		{
+		(ValueBool::True, _) | (_, ValueBool::True) => ValueBool::True,
+		(ValueBool::False, ValueBool::False) => ValueBool::False,
+		_ => ValueBool::Unknown,
+		}
+	}
+}
+impl ::std::ops::BitXor for ValueBool
+{
+	type Output = ValueBool;
+	fn bitxor(self, other: ValueBool) -> ValueBool
+	{
+		match (self, other)
+		{
+		(ValueBool::Unknown, _) | (_, ValueBool::Unknown) => ValueBool::Unknown,
+		(a, b) => ValueBool::from_bool(a != b),
 		}
 	}
 }
@@ -228,11 +1126,27 @@ impl<T: ValueType> ::std::ops::Add for Value<T>
 		}
 		match (self, other)
 		{
-		(Value::Unknown,_) => Value::Unknown,
-		(_,Value::Unknown) => Value::Unknown,
-		(Value::Input(_),_) => Value::Unknown,
-		(_,Value::Input(_)) => Value::Unknown,
-		(Value::Known(a),Value::Known(b)) => Value::Known(a+b),
+		(ref a,ref b) if a.is_fixed_set() && b.is_fixed_set() => Value::set_binop(a, b, |a,b| a.wrapping_add(&b)),
+		// - Loaded values keep their origin when offset
+		(Value::Loaded(p,o), Value::Known(k)) | (Value::Known(k), Value::Loaded(p,o)) => Value::Loaded(p, o.wrapping_add(&k)),
+		// - Symbolic values combine (if the other side is known or symbolic)
+		(ref a,ref b) if a.is_symbolic() || b.is_symbolic() => match (a.as_linear(), b.as_linear())
+			{
+			(Some(a), Some(b)) => Value::from_linear( a.combine(&b, false) ),
+			_ => Value::Unknown,
+			},
+		// - Bounded values add bounds (as long as they don't overflow),
+		//   otherwise only the bits below the first unknown bit are known
+		(a,b) => match Value::range_binop(&a, &b, range_add)
+			{
+			Some(v) => v,
+			None => {
+				let (a_v,a_m) = a.known_bits();
+				let (b_v,b_m) = b.known_bits();
+				let low = Value::<T>::low_mask( (!(a_m & b_m)).trailing_zeros() as usize );
+				Value::masked(a_v.wrapping_add(&b_v), low)
+				},
+			},
 		}
 	}
 }
@@ -250,13 +1164,28 @@ impl<T: ValueType> ::std::ops::Sub for Value<T>
 		}
 		match (self, other)
 		{
-		// - Pure unknown poisons
-		(Value::Unknown,_) => Value::Unknown,
-		(_,Value::Unknown) => Value::Unknown,
-		(Value::Input(_),_) => Value::Unknown,
-		(_,Value::Input(_)) => Value::Unknown,
-		// - Known resolves
-		(Value::Known(a),Value::Known(b)) => Value::Known(a-b),
+		// - Known/sets resolve
+		(ref a,ref b) if a.is_fixed_set() && b.is_fixed_set() => Value::set_binop(a, b, |a,b| a.wrapping_sub(&b)),
+		(Value::Loaded(p,o), Value::Known(k)) => Value::Loaded(p, o.wrapping_sub(&k)),
+		(Value::Loaded(ref a,a_o), Value::Loaded(ref b,b_o)) if a == b => Value::Known(a_o.wrapping_sub(&b_o)),
+		// - Symbolic values combine (e.g. SP-SP = 0)
+		(ref a,ref b) if a.is_symbolic() || b.is_symbolic() => match (a.as_linear(), b.as_linear())
+			{
+			(Some(a), Some(b)) => Value::from_linear( a.combine(&b, true) ),
+			_ => Value::Unknown,
+			},
+		// - Bounded values subtract bounds (as long as they don't underflow),
+		//   otherwise only the bits below the first unknown bit are known
+		(a,b) => match Value::range_binop(&a, &b, range_sub)
+			{
+			Some(v) => v,
+			None => {
+				let (a_v,a_m) = a.known_bits();
+				let (b_v,b_m) = b.known_bits();
+				let low = Value::<T>::low_mask( (!(a_m & b_m)).trailing_zeros() as usize );
+				Value::masked(a_v.wrapping_sub(&b_v), low)
+				},
+			},
 		}
 	}
 }
@@ -290,14 +1219,32 @@ impl<T: ValueType> ::std::ops::Mul for Value<T>
 		// Otherwise, unknown values are poisonous
 		(Value::Unknown,_) => (Value::Unknown,Value::Unknown),
 		(_,Value::Unknown) => (Value::Unknown,Value::Unknown),
+		// Symbolic values can be scaled by a known value
+		(ref a,Value::Known(k)) | (Value::Known(k),ref a) if a.is_symbolic() =>
+			(Value::Unknown, Value::from_linear( a.as_linear().unwrap().scale(k) )),
 		(Value::Input(_),_) => (Value::Unknown,Value::Unknown),
 		(_,Value::Input(_)) => (Value::Unknown,Value::Unknown),
+		(Value::Expr(_),_) => (Value::Unknown,Value::Unknown),
+		(_,Value::Expr(_)) => (Value::Unknown,Value::Unknown),
+		(Value::Masked(..),_) => (Value::Unknown,Value::Unknown),
+		(_,Value::Masked(..)) => (Value::Unknown,Value::Unknown),
+		// Ranges can be scaled by a known value (e.g. indexing an array)
+		(Value::Range(lo,hi,st),Value::Known(k)) | (Value::Known(k),Value::Range(lo,hi,st)) => match hi.checked_mul(&k)
+			{
+			Some(new_hi) => (Value::zero(), Value::range(lo * k, new_hi, st * k)),
+			None => (Value::Unknown,Value::Unknown),
+			},
+		(Value::Range(..),_) => (Value::Unknown,Value::Unknown),
+		(_,Value::Range(..)) => (Value::Unknown,Value::Unknown),
 		// But known values are fixed
-		(Value::Known(a),Value::Known(b)) => {
-			if a*b < a || a*b < b {
+		(a,b) => {
+			if a.possibilities().any(|x| b.possibilities().any(|y| x.checked_mul(&y).is_none())) {
 				error!("TODO: Handle overflow in value multiply");
+				(Value::Unknown, Value::set_binop(&a, &b, |a,b| a.wrapping_mul(&b)))
+			}
+			else {
+				(Value::zero(), Value::set_binop(&a, &b, |a,b| a.wrapping_mul(&b)))
 			}
-			(Value::zero(),Value::Known(a*b))
 			},
 		}
 	}
@@ -308,19 +1255,26 @@ impl<T: ValueType> ::std::ops::BitAnd for Value<T>
 	type Output = Value<T>;
 	fn bitand(self, other: Value<T>) -> Value<T>
 	{
-		// TODO: Restrict range of unknown
 		match (self, other)
 		{
 		// - Zero nukes result
 		(_,Value::Known(v)) if v == Zero::zero() => Value::zero(),
 		(Value::Known(v),_) if v == Zero::zero() => Value::zero(),
-		// - Pure unkown poisons
-		(Value::Unknown,_) => Value::Unknown,
-		(_,Value::Unknown) => Value::Unknown,
-		(Value::Input(_),_) => Value::Unknown,
-		(_,Value::Input(_)) => Value::Unknown,
-		// - Known resolves
-		(Value::Known(a),Value::Known(b)) => Value::Known(a&b),
+		// - Known/sets resolve
+		(ref a,ref b) if a.is_fixed_set() && b.is_fixed_set() => Value::set_binop(a, b, |a,b| a & b),
+		// - Otherwise, bits are known if known in both, or known zero in either
+		(a,b) => {
+			let (a_v,a_m) = a.known_bits();
+			let (b_v,b_m) = b.known_bits();
+			let res = Value::masked(a_v & b_v, (a_m & b_m) | (a_m & !a_v) | (b_m & !b_v));
+			// - The result also can't be larger than either input
+			let bound = [a.as_range(), b.as_range()].iter().filter_map(|r| r.map(|r| r.1)).min();
+			match (res.as_range(), bound)
+			{
+			(Some((lo,hi,stride)), Some(bound)) if bound < hi => Value::range(lo, bound, stride),
+			_ => res,
+			}
+			},
 		}
 	}
 }
@@ -330,14 +1284,15 @@ impl<T: ValueType> ::std::ops::BitOr for Value<T>
 	type Output = Value<T>;
 	fn bitor(self, other: Value<T>) -> Value<T>
 	{
-		// TODO: Restrict range of unknown
 		match (self, other)
 		{
-		(Value::Unknown,_) => Value::Unknown,
-		(_,Value::Unknown) => Value::Unknown,
-		(Value::Input(_),_) => Value::Unknown,
-		(_,Value::Input(_)) => Value::Unknown,
-		(Value::Known(a),Value::Known(b)) => Value::Known(a|b),
+		(ref a,ref b) if a.is_fixed_set() && b.is_fixed_set() => Value::set_binop(a, b, |a,b| a | b),
+		// - Otherwise, bits are known if known in both, or known one in either
+		(a,b) => {
+			let (a_v,a_m) = a.known_bits();
+			let (b_v,b_m) = b.known_bits();
+			Value::masked(a_v | b_v, (a_m & b_m) | (a_m & a_v) | (b_m & b_v))
+			},
 		}
 	}
 }
@@ -349,11 +1304,13 @@ impl<T: ValueType> ::std::ops::BitXor for Value<T>
 	{
 		match (self, other)
 		{
-		(Value::Unknown,_) => Value::Unknown,
-		(_,Value::Unknown) => Value::Unknown,
-		(Value::Input(_),_) => Value::Unknown,
-		(_,Value::Input(_)) => Value::Unknown,
-		(Value::Known(a),Value::Known(b)) => Value::Known(a^b),
+		(ref a,ref b) if a.is_fixed_set() && b.is_fixed_set() => Value::set_binop(a, b, |a,b| a ^ b),
+		// - Otherwise, bits are only known if known in both
+		(a,b) => {
+			let (a_v,a_m) = a.known_bits();
+			let (b_v,b_m) = b.known_bits();
+			Value::masked(a_v ^ b_v, a_m & b_m)
+			},
 		}
 	}
 }
@@ -366,9 +1323,17 @@ impl<T: ValueType> ::std::ops::Not for Value<T>
 	{
 		match self
 		{
-		Value::Input(_) => Value::Unknown,
+		// - !x = -x - 1
+		ref v @ Value::Input(_) | ref v @ Value::Expr(_) => {
+			let neg = v.as_linear().unwrap().scale(Value::<T>::ones_raw());
+			Value::from_linear( neg.combine(&LinearExpr { terms: vec![], offset: T::one() }, true) )
+			},
+		Value::Loaded(..) => Value::Unknown,
 		Value::Unknown => Value::Unknown,
 		Value::Known(a) => Value::Known(!a),
+		v @ Value::Set(_) => v.set_map(|a| !a),
+		Value::Masked(v,m) => Value::masked(!v, m),
+		Value::Range(lo,hi,st) => Value::range(!hi, !lo, st),
 		}
 	}
 }
@@ -391,8 +1356,21 @@ impl<T: ValueType> ::std::ops::Shl<usize> for Value<T>
 			match self
 			{
 			Value::Known(a) => (Value::Known(a>>(self.bitsize()-rhs)), Value::Known(a<<rhs)),
-			// TODO: Return a pair of masked values
-			_ => (Value::Unknown,Value::Unknown),
+			ref v @ Value::Set(_) => (v.set_map(|a| a>>(v.bitsize()-rhs)), v.set_map(|a| a<<rhs)),
+			// - Symbolic values are multiplied (shifted-out bits are unknown)
+			ref v @ Value::Input(_) | ref v @ Value::Expr(_) =>
+				(Value::Unknown, Value::from_linear( v.as_linear().unwrap().scale(T::one() << rhs) )),
+			// - Ranges that don't overflow just scale
+			Value::Range(lo,hi,st) if hi >> (self.bitsize()-rhs) == T::zero() =>
+				(Value::zero(), Value::range(lo << rhs, hi << rhs, st << rhs)),
+			ref v => {
+				// Vacated bits (top of the extra, bottom of the result) are known zero
+				let (val,mask) = v.known_bits();
+				let low = Value::<T>::low_mask(rhs);
+				let extra = Value::masked(val >> (v.bitsize()-rhs), (mask >> (v.bitsize()-rhs)) | !low);
+				let res = Value::masked(val << rhs, (mask << rhs) | low);
+				(extra, res)
+				},
 			}
 		}
 	}
@@ -419,8 +1397,23 @@ impl<T: ValueType> ::std::ops::Shr<usize> for Value<T>
 			match self
 			{
 			Value::Known(a) => (Value::Known(a<<(self.bitsize()-rhs)), Value::Known(a>>rhs)),
-			// TODO: Return a pair of masked values
-			_ => (Value::Unknown,Value::Unknown),
+			ref v @ Value::Set(_) => (v.set_map(|a| a<<(v.bitsize()-rhs)), v.set_map(|a| a>>rhs)),
+			ref v => {
+				// Vacated bits (bottom of the extra, top of the result) are known zero
+				let (val,mask) = v.known_bits();
+				let low = Value::<T>::low_mask(v.bitsize()-rhs);
+				let extra = Value::masked(val << (v.bitsize()-rhs), (mask << (v.bitsize()-rhs)) | low);
+				let res = match v
+					{
+					// - Ranges keep their bounds (and the stride, if it's a multiple of the shift)
+					&Value::Range(lo,hi,st) => {
+						let st = if st % (T::one() << rhs) == T::zero() { st >> rhs } else { T::one() };
+						Value::range(lo >> rhs, hi >> rhs, st)
+						},
+					_ => Value::masked(val >> rhs, (mask >> rhs) | !low),
+					};
+				(extra, res)
+				},
 			}
 		}
 	}
@@ -447,9 +1440,32 @@ impl<T: ValueType> ::std::cmp::PartialOrd for Value<T>
 		(&Value::Input(i1), &Value::Input(i2)) => if i1 == i2 { Some(Ordering::Equal) } else { None },
 		(&Value::Input(_),_) => None,
 		(_,&Value::Input(_)) => None,
+		(&Value::Expr(ref a), &Value::Expr(ref b)) => if a == b { Some(Ordering::Equal) } else { None },
+		(&Value::Expr(_),_) => None,
+		(_,&Value::Expr(_)) => None,
+		(&Value::Loaded(ref a, a_o), &Value::Loaded(ref b, b_o)) => if a == b && a_o == b_o { Some(Ordering::Equal) } else { None },
+		(&Value::Loaded(..),_) => None,
+		(_,&Value::Loaded(..)) => None,
 		(&Value::Unknown,_) => None,
 		(_,&Value::Unknown) => None,
+		(&Value::Masked(..),_) => None,
+		(_,&Value::Masked(..)) => None,
 		(&Value::Known(a),&Value::Known(b)) => a.partial_cmp(&b),
+		// - Sets (and ranges) are only ordered if they don't overlap
+		//  > Two identical sets can still hold different values, use `is_identical` to compare them
+		(a,b) => {
+			let (a_min,a_max) = a.set_bounds();
+			let (b_min,b_max) = b.set_bounds();
+			if a_max < b_min {
+				Some(Ordering::Less)
+			}
+			else if a_min > b_max {
+				Some(Ordering::Greater)
+			}
+			else {
+				None
+			}
+			},
 		}
 	}
 }
@@ -462,8 +1478,128 @@ impl<T: ValueType> ::std::fmt::Debug for Value<T>
 		match self
 		{
 		&Value::Input(i) => write!(f, "I{}", i),
+		&Value::Expr(ref e) => write!(f, "{:?}", e),
+		&Value::Loaded(ref p, ofs) =>
+			if ofs == T::zero() {
+				write!(f, "{:?}", p)
+			}
+			else {
+				write!(f, "{:?}{}", p, LinearExpr::<T>::fmt_offset(ofs))
+			},
 		&Value::Unknown => write!(f, "?"),
 		&Value::Known(v) => write!(f, "{:#x}", v),
+		&Value::Masked(v,m) => write!(f, "{:#x}/{:#x}", v, m),
+		&Value::Range(lo,hi,st) => write!(f, "[{:#x}..{:#x}:{:#x}]", lo, hi, st),
+		&Value::Set(ref vals) => {
+			try!( write!(f, "{{") );
+			for (i,v) in vals.iter().enumerate() {
+				if i != 0 {
+					try!( write!(f, ",") );
+				}
+				try!( write!(f, "{:#x}", v) );
+			}
+			write!(f, "}}")
+			},
+		}
+	}
+}
+
+impl<T: ValueType> LinearExpr<T>
+{
+	/// Add (or subtract) another expression
+	fn combine(&self, other: &LinearExpr<T>, negate: bool) -> LinearExpr<T>
+	{
+		let mut terms = self.terms.clone();
+		for &(reg, coeff) in other.terms.iter()
+		{
+			let coeff = if negate { T::zero().wrapping_sub(&coeff) } else { coeff };
+			match terms.binary_search_by(|e| e.0.cmp(&reg))
+			{
+			Ok(i) => { terms[i].1 = terms[i].1.wrapping_add(&coeff); },
+			Err(i) => terms.insert(i, (reg, coeff)),
+			}
+		}
+		terms.retain(|&(_,c)| c != T::zero());
+		LinearExpr {
+			terms: terms,
+			offset: if negate { self.offset.wrapping_sub(&other.offset) } else { self.offset.wrapping_add(&other.offset) },
+		}
+	}
+	/// Multiply by a constant
+	fn scale(&self, k: T) -> LinearExpr<T>
+	{
+		LinearExpr {
+			terms: self.terms.iter().map(|&(r,c)| (r, c.wrapping_mul(&k))).filter(|&(_,c)| c != T::zero()).collect(),
+			offset: self.offset.wrapping_mul(&k),
+		}
+	}
+	/// Convert to another value size
+	///
+	/// Constants are sign-extended, so small negative offsets (e.g. SP-8) are preserved
+	fn cast<U: ValueType>(&self) -> LinearExpr<U>
+	{
+		LinearExpr {
+			terms: self.terms.iter().map(|&(r,c)| (r, LinearExpr::cast_signed(c))).filter(|&(_,c)| c != U::zero()).collect(),
+			offset: LinearExpr::cast_signed(self.offset),
+		}
+	}
+	/// (internal) Sign-extend (or truncate) a constant into another size
+	fn cast_signed<U: ValueType>(v: T) -> U
+	{
+		let rv = Value::<U>::cast(v);
+		if v.leading_zeros() == 0 {
+			rv | !Value::<U>::cast(T::max_value())
+		}
+		else {
+			rv
+		}
+	}
+	/// (internal) Format a constant offset, showing values with the top bit set as negative (e.g. SP-8)
+	fn fmt_offset(ofs: T) -> String
+	{
+		if ofs.leading_zeros() == 0 {
+			format!("-{:#x}", T::zero().wrapping_sub(&ofs))
+		}
+		else {
+			format!("+{:#x}", ofs)
+		}
+	}
+}
+
+impl<T: ValueType> ::std::fmt::Debug for LinearExpr<T>
+{
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+	{
+		for (i,&(reg,coeff)) in self.terms.iter().enumerate()
+		{
+			if i != 0 {
+				try!( write!(f, "+") );
+			}
+			if coeff == T::one() {
+				try!( write!(f, "I{}", reg) );
+			}
+			else {
+				try!( write!(f, "I{}*{:#x}", reg, coeff) );
+			}
+		}
+		if self.offset == T::zero() {
+			Ok( () )
+		}
+		else {
+			write!(f, "{}", LinearExpr::fmt_offset(self.offset))
+		}
+	}
+}
+
+impl ::std::fmt::Debug for Provenance
+{
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result
+	{
+		if self.offset == 0 {
+			write!(f, "*({:?}):{}", self.base, self.size)
+		}
+		else {
+			write!(f, "*({:?}{}):{}", self.base, LinearExpr::<u64>::fmt_offset(self.offset), self.size)
 		}
 	}
 }
@@ -478,14 +1614,323 @@ where
 		let rv = match self.val
 			{
 			&Value::Input(_) => panic!("Can't get possibilities for an unknown value"),
+			&Value::Expr(_) => panic!("Can't get possibilities for an unknown value"),
+			&Value::Loaded(..) => panic!("Can't get possibilities for an unknown value"),
 			&Value::Unknown => panic!("Can't get possibilities for an unknown value"),
+			&Value::Masked(..) => panic!("Can't get possibilities for a partially known value"),
 			&Value::Known(v) => {
 				if self.idx == 0 { Some(v) } else { None }
 				},
+			&Value::Set(ref vals) => vals.get(self.idx).map(|&v| v),
+			&Value::Range(lo,hi,stride) => {
+				let steps: usize = NumCast::from( (hi - lo) / stride ).unwrap_or(!0);
+				if self.idx <= steps {
+					Some( lo + stride * NumCast::from(self.idx).unwrap() )
+				}
+				else {
+					None
+				}
+				},
 			};
 		self.idx += 1;
 		rv
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	
+	fn vals<T: ValueType>(v: &Value<T>) -> Vec<T> {
+		v.possibilities().collect()
+	}
+	
+	#[test]
+	fn set_collapses()
+	{
+		assert!( Value::<u32>::set(vec![5, 5]).is_identical(&Value::known(5)) );
+		assert_eq!( vals(&Value::<u32>::set(vec![3, 1, 2, 1])), vec![1, 2, 3] );
+		assert!( Value::<u32>::set((0 .. MAX_SET_SIZE as u32 + 1).collect()).is_unknown() );
+	}
+	
+	#[test]
+	fn set_arithmetic()
+	{
+		let a = Value::<u8>::set(vec![1, 2]);
+		assert_eq!( vals(&(a.clone() + Value::known(10))), vec![11, 12] );
+		assert_eq!( vals(&(a.clone() - Value::known(2))), vec![0, 0xFF] );
+		assert_eq!( vals(&(a.clone() * Value::set(vec![1, 3])).1), vec![1, 2, 3, 6] );
+		assert_eq!( vals(&(a.clone() | Value::known(0x10))), vec![0x11, 0x12] );
+		// - Wrapping results are still exact
+		assert_eq!( vals(&(Value::<u8>::set(vec![0xFF, 0xFE]) + Value::known(2))), vec![0, 1] );
+	}
+	
+	#[test]
+	fn set_union()
+	{
+		let u = Value::<u32>::known(4).union(&Value::set(vec![1, 4]));
+		assert_eq!( vals(&u), vec![1, 4] );
+		assert!( Value::<u32>::known(4).union(&Value::Unknown).is_unknown() );
+	}
+	
+	#[test]
+	fn set_equality()
+	{
+		// - Two values from the same set can still differ, so aren't equal
+		let a = Value::<u32>::set(vec![1, 2]);
+		assert!( a != a.clone() );
+		assert!( a.is_identical(&a.clone()) );
+		assert!( !a.is_identical(&Value::set(vec![1, 3])) );
+		assert!( Value::<u32>::set(vec![1, 2]) < Value::set(vec![3, 4]) );
+		assert!( Value::<u32>::known(7) == Value::known(7) );
+	}
+	
+	#[test]
+	fn masked_bits()
+	{
+		let v = Value::<u32>::masked(0x1234_0000, 0xFFFF_0000);
+		assert_eq!( v.known_bits(), (0x1234_0000, 0xFFFF_0000) );
+		assert_eq!( v.bit(18), ValueBool::True );
+		assert_eq!( v.bit(16), ValueBool::False );
+		assert_eq!( v.bit(0), ValueBool::Unknown );
+		// - Only a few unknown bits enumerate as a set
+		assert_eq!( vals(&Value::<u32>::masked(0x10, !0x3)), vec![0x10, 0x11, 0x12, 0x13] );
+		assert!( Value::<u32>::masked(0, 0).is_unknown() );
+	}
+	
+	#[test]
+	fn masked_logic()
+	{
+		let v = Value::<u32>::masked(0x1234_0000, 0xFFFF_0000);
+		// - AND with known zeroes, OR with known ones
+		assert!( (v.clone() & Value::known(0xFF00_0000)).is_identical(&Value::known(0x1200_0000)) );
+		assert_eq!( (v.clone() | Value::known(0xFFFF)).val_known(), Some(0x1234_FFFF) );
+		assert_eq!( (Value::<u32>::Unknown & Value::known(0xF0)).known_bits(), (0, !0xF0) );
+		assert_eq!( (v.clone() ^ Value::known(0xFFFF_0000)).known_bits(), (0xEDCB_0000, 0xFFFF_0000) );
+		// - Shifted in bits are known zero
+		assert_eq!( (Value::<u32>::Unknown << 4).1.known_bits(), (0, 0xF) );
+		assert_eq!( (Value::<u32>::Unknown >> 28).1.known_bits(), (0, !0xF) );
+	}
+	
+	#[test]
+	fn masked_add_low_bits()
+	{
+		// - Bits below the lowest unknown bit are exact
+		let v = Value::<u32>::masked(0x3, 0xF) + Value::known(0x1);
+		assert_eq!( v.known_bits().1 & 0xF, 0xF );
+		assert_eq!( v.known_bits().0 & 0xF, 0x4 );
+		// - Bits that agree are kept when merging
+		let (u_v, u_m) = Value::<u32>::masked(0x3, 0xF).union(&Value::masked(0x7, 0xF)).known_bits();
+		assert_eq!( (u_v & 0x3, u_m & 0x3), (0x3, 0x3) );
+	}
+	
+	#[test]
+	fn range_construction()
+	{
+		let r = Value::<u32>::range(0, 0x1000, 4);
+		assert_eq!( r.as_range(), Some( (0, 0x1000, 4) ) );
+		// - The upper bound is aligned to the stride
+		assert_eq!( Value::<u32>::range(0, 0x1003, 4).as_range(), Some( (0, 0x1000, 4) ) );
+		// - Small ranges become sets, full ranges unknown
+		assert_eq!( vals(&Value::<u32>::range(8, 20, 4)), vec![8, 12, 16, 20] );
+		assert!( Value::<u8>::range(0, 0xFF, 1).is_unknown() );
+	}
+	
+	#[test]
+	fn range_arithmetic()
+	{
+		let r = Value::<u32>::range(0, 0x1000, 4);
+		assert_eq!( (r.clone() + Value::known(0x100)).as_range(), Some( (0x100, 0x1100, 4) ) );
+		assert_eq!( (r.clone() * Value::known(2)).1.as_range(), Some( (0, 0x2000, 8) ) );
+		assert_eq!( (r.clone() >> 2).1.as_range(), Some( (0, 0x400, 1) ) );
+		assert_eq!( (r.clone() << 1).1.as_range(), Some( (0, 0x2000, 8) ) );
+		// - AND limits the upper bound
+		assert_eq!( (Value::<u32>::Unknown & Value::known(0xFF)).as_range().map(|r| r.1), Some(0xFF) );
+		// - Overflowing ranges only keep the low bits
+		let wrapped = Value::<u8>::range(0x80, 0xF0, 0x10) + Value::range(0x80, 0xF0, 0x10);
+		assert_eq!( wrapped.known_bits(), (0, 0xF) );
+	}
+	
+	#[test]
+	fn range_union()
+	{
+		let u = Value::<u32>::known(0).union(&Value::known(0x1000));
+		assert!( u.is_identical(&Value::set(vec![0, 0x1000])) );
+		let u = Value::<u32>::range(0, 0x100, 4).union(&Value::range(0x200, 0x300, 8));
+		assert_eq!( u.as_range(), Some( (0, 0x300, 4) ) );
+		assert!( u.is_enumerable() );
+		assert!( !Value::<u32>::range(0, 0x10000, 4).is_enumerable() );
+	}
+	
+	#[test]
+	fn linear_expressions()
+	{
+		let sp = Value::<u64>::Input(13);
+		let frame = sp.clone() - Value::known(8);
+		assert_eq!( frame.input_offset(), Some( (13, (-8i64) as u64) ) );
+		assert!( (frame.clone() + Value::known(8)) == sp );
+		// - Inputs cancel out
+		let sum = Value::<u64>::Input(0) + Value::Input(1);
+		assert!( (sum.clone() - Value::Input(1)) == Value::Input(0) );
+		assert_eq!( (sum.clone() - Value::Input(0) - Value::Input(1)).val_known(), Some(0) );
+		assert_eq!( (frame.clone() << 1).1.input_offset(), None );
+		assert_eq!( frame.pointer_base().map(|(b,o)| (b == sp, o)), Some( (true, (-8i64) as u64) ) );
+		// - Non-linear operations lose the expression
+		assert!( (sp.clone() * sp.clone()).1.is_unknown() );
+	}
+	
+	#[test]
+	fn linear_register_round_trip()
+	{
+		// - Register-sized symbolic values survive truncation and extension (e.g. 32-bit ARM registers)
+		let frame = Value::<u64>::Input(13) - Value::known(8);
+		let narrowed = frame.truncate::<u32>() - Value::known(4);
+		assert_eq!( narrowed.zero_extend::<u64>().input_offset(), Some( (13, (-12i64) as u64) ) );
+	}
+	
+	#[test]
+	fn linear_narrowed()
+	{
+		// - A byte of an input is only the low bits, so extending it leaves the high bits known zero
+		let byte = Value::<u64>::Input(0).truncate::<u32>().truncate::<u8>();
+		assert!( byte == Value::Input(0) );
+		assert_eq!( byte.truncate::<u32>().known_bits(), (0, 0xFFFF_FF00) );
+		assert_eq!( (Value::<u64>::Input(0) + Value::known(1)).truncate::<u16>().zero_extend::<u32>().known_bits(), (0, 0xFFFF_0000) );
+	}
+	
+	#[test]
+	fn restrict_unsigned()
+	{
+		let max = 0xFFFF_FFFF;
+		let idx = Value::<u32>::Unknown;
+		// - Bounds check (e.g. a jump table index)
+		assert_eq!( idx.restrict(Relation::Below, &Value::known(8), max).map(|v| vals(&v)), Some( (0 .. 8).collect() ) );
+		assert_eq!( idx.restrict(Relation::AboveEqual, &Value::known(0x100), max).and_then(|v| v.as_range()), Some( (0x100, max, 1) ) );
+		assert!( idx.restrict(Relation::Equal, &Value::known(5), max).map_or(false, |v| v.is_identical(&Value::known(5))) );
+		// - Relations that can't hold
+		assert!( Value::<u32>::known(3).restrict(Relation::Above, &Value::known(4), max).is_none() );
+		assert!( idx.restrict(Relation::Below, &Value::zero(), max).is_none() );
+	}
+	
+	#[test]
+	fn restrict_exclude()
+	{
+		let max = 0xFF;
+		let set = Value::<u8>::set(vec![1, 2, 3]);
+		assert_eq!( set.restrict(Relation::NotEqual, &Value::known(2), max).map(|v| vals(&v)), Some(vec![1, 3]) );
+		assert!( Value::<u8>::known(2).restrict(Relation::NotEqual, &Value::known(2), max).is_none() );
+		let r = Value::<u8>::range(0, 0xF0, 0x10);
+		assert_eq!( r.restrict(Relation::NotEqual, &Value::zero(), max).and_then(|v| v.as_range()), Some( (0x10, 0xF0, 0x10) ) );
+		// - Symbolic values aren't constrained
+		assert!( Value::<u8>::Input(1).restrict(Relation::Below, &Value::known(4), max) == Some(Value::Input(1)) );
+	}
+	
+	#[test]
+	fn relations()
+	{
+		assert_eq!( Relation::Below.negate(), Relation::AboveEqual );
+		assert_eq!( Relation::Below.swap(), Relation::Above );
+		assert_eq!( Relation::LessEqual.negate(), Relation::Greater );
+		assert_eq!( Relation::Equal.unsigned(), None );
+	}
+	
+	#[test]
+	fn loaded_provenance()
+	{
+		let p = Rc::new(Provenance { base: Value::Input(0), offset: 8, size: 4, generation: 0 });
+		let v = Value::<u32>::Loaded(p.clone(), 0);
+		// - Offsets keep the origin, and cancel out between values from the same origin
+		let field = v.clone() + Value::known(0x10);
+		assert!( field == Value::Loaded(p.clone(), 0x10) );
+		assert_eq!( (field.clone() - v.clone()).val_known(), Some(0x10) );
+		assert_eq!( field.pointer_base().map(|(b,o)| (b == v, o)), Some( (true, 0x10) ) );
+		// - Other loads (or other operations) don't
+		let other = Value::<u32>::Loaded(Rc::new(Provenance { base: Value::Input(0), offset: 12, size: 4, generation: 0 }), 0);
+		assert!( v != other );
+		assert!( (v.clone() - other).is_unknown() );
+		// - Neither does the same pointer loaded again after a store
+		let reloaded = Value::<u32>::Loaded(Rc::new(Provenance { generation: 1, .. (*p).clone() }), 0);
+		assert!( v != reloaded );
+		assert!( (v.clone() - reloaded.clone()).is_unknown() );
+		assert!( v.union(&reloaded).is_unknown() );
+		assert!( (v.clone() ^ Value::known(1)).bit(1) == ValueBool::Unknown );
+	}
+	
+	#[test]
+	fn widen_converges()
+	{
+		// - Stable values are left alone
+		assert!( Value::<u32>::known(4).widen(&Value::known(4)).is_identical(&Value::known(4)) );
+		// - A growing counter has its upper bound pushed to the limit (keeping the stride)
+		let w = Value::<u32>::set(vec![0, 4]).widen(&Value::set(vec![0, 4, 8]));
+		assert_eq!( w.as_range(), Some( (0, 0xFFFF_FFFC, 4) ) );
+		// - And further iterations don't change it
+		assert!( w.widen(&Value::known(12)).is_identical(&w) );
+		assert!( Value::<u32>::known(1).widen(&Value::Unknown).is_unknown() );
+	}
+	
+	#[test]
+	fn signed_extension()
+	{
+		assert_eq!( Value::<u32>::known(0x80).sign_extend_from(8).val_known(), Some(0xFFFF_FF80) );
+		assert_eq!( Value::<u32>::known(0x7F).sign_extend_from(8).val_known(), Some(0x7F) );
+		assert_eq!( Value::<u8>::known(0xFE).sign_extend::<u32>().val_known(), Some(0xFFFF_FFFE) );
+		// - Unknown sign bit leaves the high bits unknown
+		let v = Value::<u32>::masked(0x01, 0x0F).sign_extend_from(8);
+		assert_eq!( v.known_bits(), (0x01, 0x0F) );
+	}
+	
+	#[test]
+	fn signed_shift()
+	{
+		assert_eq!( Value::<u32>::known(0x8000_0000).asr(4).1.val_known(), Some(0xF800_0000) );
+		assert_eq!( Value::<u32>::known(0x4000_0000).asr(4).1.val_known(), Some(0x0400_0000) );
+		// - Unknown sign bit: vacated bits are unknown, others still shift
+		let (_, r) = Value::<u32>::masked(0x10, 0x7FFF_FFFF).asr(4);
+		assert_eq!( r.bit(0), ValueBool::True );
+		assert_eq!( r.bit(31), ValueBool::Unknown );
+	}
+	
+	#[test]
+	fn signed_compare()
+	{
+		let m1 = Value::<u32>::known(0xFFFF_FFFF);
+		assert_eq!( m1.signed_cmp(&Value::known(1)), Some(Ordering::Less) );
+		assert_eq!( Value::<u32>::known(1).signed_cmp(&m1), Some(Ordering::Greater) );
+		assert_eq!( m1.signed_cmp(&m1), Some(Ordering::Equal) );
+		assert_eq!( Value::<u32>::range(0, 10, 1).signed_cmp(&Value::known(5)), None );
+		
+		assert_eq!( Value::<u32>::known(0x7FFF_FFFF).signed_add_overflows(&Value::known(1)), ValueBool::True );
+		assert_eq!( Value::<u32>::known(1).signed_add_overflows(&m1), ValueBool::False );
+		assert_eq!( Value::<u32>::known(0x8000_0000).signed_sub_overflows(&Value::known(1)), ValueBool::True );
+		assert_eq!( Value::<u32>::range(0, 0x7FFF_FFFF, 1).signed_add_overflows(&Value::known(1)), ValueBool::Unknown );
+	}
+	
+	#[test]
+	fn narrow_symbolic_extension()
+	{
+		// - Register-sized symbolic values extend unchanged
+		assert!( Value::<u32>::Input(0).zero_extend::<u64>().is_identical(&Value::Input(0)) );
+		// - Narrower ones are only known to have zero high bits
+		let z = Value::<u8>::Input(0).zero_extend::<u32>();
+		assert_eq!( z.bit(0), ValueBool::Unknown );
+		assert_eq!( z.bit(8), ValueBool::False );
+		assert_eq!( z.bit(31), ValueBool::False );
+		let s = Value::<u8>::Input(0).sign_extend::<u32>();
+		assert_eq!( s.bit(31), ValueBool::Unknown );
+		// - Narrowing keeps the symbolic value
+		assert!( Value::<u32>::Input(0).truncate::<u8>().is_identical(&Value::Input(0)) );
+	}
+	
+	#[test]
+	fn merge_ops()
+	{
+		let prev = Value::<u32>::set(vec![0, 4]);
+		let next = Value::set(vec![0, 4, 8]);
+		assert_eq!( vals(&MergeOp::Join.apply(&prev, &next)), vec![0, 4, 8] );
+		assert!( MergeOp::Widen.apply(&prev, &next).is_identical(&prev.widen(&next)) );
+	}
+}
+
 // vim: ft=rust